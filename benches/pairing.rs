@@ -0,0 +1,134 @@
+//! Pairing latency and throughput of the TCP rendezvous server, driven over
+//! loopback by in-process clients.
+//!
+//!     cargo bench --bench pairing
+//!
+//! Clients register with their first frame, so the latency is the server's
+//! own.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::{SinkExt, StreamExt};
+use nat_traversal_test::{
+    REGISTER, RendezvousServer,
+    limits::{LimitConfig, Rate},
+};
+use socket2::SockRef;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+// A port nobody listens on, for the server to bind.
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+// Connect, register, wait for the peer address and hang up, returning how long the
+// server took to introduce us.
+async fn rendezvous(server: SocketAddr) -> Duration {
+    let start = Instant::now();
+    let stream = TcpStream::connect(server).await.unwrap();
+    // reset instead of lingering in TIME_WAIT, the benchmark opens far more
+    // connections than there are ephemeral ports
+    SockRef::from(&stream)
+        .set_linger(Some(Duration::ZERO))
+        .unwrap();
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    let register = serde_json::json!({ REGISTER: true });
+    stream
+        .send(bytes::Bytes::from(register.to_string()))
+        .await
+        .unwrap();
+    loop {
+        let msg = stream.next().await.unwrap().unwrap();
+        // skip server heartbeats
+        if !msg.is_empty() {
+            break;
+        }
+    }
+    let latency = start.elapsed();
+    let _ = stream.send(bytes::Bytes::from("done")).await;
+    latency
+}
+
+// `sessions` clients registering at once, all of them paired.
+async fn pair_all(server: SocketAddr, sessions: usize) -> Duration {
+    let start = Instant::now();
+    let clients: Vec<_> = (0..sessions)
+        .map(|_| tokio::spawn(rendezvous(server)))
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn pairing(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let server = free_addr();
+    let unlimited = Rate {
+        per_sec: f64::MAX,
+        burst: f64::MAX,
+    };
+    let rendezvous_server = RendezvousServer::builder()
+        .tcp_listen([server])
+        .limits(LimitConfig {
+            per_ip: unlimited,
+            per_prefix: unlimited,
+            max_sessions_per_ip: usize::MAX,
+            ..LimitConfig::default()
+        })
+        .idle_timeout(Duration::from_secs(60))
+        .build();
+    rt.spawn(rendezvous_server.run());
+    // the server binds once it runs, a connection that says nothing is not
+    // registered
+    rt.block_on(async {
+        while TcpStream::connect(server).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+    });
+
+    let mut group = c.benchmark_group("tcp_pairing");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+    // one pair: the time from connecting until the address arrives
+    group.bench_function("latency", |b| {
+        b.to_async(&rt).iter_custom(|iters| async move {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let (a, b) = tokio::join!(rendezvous(server), rendezvous(server));
+                total += a.max(b);
+            }
+            total
+        })
+    });
+    // thousands of sessions registering at once
+    for sessions in [1000, 4000] {
+        group.throughput(Throughput::Elements(sessions as u64));
+        group.bench_with_input(
+            BenchmarkId::new("concurrent", sessions),
+            &sessions,
+            |b, &sessions| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        total += pair_all(server, sessions).await;
+                    }
+                    total
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pairing);
+criterion_main!(benches);
@@ -0,0 +1,113 @@
+//! Packets per second of the UDP rendezvous server with one, two and four
+//! SO_REUSEPORT workers, answering in-process clients over loopback. Mapping
+//! requests are answered by each worker on its own, registrations (`ping`)
+//! all take the one lock of the pairing state, so comparing both shows what
+//! that lock costs.
+//!
+//!     cargo bench --bench udp_pps
+//!
+//! Clients and server share the machine, so the scaling shows best with
+//! more cores than workers.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use nat_traversal_test::{
+    RendezvousServer,
+    limits::{LimitConfig, Rate},
+    traversal::{MAPPING_REQUEST, padded},
+};
+use tokio::{net::UdpSocket, time};
+
+const CLIENTS: usize = 32;
+const REQUESTS_PER_CLIENT: usize = 1000;
+// requests each client keeps in flight
+const WINDOW: usize = 8;
+
+// Send `request` until `REQUESTS_PER_CLIENT` answers arrived, refilling the
+// window after losses.
+async fn client(server: SocketAddr, request: Vec<u8>) {
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = [0; 128];
+    let mut answered = 0;
+    while answered < REQUESTS_PER_CLIENT {
+        for _ in 0..WINDOW.min(REQUESTS_PER_CLIENT - answered) {
+            sock.send_to(&request, server).await.unwrap();
+        }
+        while let Ok(res) = time::timeout(Duration::from_millis(20), sock.recv_from(&mut buf)).await
+        {
+            res.unwrap();
+            answered += 1;
+            if answered == REQUESTS_PER_CLIENT {
+                break;
+            }
+            sock.send_to(&request, server).await.unwrap();
+        }
+    }
+}
+
+// What `client` number `n` sends, a registered client pinging again is told
+// that it is waiting.
+fn request(kind: &str, n: usize) -> Vec<u8> {
+    match kind {
+        "ping" => padded(format!("ping {}", n).as_bytes()),
+        _ => padded(MAPPING_REQUEST),
+    }
+}
+
+fn udp_pps(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let unlimited = Rate {
+        per_sec: f64::MAX,
+        burst: f64::MAX,
+    };
+    let limits = LimitConfig {
+        per_ip: unlimited,
+        per_prefix: unlimited,
+        ..LimitConfig::default()
+    };
+
+    let mut group = c.benchmark_group("udp_pps");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10))
+        .throughput(Throughput::Elements((CLIENTS * REQUESTS_PER_CLIENT) as u64));
+    for workers in [1, 2, 4] {
+        let server: SocketAddr = format!("127.0.0.1:{}", 18290 + workers).parse().unwrap();
+        let rendezvous_server = RendezvousServer::builder()
+            .udp_listen([server])
+            .udp_workers(workers)
+            .limits(limits.clone())
+            .build();
+        rt.spawn(rendezvous_server.run());
+        for kind in ["mapping", "ping"] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}/workers", kind), workers),
+                &server,
+                |b, &server| {
+                    b.to_async(&rt).iter_custom(|iters| async move {
+                        let mut total = Duration::ZERO;
+                        for _ in 0..iters {
+                            let start = Instant::now();
+                            let clients: Vec<_> = (0..CLIENTS)
+                                .map(|n| tokio::spawn(client(server, request(kind, n))))
+                                .collect();
+                            for client in clients {
+                                client.await.unwrap();
+                            }
+                            total += start.elapsed();
+                        }
+                        total
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, udp_pps);
+criterion_main!(benches);
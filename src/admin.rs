@@ -0,0 +1,165 @@
+use std::net::SocketAddr;
+
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    RendezvousServer, ServerState,
+    http::{self, Request, Response},
+    relay::{RelayServer, RelayState},
+};
+
+/// Serve the admin API. It can evict clients, bind it to a local address.
+///
+/// - `GET /sessions`: TCP sessions, UDP registrations, pending pairings and
+///   relay allocations
+/// - `DELETE /tcp/<session id>`: close a TCP rendezvous session
+/// - `DELETE /udp/<address>`: forget one UDP registration
+/// - `DELETE /udp`: forget all UDP registrations
+/// - `DELETE /relay/<room>`: drop the allocations of a relay room
+///
+/// TCP and UDP clients are those of `server`, relay allocations those of
+/// `relay`.
+pub async fn admin_server(
+    listen_addr: SocketAddr,
+    server: Option<RendezvousServer>,
+    relay: Option<RelayServer>,
+) {
+    http::serve(listen_addr, move |request| {
+        handle(
+            request,
+            server.as_ref().map(|server| server.state.as_ref()),
+            relay.as_ref().map(|relay| relay.state.as_ref()),
+        )
+    })
+    .await
+}
+
+fn handle(request: &Request, state: Option<&ServerState>, relay: Option<&RelayState>) -> Response {
+    let path: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), path.as_slice()) {
+        ("GET", ["sessions"]) => Response::json(200, sessions(state, relay)),
+        ("DELETE", ["tcp", id]) => match id.parse::<usize>() {
+            Ok(id) => found(state.is_some_and(|state| evict_tcp(state, id))),
+            Err(_) => bad_request("session id must be a number"),
+        },
+        ("DELETE", ["udp", addr]) => match addr.parse::<SocketAddr>() {
+            Ok(addr) => found(state.is_some_and(|state| state.udp.lock().unwrap().remove(addr))),
+            Err(_) => bad_request("udp registration must be an address"),
+        },
+        ("DELETE", ["udp"]) => match state {
+            Some(state) => {
+                state.udp.lock().unwrap().clear();
+                found(true)
+            }
+            None => found(false),
+        },
+        ("DELETE", ["relay", room]) => found(relay.is_some_and(|relay| relay.clear_room(room) > 0)),
+        (_, ["sessions" | "tcp" | "udp" | "relay", ..]) => Response::json(
+            405,
+            json!({ "error": format!("{} not allowed here", request.method) }),
+        ),
+        _ => Response::not_found(),
+    }
+}
+
+fn found(found: bool) -> Response {
+    if found {
+        Response::json(200, json!({ "ok": true }))
+    } else {
+        Response::json(404, json!({ "error": "no such session" }))
+    }
+}
+
+fn bad_request(error: &str) -> Response {
+    Response::json(400, json!({ "error": error }))
+}
+
+fn evict_tcp(state: &ServerState, id: usize) -> bool {
+    match state.tcp.lock().unwrap().sessions.get(&id) {
+        Some(registration) => {
+            info!(session = id, peer = %registration.addr, "Tcp evicting session");
+            registration.evict.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+fn sessions(state: Option<&ServerState>, relay: Option<&RelayState>) -> serde_json::Value {
+    let (mut tcp, mut udp, mut udp_pending) = (Vec::new(), Vec::new(), Vec::new());
+    if let Some(state) = state {
+        tcp = state
+            .tcp
+            .lock()
+            .unwrap()
+            .sessions
+            .iter()
+            .map(|(id, r)| {
+                json!({
+                    "id": id,
+                    "address": r.addr.to_string(),
+                    "age_secs": r.registered.elapsed().as_secs(),
+                    "symmetric": r.hello.symmetric,
+                    "candidate": r.hello.candidate.map(|addr| addr.to_string()),
+                    "paired": r.paired.map(|addr| addr.to_string()),
+                })
+            })
+            .collect();
+        tcp.sort_by_key(|session| session["id"].as_u64());
+
+        let udp_state = state.udp.lock().unwrap();
+        udp = udp_state
+            .nat_addr
+            .iter()
+            .map(|addr| {
+                json!({
+                    "address": addr.to_string(),
+                    "id": udp_state
+                        .client_ids
+                        .iter()
+                        .find(|(_, a)| *a == addr)
+                        .map(|(id, _)| id),
+                    "age_secs": udp_state.registered.get(addr).map(|at| at.elapsed().as_secs()),
+                    "candidate": udp_state.candidates.get(addr).map(|addr| addr.to_string()),
+                })
+            })
+            .collect();
+        // a lone UDP registration waits for a second client
+        if udp_state.nat_addr.len() == 1 {
+            udp_pending.push(udp_state.nat_addr[0].to_string());
+        }
+    }
+
+    let relay: Vec<_> = relay
+        .map(|relay| {
+            relay
+                .allocations
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(addr, a)| {
+                    json!({
+                        "address": addr.to_string(),
+                        "room": a.room,
+                        "idle_secs": a.last_seen.elapsed().as_secs(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    json!({
+        "pending": {
+            "tcp": tcp
+                .iter()
+                .filter(|session| session["paired"].is_null())
+                .map(|session| session["id"].clone())
+                .collect::<Vec<_>>(),
+            "udp": udp_pending,
+        },
+        "tcp": tcp,
+        "udp": udp,
+        "relay": relay,
+    })
+}
@@ -0,0 +1,269 @@
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// Datagrams moved per system call.
+pub const BATCH: usize = 32;
+// Rendezvous requests are padded to 128 bytes, anything up to a typical MTU
+// is read whole.
+const MAX_DATAGRAM: usize = 1500;
+
+/// Receive buffers for up to `BATCH` datagrams, filled by one `recvmmsg`
+/// on Linux and one `recv_from` elsewhere.
+pub struct RecvBatch {
+    bufs: Vec<[u8; MAX_DATAGRAM]>,
+    // buffer index, length and sender of each datagram
+    received: Vec<(usize, usize, SocketAddr)>,
+    // datagrams of the last `recv` cut short by their buffer and dropped
+    truncated: usize,
+}
+
+impl Default for RecvBatch {
+    fn default() -> Self {
+        RecvBatch {
+            bufs: vec![[0; MAX_DATAGRAM]; BATCH],
+            received: Vec::with_capacity(BATCH),
+            truncated: 0,
+        }
+    }
+}
+
+impl RecvBatch {
+    /// Wait for at least one datagram and take whatever else is queued.
+    pub async fn recv(&mut self, sock: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+        self.truncated = 0;
+        #[cfg(target_os = "linux")]
+        loop {
+            sock.readable().await?;
+            match sock.try_io(tokio::io::Interest::READABLE, || {
+                sys::recvmmsg(sock, &mut self.bufs, &mut self.received)
+            }) {
+                Ok(truncated) => {
+                    self.truncated = truncated;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let (len, addr) = sock.recv_from(&mut self.bufs[0]).await?;
+            self.received.push((0, len, addr));
+            Ok(())
+        }
+    }
+
+    /// Datagrams of the last `recv` that were larger than a buffer. They are
+    /// dropped rather than handled cut short. Only counted on Linux, where
+    /// `recvmmsg` flags them.
+    pub fn truncated(&self) -> usize {
+        self.truncated
+    }
+
+    /// The datagrams of the last `recv` with their senders.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .map(|(index, len, addr)| (&self.bufs[*index][..*len], *addr))
+    }
+}
+
+/// Send every datagram, batched with `sendmmsg` on Linux. Datagrams the
+/// kernel refuses are skipped and returned with their error.
+pub async fn send(sock: &UdpSocket, msgs: &[(SocketAddr, &[u8])]) -> Vec<(SocketAddr, io::Error)> {
+    let mut failed = Vec::new();
+    #[cfg(target_os = "linux")]
+    {
+        let mut sent = 0;
+        while sent < msgs.len() {
+            if let Err(err) = sock.writable().await {
+                failed.extend(msgs[sent..].iter().map(|(to, _)| (*to, err.kind().into())));
+                break;
+            }
+            match sock.try_io(tokio::io::Interest::WRITABLE, || {
+                sys::sendmmsg(sock, &msgs[sent..(sent + BATCH).min(msgs.len())])
+            }) {
+                Ok(count) => sent += count,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                // the error belongs to the first datagram of the batch
+                Err(err) => {
+                    failed.push((msgs[sent].0, err));
+                    sent += 1;
+                }
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    for (to, payload) in msgs {
+        if let Err(err) = sock.send_to(payload, to).await {
+            failed.push((*to, err));
+        }
+    }
+    failed
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{io, mem, net::SocketAddr, os::fd::AsRawFd, ptr};
+
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    use super::{BATCH, MAX_DATAGRAM};
+
+    /// Returns how many datagrams were dropped for not fitting their buffer.
+    pub fn recvmmsg(
+        sock: &UdpSocket,
+        bufs: &mut [[u8; MAX_DATAGRAM]],
+        received: &mut Vec<(usize, usize, SocketAddr)>,
+    ) -> io::Result<usize> {
+        // SAFETY: all zero is a valid sockaddr_storage and mmsghdr
+        let mut addrs: [libc::sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut hdrs: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        for ((hdr, iovec), addr) in hdrs.iter_mut().zip(&mut iovecs).zip(&mut addrs) {
+            hdr.msg_hdr.msg_name = ptr::from_mut(addr).cast();
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_hdr.msg_iov = iovec;
+            hdr.msg_hdr.msg_iovlen = 1;
+        }
+        // SAFETY: every header points at live buffers and address storage
+        // of the advertised sizes
+        let count = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                iovecs.len() as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut truncated = 0;
+        for (index, (hdr, addr)) in hdrs.iter().zip(addrs).take(count as usize).enumerate() {
+            if hdr.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                truncated += 1;
+                continue;
+            }
+            // SAFETY: the kernel filled `addr` with `msg_namelen` bytes
+            let addr = unsafe { SockAddr::new(addr, hdr.msg_hdr.msg_namelen) };
+            // datagrams from anything but an IP socket are skipped, the
+            // index keeps the rest with their buffers
+            if let Some(addr) = addr.as_socket() {
+                received.push((index, hdr.msg_len as usize, addr));
+            }
+        }
+        Ok(truncated)
+    }
+
+    /// Returns how many of `msgs` went out.
+    pub fn sendmmsg(sock: &UdpSocket, msgs: &[(SocketAddr, &[u8])]) -> io::Result<usize> {
+        let addrs: Vec<SockAddr> = msgs.iter().map(|(to, _)| SockAddr::from(*to)).collect();
+        let mut iovecs: Vec<libc::iovec> = msgs
+            .iter()
+            .map(|(_, payload)| libc::iovec {
+                iov_base: payload.as_ptr().cast_mut().cast(),
+                iov_len: payload.len(),
+            })
+            .collect();
+        let mut hdrs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(&addrs)
+            .map(|(iovec, addr)| {
+                // SAFETY: all zero is a valid mmsghdr
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_name = addr.as_ptr().cast_mut().cast();
+                hdr.msg_hdr.msg_namelen = addr.len();
+                hdr.msg_hdr.msg_iov = iovec;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            })
+            .collect();
+        // SAFETY: every header points at live payloads and addresses, the
+        // kernel only reads them
+        let count = unsafe {
+            libc::sendmmsg(
+                sock.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                hdrs.len() as _,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_datagrams_with_their_senders() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = sock.local_addr().unwrap();
+        let mut senders = Vec::new();
+        for n in 0..3u8 {
+            let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            sender.send_to(&[n; 3], server).await.unwrap();
+            senders.push(sender);
+        }
+
+        let mut batch = RecvBatch::default();
+        let mut received = Vec::new();
+        while received.len() < senders.len() {
+            batch.recv(&sock).await.unwrap();
+            received.extend(batch.iter().map(|(data, addr)| (data.to_vec(), addr)));
+        }
+        for (n, sender) in senders.iter().enumerate() {
+            let from = sender.local_addr().unwrap();
+            assert!(received.contains(&(vec![n as u8; 3], from)));
+        }
+
+        let msgs: Vec<_> = senders
+            .iter()
+            .map(|sender| (sender.local_addr().unwrap(), &b"reply"[..]))
+            .collect();
+        assert!(send(&sock, &msgs).await.is_empty());
+        let mut buf = [0; 16];
+        for sender in &senders {
+            let (len, from) = sender.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..len], from), (&b"reply"[..], server));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn drops_datagrams_larger_than_a_buffer() {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = sock.local_addr().unwrap();
+        sender
+            .send_to(&[1; MAX_DATAGRAM + 1], server)
+            .await
+            .unwrap();
+        sender.send_to(&[2; 3], server).await.unwrap();
+
+        let mut batch = RecvBatch::default();
+        let (mut received, mut truncated) = (Vec::new(), 0);
+        while received.len() + truncated < 2 {
+            batch.recv(&sock).await.unwrap();
+            truncated += batch.truncated();
+            received.extend(batch.iter().map(|(data, _)| data.to_vec()));
+        }
+        assert_eq!(truncated, 1);
+        assert_eq!(received, vec![vec![2; 3]]);
+    }
+}
@@ -1,50 +1,946 @@
 use nat_traversal_test::{
-    tcp::{create_socket, nat_client, nat_server},
-    tcp_stun_server,
-    udp::nat_client as udp_nat_client,
-    udp_stun_server,
+    DEFAULT_ADDR, RendezvousServer,
+    admin::admin_server,
+    cluster::ClusterConfig,
+    keepalive::KeepaliveConfig,
+    lifetime::{self, LifetimeConfig, Protocol},
+    limits::{Cidr, LimitConfig, Rate},
+    metrics::{Metrics, metrics_server},
+    mux::Mux,
+    portmap::{self, PortMapConfig, PortMapping},
+    relay::{RelayIssuer, RelayServer},
+    shutdown::Shutdown,
+    store::FileStore,
+    strategy::{self, DEFAULT_SWEEP_WIDTH, ListenOnly, PortSweep, SimultaneousOpen},
+    supervisor::{SupervisedUdp, SupervisorConfig},
+    tcp::{Endpoint, MuxSession, PunchConfig, create_socket, nat_client, nat_server},
+    traversal::{any_addr_for, classify, failure_report},
+    udp,
 };
 
-use std::net::SocketAddr;
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use std::{
+    future::Future,
+    io::IsTerminal,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+const LINGER: Duration = Duration::from_secs(3);
 
 fn main() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let rt = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(4)
         .enable_all()
         .build()
         .unwrap();
-    let matches = clap::Command::new("nat_traversal")
+    let matches = Command::new("nat_traversal")
         .name("Nat traversal demo")
         .about("Nat traversal demo on TCP and UDP")
         .version(clap::crate_version!())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
-            clap::Arg::new("address")
-                .help("stun server address")
-                .value_parser(clap::value_parser!(SocketAddr)),
+            Arg::new("log-format")
+                .long("log-format")
+                .help("log lines as plain text, multi-line pretty text or json objects")
+                .value_parser(["text", "pretty", "json"])
+                .default_value("text")
+                .global(true)
+                .action(ArgAction::Set),
         )
-        .arg(
-            clap::Arg::new("protocol")
-                .short('p')
-                .help("use TCP or UDP")
-                .value_parser(["tcp", "udp"])
-                .default_value("tcp")
-                .action(clap::ArgAction::Set),
+        .subcommand(
+            Command::new("server")
+                .about("Run the stun/rendezvous server for TCP and UDP clients")
+                .arg(
+                    listen_arg(DEFAULT_ADDR)
+                        .num_args(1..)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("tcp-listen")
+                        .long("tcp-listen")
+                        .help("addresses for TCP rendezvous, overrides --listen")
+                        .value_parser(value_parser!(SocketAddr))
+                        .num_args(1..)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("udp-listen")
+                        .long("udp-listen")
+                        .help("addresses for UDP rendezvous, overrides --listen")
+                        .value_parser(value_parser!(SocketAddr))
+                        .num_args(1..)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("protocol")
+                        .short('p')
+                        .long("protocol")
+                        .help("serve TCP rendezvous, UDP rendezvous or both")
+                        .value_parser(["tcp", "udp", "both"])
+                        .default_value("both")
+                        .action(ArgAction::Set),
+                )
+                .arg(metrics_arg())
+                .arg(admin_arg())
+                .arg(store_arg())
+                .arg(
+                    Arg::new("alternative")
+                        .long("alternative")
+                        .help("server that waiting clients are sent to on shutdown")
+                        .value_parser(value_parser!(SocketAddr))
+                        .action(ArgAction::Set),
+                )
+                .args(limit_args())
+                .arg(
+                    Arg::new("drain-timeout")
+                        .long("drain-timeout")
+                        .help("seconds pairings in flight may take to finish on shutdown")
+                        .value_parser(value_parser!(u64))
+                        .default_value("10")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("udp-workers")
+                        .long("udp-workers")
+                        .help("udp sockets per listen address sharing the port, one per cpu by default")
+                        .value_parser(value_parser!(usize))
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("idle-timeout")
+                        .long("idle-timeout")
+                        .help("seconds a tcp rendezvous session may go without a heartbeat")
+                        .value_parser(value_parser!(u64))
+                        .default_value("45")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("cluster")
+                        .long("cluster")
+                        .help("gossip address of this node, reachable from the other nodes")
+                        .value_parser(value_parser!(SocketAddr))
+                        .requires("cluster-secret")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("cluster-secret")
+                        .long("cluster-secret")
+                        .help("secret shared by the nodes of the cluster")
+                        .requires("cluster")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("cluster-peer")
+                        .long("cluster-peer")
+                        .help("gossip address of another node of the cluster")
+                        .value_parser(value_parser!(SocketAddr))
+                        .requires("cluster")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("relay")
+                        .long("relay")
+                        .help("relay that introduced peers get a room on")
+                        .value_parser(value_parser!(SocketAddr))
+                        .requires("relay-secret")
+                        .action(ArgAction::Set),
+                )
+                .arg(secret_arg("relay-secret").requires("relay"))
+                .arg(
+                    Arg::new("v6-only")
+                        .long("v6-only")
+                        .help("keep IPv6 sockets off IPv4, listen on an IPv4 address separately")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("client")
+                .about("Traverse the NAT to a peer introduced by a stun server")
+                .subcommand_required(true)
+                .subcommand(
+                    client_args(Command::new("tcp").about("Punch a TCP connection"))
+                        .arg(
+                            Arg::new("syn-ttl")
+                                .long("syn-ttl")
+                                .help("precede punch attempts with a SYN of this TTL, enough to leave our NAT but not to reach the peer's")
+                                .value_parser(value_parser!(u32).range(1..=255))
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            Arg::new("syn-ttl-every")
+                                .long("syn-ttl-every")
+                                .help("send the low TTL SYN before every n-th punch attempt only")
+                                .value_parser(value_parser!(u32).range(1..))
+                                .default_value("1")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            Arg::new("strategy")
+                                .long("strategy")
+                                .help("how to punch, auto picks from both ends' NATs and the role the server assigns")
+                                .value_parser(["auto", "simultaneous-open", "listen", "sweep"])
+                                .default_value("auto")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            Arg::new("streams")
+                                .long("streams")
+                                .help("multiplex the punched connection, open this many streams on it and echo the peer's, both ends need it")
+                                .value_parser(value_parser!(u32))
+                                .action(ArgAction::Set),
+                        ),
+                )
+                .subcommand(
+                    client_args(Command::new("udp").about("Punch a UDP path"))
+                        .arg(
+                            Arg::new("max-migrations")
+                                .long("max-migrations")
+                                .help("how many times a lost UDP path is punched again")
+                                .value_parser(value_parser!(u32))
+                                .default_value("3")
+                                .action(ArgAction::Set),
+                        )
+                        .arg(
+                            Arg::new("streams")
+                                .long("streams")
+                                .help("multiplex the punched path with retransmissions, open this many streams on it and echo the peer's, both ends need it")
+                                .value_parser(value_parser!(u32))
+                                .action(ArgAction::Set),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("classify")
+                .about("Classify the NAT by asking several stun servers for our mapping")
+                .arg(servers_arg())
+                .arg(bind_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            Command::new("lifetime")
+                .about("Measure how long the NAT keeps idle bindings to a stun server")
+                .arg(
+                    Arg::new("address")
+                        .help("stun server address")
+                        .required(true)
+                        .value_parser(value_parser!(SocketAddr)),
+                )
+                .arg(
+                    Arg::new("protocol")
+                        .short('p')
+                        .help("measure UDP, TCP or both")
+                        .value_parser(["tcp", "udp", "both"])
+                        .default_value("both")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("max")
+                        .long("max")
+                        .help("longest idle period tried, in seconds")
+                        .value_parser(value_parser!(u64))
+                        .default_value("300")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("resolution")
+                        .long("resolution")
+                        .help("precision of the measurement, in seconds")
+                        .value_parser(value_parser!(u64))
+                        .default_value("5")
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("relay")
+                .about("Run a UDP relay forwarding datagrams between two peers of a room")
+                .arg(listen_arg("[::]:8091"))
+                .arg(secret_arg("secret").required(true))
+                .arg(metrics_arg())
+                .arg(admin_arg())
+                .arg(store_arg())
+                .args(limit_args()),
         )
         .get_matches();
-    let protocol = matches.get_one::<String>("protocol").unwrap();
+    init_logging(matches.get_one::<String>("log-format").unwrap());
 
-    if std::env::args().len() == 1 {
-        rt.spawn(udp_stun_server());
-        rt.block_on(tcp_stun_server());
+    match matches.subcommand() {
+        Some(("server", matches)) => run_server(&rt, matches),
+        Some(("client", matches)) => match matches.subcommand() {
+            Some(("tcp", matches)) => run_tcp_client(&rt, matches),
+            Some(("udp", matches)) => run_udp_client(&rt, matches),
+            _ => unreachable!("client subcommand is required"),
+        },
+        Some(("classify", matches)) => {
+            let servers = servers(matches);
+            let bind_addr = bind_addr(matches, &servers);
+            let json = matches.get_one::<String>("output").unwrap() == "json";
+            let classification = match rt.block_on(classify(&servers, bind_addr)) {
+                Ok(classification) => classification,
+                Err(err) if json => finish(
+                    &rt,
+                    failure_report("classify", &servers, &err.to_string()),
+                    None,
+                ),
+                Err(err) => {
+                    info!("Classification failed: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            if json {
+                println!("{}", classification.report());
+            } else {
+                info!("Classification: {:?}", classification);
+            }
+        }
+        Some(("lifetime", matches)) => run_lifetime(&rt, matches),
+        Some(("relay", matches)) => {
+            let metrics = Arc::new(Metrics::default());
+            let mut builder = RelayServer::builder()
+                .metrics(Arc::clone(&metrics))
+                .listen(*matches.get_one::<SocketAddr>("listen").unwrap())
+                .secret(matches.get_one::<String>("secret").unwrap())
+                .limits(limits(matches));
+            if let Some(store) = open_store(matches, metrics) {
+                builder = builder.store(Box::new(store));
+            }
+            let relay = builder.build();
+            spawn_http(&rt, matches, None, Some(&relay));
+            rt.block_on(relay.run());
+        }
+        _ => unreachable!("subcommand is required"),
     }
+}
 
-    let stun_addr = *matches.get_one::<SocketAddr>("address").unwrap();
-    if protocol == "tcp" {
-        let (socket, listen_addr) = create_socket(socket2::Domain::for_address(stun_addr));
-        rt.spawn(nat_client(socket, stun_addr));
-        rt.block_on(nat_server(listen_addr));
-    } else if protocol == "udp" {
-        rt.block_on(udp_nat_client(stun_addr));
+// Logs go to stderr so json reports on stdout stay parseable. RUST_LOG
+// filters as usual, e.g. `RUST_LOG=nat_traversal_test=debug`.
+fn init_logging(format: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match format {
+        "json" => builder.json().init(),
+        "pretty" => builder.pretty().init(),
+        _ => builder.init(),
     }
 }
+
+fn listen_arg(default: &'static str) -> Arg {
+    Arg::new("listen")
+        .long("listen")
+        .help("address to listen on")
+        .value_parser(value_parser!(SocketAddr))
+        .default_value(default)
+        .action(ArgAction::Set)
+}
+
+fn metrics_arg() -> Arg {
+    Arg::new("metrics")
+        .long("metrics")
+        .help("serve Prometheus metrics on http://<address>/metrics")
+        .value_parser(value_parser!(SocketAddr))
+        .action(ArgAction::Set)
+}
+
+fn limit_args() -> Vec<Arg> {
+    let number = |id: &'static str, help: &'static str, default: &'static str| {
+        Arg::new(id)
+            .long(id)
+            .help(help)
+            .value_parser(value_parser!(f64))
+            .default_value(default)
+            .action(ArgAction::Set)
+    };
+    let prefix = |id: &'static str, help: &'static str, default: &'static str| {
+        Arg::new(id)
+            .long(id)
+            .help(help)
+            .value_parser(value_parser!(u8))
+            .default_value(default)
+            .action(ArgAction::Set)
+    };
+    let cidrs = |id: &'static str, help: &'static str| {
+        Arg::new(id)
+            .long(id)
+            .help(help)
+            .value_parser(value_parser!(Cidr))
+            .num_args(1..)
+            .action(ArgAction::Append)
+    };
+    vec![
+        number("rate", "requests per second allowed from one address", "10"),
+        number("burst", "requests one address may send in a burst", "20"),
+        number(
+            "prefix-rate",
+            "requests per second allowed from one prefix",
+            "50",
+        ),
+        number(
+            "prefix-burst",
+            "requests one prefix may send in a burst",
+            "100",
+        ),
+        prefix("prefix-v4", "IPv4 prefix length sharing a budget", "24"),
+        prefix("prefix-v6", "IPv6 prefix length sharing a budget", "48"),
+        Arg::new("max-sessions-per-ip")
+            .long("max-sessions-per-ip")
+            .help("concurrent TCP rendezvous sessions allowed from one address")
+            .value_parser(value_parser!(usize))
+            .default_value("8")
+            .action(ArgAction::Set),
+        cidrs("allow", "only serve these address blocks, e.g. 10.0.0.0/8"),
+        cidrs("deny", "never serve these address blocks"),
+    ]
+}
+
+fn limits(matches: &ArgMatches) -> LimitConfig {
+    let number = |id: &str| *matches.get_one::<f64>(id).unwrap();
+    let cidrs = |id: &str| {
+        matches
+            .get_many::<Cidr>(id)
+            .map(|cidrs| cidrs.copied().collect())
+            .unwrap_or_default()
+    };
+    LimitConfig {
+        per_ip: Rate {
+            per_sec: number("rate"),
+            burst: number("burst"),
+        },
+        per_prefix: Rate {
+            per_sec: number("prefix-rate"),
+            burst: number("prefix-burst"),
+        },
+        prefix_v4: *matches.get_one::<u8>("prefix-v4").unwrap(),
+        prefix_v6: *matches.get_one::<u8>("prefix-v6").unwrap(),
+        max_sessions_per_ip: *matches.get_one::<usize>("max-sessions-per-ip").unwrap(),
+        allow: cidrs("allow"),
+        deny: cidrs("deny"),
+    }
+}
+
+fn admin_arg() -> Arg {
+    Arg::new("admin")
+        .long("admin")
+        .help("serve the admin API on <address>, keep it local")
+        .value_parser(value_parser!(SocketAddr))
+        .action(ArgAction::Set)
+}
+
+fn secret_arg(id: &'static str) -> Arg {
+    Arg::new(id)
+        .long(id)
+        .help("secret shared by the relay and the rendezvous servers issuing its tickets")
+        .action(ArgAction::Set)
+}
+
+fn store_arg() -> Arg {
+    Arg::new("store")
+        .long("store")
+        .help("keep registrations in this json file so they survive restarts")
+        .value_parser(value_parser!(PathBuf))
+        .action(ArgAction::Set)
+}
+
+fn open_store(matches: &ArgMatches, metrics: Arc<Metrics>) -> Option<FileStore> {
+    let path = matches.get_one::<PathBuf>("store")?;
+    let store = FileStore::open(path, metrics)
+        .unwrap_or_else(|err| panic!("cannot open store {}: {}", path.display(), err));
+    Some(store)
+}
+
+fn spawn_http(
+    rt: &tokio::runtime::Runtime,
+    matches: &ArgMatches,
+    server: Option<&RendezvousServer>,
+    relay: Option<&RelayServer>,
+) {
+    if let Some(addr) = matches.get_one::<SocketAddr>("metrics") {
+        let metrics = server
+            .map(|server| server.metrics())
+            .or(relay.map(|relay| relay.metrics()))
+            .cloned()
+            .unwrap_or_default();
+        rt.spawn(metrics_server(*addr, metrics));
+    }
+    if let Some(addr) = matches.get_one::<SocketAddr>("admin") {
+        rt.spawn(admin_server(*addr, server.cloned(), relay.cloned()));
+    }
+}
+
+fn servers_arg() -> Arg {
+    Arg::new("address")
+        .help("stun server addresses, tried in order for rendezvous")
+        .required(true)
+        .value_parser(value_parser!(SocketAddr))
+        .num_args(1..)
+        .action(ArgAction::Append)
+}
+
+fn bind_arg() -> Arg {
+    Arg::new("bind")
+        .long("bind")
+        .help("local address to bind, any address with an ephemeral port by default")
+        .value_parser(value_parser!(SocketAddr))
+        .action(ArgAction::Set)
+}
+
+fn output_arg() -> Arg {
+    Arg::new("output")
+        .long("output")
+        .help("print a json report and exit, non-zero on failure")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .action(ArgAction::Set)
+}
+
+fn client_args(command: Command) -> Command {
+    command
+        .arg(servers_arg())
+        .arg(bind_arg())
+        .arg(
+            Arg::new("binding-timeout")
+                .long("binding-timeout")
+                .help("NAT binding timeout in seconds, keepalives are sent three times within it")
+                .value_parser(value_parser!(u64))
+                .default_value("30")
+                .action(ArgAction::Set),
+        )
+        .arg(output_arg())
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .help("seconds before a traversal is reported as failed in json output")
+                .value_parser(value_parser!(u64))
+                .default_value("60")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("map-port")
+                .long("map-port")
+                .help("ask the gateway to forward our port via PCP, NAT-PMP or UPnP and tell the peer")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("gateway")
+                .long("gateway")
+                .help("gateway asked for the port mapping, the default route's by default")
+                .value_parser(value_parser!(Ipv4Addr))
+                .action(ArgAction::Set),
+        )
+}
+
+fn servers(matches: &ArgMatches) -> Vec<SocketAddr> {
+    matches
+        .get_many::<SocketAddr>("address")
+        .unwrap()
+        .copied()
+        .collect()
+}
+
+fn bind_addr(matches: &ArgMatches, servers: &[SocketAddr]) -> SocketAddr {
+    matches
+        .get_one::<SocketAddr>("bind")
+        .copied()
+        .unwrap_or_else(|| any_addr_for(servers[0]))
+}
+
+fn keepalive(matches: &ArgMatches) -> KeepaliveConfig {
+    KeepaliveConfig::for_binding_timeout(Duration::from_secs(
+        *matches.get_one::<u64>("binding-timeout").unwrap(),
+    ))
+}
+
+// Ask the gateway to forward `port` if --map-port is set. Traversal goes on
+// without a mapping when it refuses.
+fn map_port(
+    rt: &tokio::runtime::Runtime,
+    matches: &ArgMatches,
+    transport: Protocol,
+    port: u16,
+) -> Option<PortMapping> {
+    if !matches.get_flag("map-port") {
+        return None;
+    }
+    let config = PortMapConfig {
+        gateway: matches.get_one::<Ipv4Addr>("gateway").copied(),
+        ..Default::default()
+    };
+    match rt.block_on(portmap::map_port(transport, port, &config)) {
+        Ok(mapping) => Some(mapping),
+        Err(err) => {
+            info!("No port mapping: {}", err);
+            None
+        }
+    }
+}
+
+// Run `client` until it ends or we are told to stop, renewing the port
+// mapping meanwhile and removing it afterwards.
+fn run_mapped(
+    rt: &tokio::runtime::Runtime,
+    mapping: Option<PortMapping>,
+    client: impl Future<Output = ()>,
+) {
+    rt.block_on(async move {
+        let Some(mut mapping) = mapping else {
+            return client.await;
+        };
+        let renew = async {
+            loop {
+                tokio::time::sleep((mapping.lifetime / 2).max(Duration::from_secs(1))).await;
+                if let Err(err) = mapping.renew().await {
+                    info!("Failed to renew port mapping: {}", err);
+                }
+            }
+        };
+        tokio::select! {
+            _ = client => {}
+            _ = renew => {}
+            _ = shutdown_signal() => info!("Shutting down"),
+        }
+        remove_mapping(Some(mapping)).await;
+    });
+}
+
+async fn remove_mapping(mapping: Option<PortMapping>) {
+    if let Some(mapping) = mapping
+        && let Err(err) = mapping.remove().await
+    {
+        info!("Failed to remove port mapping: {}", err);
+    }
+}
+
+fn json_timeout(matches: &ArgMatches) -> Option<Duration> {
+    (matches.get_one::<String>("output").unwrap() == "json")
+        .then(|| Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap()))
+}
+
+fn run_server(rt: &tokio::runtime::Runtime, matches: &ArgMatches) {
+    let listen_addrs = |id: &str| -> Vec<SocketAddr> {
+        matches
+            .get_many::<SocketAddr>(id)
+            .or_else(|| matches.get_many::<SocketAddr>("listen"))
+            .unwrap()
+            .copied()
+            .collect()
+    };
+    let protocol = matches.get_one::<String>("protocol").unwrap().as_str();
+    let metrics = Arc::new(Metrics::default());
+    let mut builder = RendezvousServer::builder()
+        .metrics(Arc::clone(&metrics))
+        .v6_only(matches.get_flag("v6-only"))
+        .idle_timeout(Duration::from_secs(
+            *matches.get_one::<u64>("idle-timeout").unwrap(),
+        ))
+        .limits(limits(matches))
+        .shutdown(Shutdown::new(
+            matches.get_one::<SocketAddr>("alternative").copied(),
+            Duration::from_secs(*matches.get_one::<u64>("drain-timeout").unwrap()),
+        ));
+    if protocol != "tcp" {
+        builder = builder.udp_listen(listen_addrs("udp-listen"));
+    }
+    if protocol != "udp" {
+        builder = builder.tcp_listen(listen_addrs("tcp-listen"));
+    }
+    if let Some(workers) = matches.get_one::<usize>("udp-workers") {
+        builder = builder.udp_workers(*workers);
+    }
+    if let Some(store) = open_store(matches, metrics) {
+        builder = builder.store(Box::new(store));
+    }
+    if let Some(node) = matches.get_one::<SocketAddr>("cluster") {
+        let peers = matches
+            .get_many::<SocketAddr>("cluster-peer")
+            .map(|peers| peers.copied().collect())
+            .unwrap_or_default();
+        let secret = matches.get_one::<String>("cluster-secret").unwrap().clone();
+        builder = builder.cluster(ClusterConfig {
+            node: *node,
+            peers,
+            secret,
+        });
+    }
+    if let Some(relay) = matches.get_one::<SocketAddr>("relay") {
+        let secret = matches.get_one::<String>("relay-secret").unwrap();
+        builder = builder.relay(RelayIssuer::new(*relay, secret));
+    }
+    let server = builder.build();
+    spawn_http(rt, matches, Some(&server), None);
+    rt.spawn({
+        let shutdown = server.shutdown().clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down");
+            shutdown.trigger();
+        }
+    });
+    // a server failing to bind panics
+    if let Err(err) = rt.block_on(rt.spawn(server.run())) {
+        std::panic::resume_unwind(err.into_panic());
+    }
+    info!("Server stopped");
+}
+
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+fn run_tcp_client(rt: &tokio::runtime::Runtime, matches: &ArgMatches) {
+    let servers = servers(matches);
+    let keepalive = keepalive(matches);
+    let punch = PunchConfig {
+        low_ttl: matches.get_one::<u32>("syn-ttl").copied(),
+        low_ttl_every: *matches.get_one::<u32>("syn-ttl-every").unwrap(),
+        strategy: match matches.get_one::<String>("strategy").unwrap().as_str() {
+            "simultaneous-open" => |intro| Box::new(SimultaneousOpen::new(intro.peer)),
+            "listen" => |intro| Box::new(ListenOnly::new(intro.peer)),
+            "sweep" => |intro| Box::new(PortSweep::new(intro.peer, DEFAULT_SWEEP_WIDTH)),
+            _ => strategy::select,
+        },
+    };
+    let (socket, listen_addr) = create_socket(bind_addr(matches, &servers));
+    let (accepted, inbound) = tokio::sync::mpsc::unbounded_channel();
+    // tells our punched connections apart in the handshake with the peer and
+    // keeps one connection per peer between `nat_server` and `nat_client`
+    let mut endpoint = Endpoint::new();
+    let mapping = map_port(rt, matches, Protocol::Tcp, listen_addr.port());
+    if let Some(mapping) = &mapping {
+        endpoint = endpoint.with_candidate(mapping.external);
+    }
+    if let Some(streams) = matches.get_one::<u32>("streams").copied() {
+        let (multiplexed, sessions) = endpoint.multiplexed();
+        endpoint = multiplexed;
+        rt.spawn(echo_streams(sessions, streams));
+    }
+    if let Some(timeout) = json_timeout(matches) {
+        rt.spawn(nat_server(
+            listen_addr,
+            endpoint.clone(),
+            keepalive,
+            accepted,
+        ));
+        let report = match rt.block_on(async {
+            tokio::time::timeout(
+                timeout,
+                nat_client(
+                    socket,
+                    servers.clone(),
+                    endpoint.clone(),
+                    keepalive,
+                    punch,
+                    inbound,
+                ),
+            )
+            .await
+        }) {
+            Ok(Ok(result)) => result.report("tcp"),
+            Ok(Err(err)) => failure_report("tcp", &servers, &err.to_string()),
+            Err(_) => failure_report("tcp", &servers, "traversal timed out"),
+        };
+        finish(rt, report, mapping);
+    }
+    let client_endpoint = endpoint.clone();
+    rt.spawn(async move {
+        let result = nat_client(socket, servers, client_endpoint, keepalive, punch, inbound).await;
+        info!("Traversal result: {:?}", result);
+    });
+    run_mapped(
+        rt,
+        mapping,
+        nat_server(listen_addr, endpoint, keepalive, accepted),
+    );
+}
+
+// Run `echo_session` on every multiplexed session.
+async fn echo_streams(mut sessions: mpsc::UnboundedReceiver<MuxSession>, streams: u32) {
+    while let Some(MuxSession { connection, mux }) = sessions.recv().await {
+        info!("Multiplexed session with {}", connection.peer);
+        echo_session(mux, streams);
+    }
+}
+
+// Open `streams` streams that send a greeting and log what comes back, and
+// echo every stream the peer opens.
+fn echo_session(mut mux: Mux, streams: u32) {
+    for _ in 0..streams {
+        let Ok(mut stream) = mux.open() else {
+            break;
+        };
+        tokio::spawn(async move {
+            let greeting = format!("Hello from stream {}", stream.id());
+            let mut echo = String::new();
+            let result = async {
+                stream.write_all(greeting.as_bytes()).await?;
+                stream.shutdown().await?;
+                stream.read_to_string(&mut echo).await
+            };
+            match result.await {
+                Ok(_) => info!("Stream {} echoed: {}", stream.id(), echo),
+                Err(err) => info!("Stream {} failed: {}", stream.id(), err),
+            }
+        });
+    }
+    tokio::spawn(async move {
+        while let Some(stream) = mux.accept().await {
+            let id = stream.id();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                match tokio::io::copy(&mut reader, &mut writer).await {
+                    Ok(len) => info!("Echoed {} bytes on stream {}", len, id),
+                    Err(err) => info!("Echo on stream {} failed: {}", id, err),
+                }
+                let _ = writer.shutdown().await;
+            });
+        }
+    });
+}
+
+fn run_udp_client(rt: &tokio::runtime::Runtime, matches: &ArgMatches) {
+    let servers = servers(matches);
+    let mut bind_addr = bind_addr(matches, &servers);
+    let mut mapping = None;
+    if matches.get_flag("map-port") && bind_addr.port() == 0 {
+        // the mapping is for one port, which every migration binds again
+        match std::net::UdpSocket::bind(bind_addr).and_then(|sock| sock.local_addr()) {
+            Ok(free) => bind_addr.set_port(free.port()),
+            Err(err) if json_timeout(matches).is_some() => {
+                finish(rt, failure_report("udp", &servers, &err.to_string()), None)
+            }
+            Err(err) => {
+                info!("Failed to bind {}: {}", bind_addr, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if bind_addr.port() != 0 {
+        mapping = map_port(rt, matches, Protocol::Udp, bind_addr.port());
+    }
+    let config = SupervisorConfig {
+        keepalive: keepalive(matches),
+        max_migrations: *matches.get_one::<u32>("max-migrations").unwrap(),
+        candidate: mapping.as_ref().map(|mapping| mapping.external),
+    };
+    if let Some(timeout) = json_timeout(matches) {
+        let report = match rt.block_on(async {
+            tokio::time::timeout(
+                timeout,
+                SupervisedUdp::connect(servers.clone(), bind_addr, config),
+            )
+            .await
+        }) {
+            Ok(Ok(conn)) => conn.result().report("udp"),
+            Ok(Err(err)) => failure_report("udp", &servers, &err.to_string()),
+            Err(_) => failure_report("udp", &servers, "traversal timed out"),
+        };
+        finish(rt, report, mapping);
+    }
+    if let Some(streams) = matches.get_one::<u32>("streams").copied() {
+        // a multiplexed path is not migrated, its streams end with it
+        run_mapped(rt, mapping, async move {
+            let (sock, result) =
+                match udp::nat_client(servers, bind_addr, rand::random(), config.candidate).await {
+                    Ok(punched) => punched,
+                    Err(err) => {
+                        info!("Traversal failed: {}", err);
+                        return;
+                    }
+                };
+            info!("Traversal result: {:?}", result);
+            info!("Multiplexed session with {}", sock.peer_addr().unwrap());
+            let (announce, mux) = oneshot::channel();
+            let session = tokio::spawn(udp::run_mux_session(sock, config.keepalive, announce));
+            if let Ok(mux) = mux.await {
+                echo_session(mux, streams);
+            }
+            info!("Session ended: {:?}", session.await);
+        });
+        return;
+    }
+    run_mapped(rt, mapping, async move {
+        let mut conn = match SupervisedUdp::connect(servers, bind_addr, config).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                info!("Traversal failed: {}", err);
+                return;
+            }
+        };
+        info!("Traversal result: {:?}", conn.result());
+        let mut buf = [0; 1024];
+        loop {
+            match conn.recv(&mut buf).await {
+                Ok(len) => info!(
+                    "Received message: {} from {}",
+                    String::from_utf8_lossy(&buf[..len]),
+                    conn.peer_addr()
+                ),
+                Err(err) => {
+                    info!("Session ended: {}, stats: {:?}", err, conn.stats());
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn run_lifetime(rt: &tokio::runtime::Runtime, matches: &ArgMatches) {
+    let server = *matches.get_one::<SocketAddr>("address").unwrap();
+    let config = LifetimeConfig {
+        max: Duration::from_secs(*matches.get_one::<u64>("max").unwrap()),
+        resolution: Duration::from_secs(*matches.get_one::<u64>("resolution").unwrap()),
+    };
+    let protocols = match matches.get_one::<String>("protocol").unwrap().as_str() {
+        "udp" => vec![Protocol::Udp],
+        "tcp" => vec![Protocol::Tcp],
+        _ => vec![Protocol::Udp, Protocol::Tcp],
+    };
+    rt.block_on(async move {
+        let reports = futures::future::join_all(
+            protocols
+                .into_iter()
+                .map(|protocol| lifetime::measure(server, protocol, config)),
+        )
+        .await;
+        for report in reports {
+            match report {
+                Ok(report) => info!("{}", report),
+                Err(err) => info!("Lifetime measurement failed: {}", err),
+            }
+        }
+    });
+}
+
+// Print the json report and exit. On success stay around for a moment so the
+// peer can finish its side of the traversal before our sockets close, the
+// port mapping goes last.
+fn finish(
+    rt: &tokio::runtime::Runtime,
+    report: serde_json::Value,
+    mapping: Option<PortMapping>,
+) -> ! {
+    println!("{}", report);
+    let success = report["success"].as_bool().unwrap_or(false);
+    rt.block_on(async {
+        if success {
+            tokio::time::sleep(LINGER).await;
+        }
+        remove_mapping(mapping).await;
+    });
+    std::process::exit(if success { 0 } else { 1 })
+}
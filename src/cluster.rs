@@ -0,0 +1,431 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use hmac_sha256::HMAC;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Notify, mpsc},
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{Instrument, debug, info, info_span};
+
+use crate::{
+    ServerState,
+    lifetime::Protocol,
+    metrics::Metrics,
+    relay::{hex, same_mac},
+    shutdown::Shutdown,
+};
+
+// Waiting clients are announced this often even when nothing changed, so a
+// node that reconnects catches up.
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// How long a new connection may take to answer the challenge.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const NONCE_BYTES: usize = 16;
+
+/// What the servers tell their cluster node, ignored while none runs.
+#[derive(Default)]
+pub(crate) struct ClusterEvents {
+    // a local client started waiting for a peer
+    changed: Notify,
+    // local TCP sessions that left while paired with a remote client
+    gone: Mutex<Option<mpsc::UnboundedSender<Gone>>>,
+}
+
+struct Gone {
+    node: SocketAddr,
+    from: SocketAddr,
+    to: SocketAddr,
+}
+
+impl ClusterEvents {
+    /// Let the cluster node announce the waiting clients right away.
+    pub(crate) fn changed(&self) {
+        self.changed.notify_one();
+    }
+
+    /// The local TCP client `from`, paired with `to` of cluster node `node`,
+    /// left before finishing.
+    pub(crate) fn gone(&self, node: SocketAddr, from: SocketAddr, to: SocketAddr) {
+        if let Some(gone) = self.gone.lock().unwrap().as_ref() {
+            let _ = gone.send(Gone { node, from, to });
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Gossip address of this node, both listened on and how the other
+    /// nodes know it, so it must be reachable from them.
+    pub node: SocketAddr,
+    /// Gossip addresses of the other nodes this one connects to. A node
+    /// that connects to this one becomes a peer as well, so listing each
+    /// pair of nodes on one side is enough.
+    pub peers: Vec<SocketAddr>,
+    /// Secret shared by all nodes, a connection that cannot prove it knows
+    /// it is closed before any gossip is read.
+    pub secret: String,
+}
+
+// Length delimited json frames between nodes. For any two nodes the one
+// with the lower address proposes introductions, the other accepts them
+// silently or rejects them, so the same client is never promised to two
+// peers. The accepting node opens every connection with a challenge, the
+// connecting one answers with its hello.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Gossip {
+    /// First frame of the accepting node, a random hex nonce.
+    Challenge { nonce: String },
+    /// First frame of the connecting node, `mac` proves it knows the
+    /// cluster secret.
+    Hello { node: SocketAddr, mac: String },
+    /// The sender's clients waiting for a peer, longest waiting first.
+    Waiting {
+        tcp: Vec<SocketAddr>,
+        udp: Vec<SocketAddr>,
+    },
+    /// The sender introduced its client `from` to the receiver's client
+    /// `to`, who should get the address of `from` now.
+    Introduce {
+        protocol: Protocol,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+    /// Answer to an `Introduce` whose `to` was not waiting anymore.
+    Reject {
+        protocol: Protocol,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+    /// The sender's TCP client `from` left before finishing, the receiver's
+    /// client `to` gives up.
+    Gone { from: SocketAddr, to: SocketAddr },
+}
+
+struct Node {
+    me: SocketAddr,
+    secret: Arc<str>,
+    state: Arc<ServerState>,
+    links: HashMap<SocketAddr, mpsc::UnboundedSender<Gossip>>,
+    // last announcement of every node
+    waiting: BTreeMap<SocketAddr, (Vec<SocketAddr>, Vec<SocketAddr>)>,
+    // remote clients we introduced and their node has not confirmed yet
+    claimed: HashMap<SocketAddr, HashSet<SocketAddr>>,
+}
+
+impl Node {
+    fn send(&mut self, node: SocketAddr, msg: Gossip) {
+        let link = self
+            .links
+            .entry(node)
+            .or_insert_with(|| spawn_link(self.me, node, Arc::clone(&self.secret)));
+        let _ = link.send(msg);
+    }
+
+    fn announce(&mut self) {
+        let tcp = self.state.tcp.lock().unwrap().waiting();
+        let udp: Vec<_> = self
+            .state
+            .udp
+            .lock()
+            .unwrap()
+            .waiting()
+            .into_iter()
+            .collect();
+        for link in self.links.values() {
+            let _ = link.send(Gossip::Waiting {
+                tcp: tcp.clone(),
+                udp: udp.clone(),
+            });
+        }
+    }
+
+    // Introduce our waiting clients to those of higher nodes.
+    fn introduce(&mut self) {
+        let mut introductions = Vec::new();
+        for (node, (tcp, udp)) in self.waiting.range(self.me..) {
+            if *node == self.me {
+                continue;
+            }
+            let claimed = self.claimed.entry(*node).or_default();
+            {
+                let mut broker = self.state.tcp.lock().unwrap();
+                let remotes = tcp.iter().filter(|remote| !claimed.contains(remote));
+                for (local, remote) in broker.waiting().into_iter().zip(remotes) {
+                    if broker.pair_remote(local, *remote, *node) {
+                        introductions.push((*node, Protocol::Tcp, local, *remote));
+                    }
+                }
+            }
+            let mut udp_state = self.state.udp.lock().unwrap();
+            if let Some(local) = udp_state.waiting()
+                && let Some(remote) = udp.iter().find(|remote| !claimed.contains(remote))
+                && let Some(replies) = udp_state.pair_remote(local, *remote, *node)
+            {
+                replies
+                    .into_iter()
+                    .for_each(|reply| self.state.send_udp(reply));
+                introductions.push((*node, Protocol::Udp, local, *remote));
+            }
+        }
+        for (node, protocol, from, to) in introductions {
+            info!(%protocol, %from, %to, %node, "Cluster introduced clients");
+            self.claimed.entry(node).or_default().insert(to);
+            self.send(node, Gossip::Introduce { protocol, from, to });
+        }
+    }
+
+    fn handle(&mut self, node: SocketAddr, msg: Gossip) {
+        match msg {
+            Gossip::Challenge { .. } => {}
+            // a node that connected to us is gossiped with like our peers
+            Gossip::Hello { .. } => {
+                if !self.links.contains_key(&node) {
+                    info!(%node, "Cluster node joined");
+                    self.links
+                        .insert(node, spawn_link(self.me, node, Arc::clone(&self.secret)));
+                    self.announce();
+                }
+            }
+            Gossip::Waiting { tcp, udp } => {
+                // a client no longer announced was paired, by us or not
+                if let Some(claimed) = self.claimed.get_mut(&node) {
+                    claimed.retain(|addr| tcp.contains(addr) || udp.contains(addr));
+                }
+                self.waiting.insert(node, (tcp, udp));
+                self.introduce();
+            }
+            Gossip::Introduce { protocol, from, to } => {
+                let accepted = match protocol {
+                    Protocol::Tcp => self.state.tcp.lock().unwrap().pair_remote(to, from, node),
+                    Protocol::Udp => {
+                        let replies = self.state.udp.lock().unwrap().pair_remote(to, from, node);
+                        replies
+                            .map(|replies| {
+                                replies
+                                    .into_iter()
+                                    .for_each(|reply| self.state.send_udp(reply))
+                            })
+                            .is_some()
+                    }
+                };
+                if accepted {
+                    info!(%protocol, %from, %to, %node, "Cluster accepted introduction");
+                } else {
+                    info!(%protocol, %from, %to, %node, "Cluster rejected introduction");
+                    self.send(node, Gossip::Reject { protocol, from, to });
+                }
+            }
+            Gossip::Reject { protocol, from, to } => {
+                info!(%protocol, %from, %to, %node, "Cluster introduction rejected");
+                if let Some(claimed) = self.claimed.get_mut(&node) {
+                    claimed.remove(&to);
+                }
+                match protocol {
+                    Protocol::Tcp => self.state.tcp.lock().unwrap().remote_gone(from, to),
+                    Protocol::Udp => {
+                        self.state.udp.lock().unwrap().remove(to);
+                    }
+                }
+            }
+            Gossip::Gone { from, to } => {
+                info!(%from, %to, %node, "Cluster peer left");
+                self.state.tcp.lock().unwrap().remote_gone(to, from);
+            }
+        }
+    }
+}
+
+fn hello_mac(secret: &str, node: SocketAddr, nonce: &str) -> String {
+    hex(&HMAC::mac(format!("hello {} {}", node, nonce), secret))
+}
+
+async fn recv_frame(
+    stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
+) -> Option<serde_json::Result<Gossip>> {
+    let frame = stream.next().await?.ok()?;
+    Some(serde_json::from_slice(&frame))
+}
+
+// Connect to `node` and read its challenge.
+async fn connect(
+    node: SocketAddr,
+) -> io::Result<(Framed<TcpStream, LengthDelimitedCodec>, String)> {
+    let stream = TcpStream::connect(node).await?;
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    match time::timeout(HELLO_TIMEOUT, recv_frame(&mut stream)).await {
+        Ok(Some(Ok(Gossip::Challenge { nonce }))) => Ok((stream, nonce)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no challenge received",
+        )),
+    }
+}
+
+// Keep a connection to `node` and forward gossip to it. Gossip queued while
+// the node is unreachable is dropped, waiting clients are announced again.
+fn spawn_link(me: SocketAddr, node: SocketAddr, secret: Arc<str>) -> mpsc::UnboundedSender<Gossip> {
+    let (tx, mut outgoing) = mpsc::unbounded_channel::<Gossip>();
+    tokio::spawn(
+        async move {
+            loop {
+                let (mut stream, nonce) = match connect(node).await {
+                    Ok(connected) => connected,
+                    Err(err) => {
+                        debug!("Cluster node unreachable: {}", err);
+                        time::sleep(RECONNECT_INTERVAL).await;
+                        loop {
+                            match outgoing.try_recv() {
+                                Ok(_) => {}
+                                Err(mpsc::error::TryRecvError::Empty) => break,
+                                Err(mpsc::error::TryRecvError::Disconnected) => return,
+                            }
+                        }
+                        continue;
+                    }
+                };
+                info!("Cluster connected");
+                let mut msg = Gossip::Hello {
+                    node: me,
+                    mac: hello_mac(&secret, me, &nonce),
+                };
+                loop {
+                    let frame = bytes::Bytes::from(serde_json::to_vec(&msg).unwrap());
+                    if let Err(err) = stream.send(frame).await {
+                        info!("Cluster connection lost: {}", err);
+                        break;
+                    }
+                    match outgoing.recv().await {
+                        Some(next) => msg = next,
+                        None => return,
+                    }
+                }
+            }
+        }
+        .instrument(info_span!("cluster_link", %node)),
+    );
+    tx
+}
+
+// Challenge a connecting node, then read its gossip until it disconnects.
+async fn receive(
+    stream: TcpStream,
+    secret: Arc<str>,
+    metrics: Arc<Metrics>,
+    inbox: mpsc::UnboundedSender<(SocketAddr, Gossip)>,
+) {
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    let nonce = hex(&rand::random::<[u8; NONCE_BYTES]>());
+    let challenge = Gossip::Challenge {
+        nonce: nonce.clone(),
+    };
+    let frame = bytes::Bytes::from(serde_json::to_vec(&challenge).unwrap());
+    if stream.send(frame).await.is_err() {
+        return;
+    }
+    let (node, mac) = match time::timeout(HELLO_TIMEOUT, recv_frame(&mut stream)).await {
+        Ok(Some(Ok(Gossip::Hello { node, mac })))
+            if same_mac(&hello_mac(&secret, node, &nonce), &mac) =>
+        {
+            (node, mac)
+        }
+        _ => {
+            info!("Cluster connection without a valid hello, closing");
+            metrics.error("cluster_bad_hello");
+            return;
+        }
+    };
+    if inbox.send((node, Gossip::Hello { node, mac })).is_err() {
+        return;
+    }
+    while let Some(msg) = recv_frame(&mut stream).await {
+        match msg {
+            Ok(msg) => {
+                if inbox.send((node, msg)).is_err() {
+                    return;
+                }
+            }
+            Err(err) => info!("Cluster bad gossip: {}", err),
+        }
+    }
+}
+
+/// Join a rendezvous server to a cluster: clients waiting on it are
+/// introduced to clients waiting on other nodes, TCP and UDP alike.
+pub(crate) async fn cluster_node(
+    config: ClusterConfig,
+    state: Arc<ServerState>,
+    shutdown: Shutdown,
+) {
+    let listener = TcpListener::bind(config.node).await.unwrap();
+    info!(
+        "Cluster node {} gossiping with {:?}",
+        config.node, config.peers
+    );
+    let (gone_tx, mut gone) = mpsc::unbounded_channel();
+    *state.cluster.gone.lock().unwrap() = Some(gone_tx);
+    let secret: Arc<str> = Arc::from(config.secret.as_str());
+    let (inbox_tx, mut inbox) = mpsc::unbounded_channel();
+    let accept = tokio::spawn({
+        let secret = Arc::clone(&secret);
+        let metrics = Arc::clone(&state.metrics);
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let span = info_span!("cluster_peer", %addr);
+                        let receive = receive(
+                            stream,
+                            Arc::clone(&secret),
+                            Arc::clone(&metrics),
+                            inbox_tx.clone(),
+                        );
+                        tokio::spawn(receive.instrument(span));
+                    }
+                    Err(err) => info!("Cluster accept error: {}", err),
+                }
+            }
+        }
+    });
+
+    let mut node = Node {
+        me: config.node,
+        secret: Arc::clone(&secret),
+        state: Arc::clone(&state),
+        links: config
+            .peers
+            .iter()
+            .map(|peer| (*peer, spawn_link(config.node, *peer, Arc::clone(&secret))))
+            .collect(),
+        waiting: BTreeMap::new(),
+        claimed: HashMap::new(),
+    };
+    let mut interval = time::interval(GOSSIP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => node.announce(),
+            _ = state.cluster.changed.notified() => {
+                node.announce();
+                node.introduce();
+            }
+            Some(Gone { node: to_node, from, to }) = gone.recv() => {
+                node.send(to_node, Gossip::Gone { from, to });
+            }
+            Some((from, msg)) = inbox.recv() => node.handle(from, msg),
+            _ = shutdown.triggered() => break,
+        }
+    }
+    accept.abort();
+    *state.cluster.gone.lock().unwrap() = None;
+}
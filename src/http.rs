@@ -0,0 +1,291 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tracing::info;
+
+// Longest request or status line.
+const MAX_LINE: usize = 8 * 1024;
+// Largest header section of a request or answer.
+const MAX_HEADERS: usize = 16 * 1024;
+/// Largest answer body `request` reads, UPnP descriptions are a few KiB.
+pub const MAX_RESPONSE: usize = 1024 * 1024;
+/// How long reading a request, or the answer to one, may take.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Just enough HTTP/1.1 for scraping, small admin requests and talking to
+// UPnP gateways: one request per connection, bodies sized by Content-Length.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response {
+            status: 404,
+            content_type: "text/plain",
+            body: "not found\n".to_string(),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serve requests without a body, anything announcing one gets 413.
+pub async fn serve<F>(listen_addr: SocketAddr, handler: F)
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    serve_with_body(listen_addr, 0, handler).await
+}
+
+/// Serve requests with bodies of up to `max_body` bytes, larger ones get 413
+/// without being read.
+pub async fn serve_with_body<F>(listen_addr: SocketAddr, max_body: usize, handler: F)
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(listen_addr).await.unwrap();
+    info!("Http listening on: {}", listen_addr);
+    serve_listener(listener, max_body, handler).await
+}
+
+// Serve on a bound `listener`, see `serve_with_body`.
+async fn serve_listener<F>(listener: TcpListener, max_body: usize, handler: F)
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(res) => res,
+            Err(err) => {
+                info!("Http accept error: {}", err);
+                continue;
+            }
+        };
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, max_body, handler.as_ref()).await {
+                info!("Http request from {} failed: {}", addr, err);
+            }
+        });
+    }
+}
+
+async fn handle<F>(stream: TcpStream, max_body: usize, handler: &F) -> io::Result<()>
+where
+    F: Fn(&Request) -> Response,
+{
+    let mut stream = BufReader::new(stream);
+    // a client trickling its request holds a task, not the server
+    let request = time::timeout(READ_TIMEOUT, read_request(&mut stream, max_body))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request not read in time"))??;
+    let response = match request {
+        Ok(request) => handler(&request),
+        Err(status) => Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", reason(status).to_lowercase()),
+        },
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Read a request, or the status refusing it.
+async fn read_request(
+    stream: &mut BufReader<TcpStream>,
+    max_body: usize,
+) -> io::Result<Result<Request, u16>> {
+    let Some(line) = read_line(stream, MAX_LINE).await? else {
+        return Ok(Err(414));
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Ok(Err(400));
+    };
+    let Some(length) = read_headers(stream).await? else {
+        return Ok(Err(431));
+    };
+    if length > max_body {
+        return Ok(Err(413));
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    Ok(Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }))
+}
+
+// Read one line of at most `limit` bytes, `None` when it is longer.
+async fn read_line(stream: &mut BufReader<TcpStream>, limit: usize) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = stream.take(limit as u64 + 1).read_line(&mut line).await?;
+    Ok((read <= limit).then_some(line))
+}
+
+// Skip the headers of a request or response, returning its Content-Length,
+// `None` when they are larger than `MAX_HEADERS`.
+async fn read_headers(stream: &mut BufReader<TcpStream>) -> io::Result<Option<usize>> {
+    let mut length = 0;
+    let mut left = MAX_HEADERS;
+    loop {
+        let Some(header) = read_line(stream, left).await? else {
+            return Ok(None);
+        };
+        if header.len() <= 2 {
+            return Ok(Some(length));
+        }
+        left -= header.len();
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().unwrap_or(0);
+        }
+    }
+}
+
+/// Send one request to `addr` and return the status and body of the answer.
+/// `headers` are complete header lines without the line break. Answers
+/// larger than `MAX_RESPONSE` or slower than `READ_TIMEOUT` fail.
+pub async fn request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[String],
+    body: &str,
+) -> io::Result<(u16, String)> {
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    stream.get_mut().write_all(head.as_bytes()).await?;
+    stream.get_mut().write_all(body.as_bytes()).await?;
+    time::timeout(READ_TIMEOUT, read_response(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "answer not read in time"))?
+}
+
+async fn read_response(stream: &mut BufReader<TcpStream>) -> io::Result<(u16, String)> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "answer too large");
+    let status = read_line(stream, MAX_LINE).await?.ok_or_else(too_large)?;
+    let status = status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status line"))?;
+    let length = read_headers(stream).await?.ok_or_else(too_large)?;
+    if length > MAX_RESPONSE {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    if length > 0 {
+        body.resize(length, 0);
+        stream.read_exact(&mut body).await?;
+    } else {
+        // answers without a length end with the connection
+        stream
+            .take(MAX_RESPONSE as u64 + 1)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > MAX_RESPONSE {
+            return Err(too_large());
+        }
+    }
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An echo server on a free port, accepting as soon as this returns.
+    async fn start(max_body: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, max_body, |request| {
+            Response::ok("text/plain", request.body.clone())
+        }));
+        addr
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_up_to_the_limit() {
+        let addr = start(4).await;
+        let answer = request(addr, "POST", "/", &[], "body").await.unwrap();
+        assert_eq!(answer, (200, "body".to_string()));
+        let (status, _) = request(addr, "POST", "/", &[], "bodies").await.unwrap();
+        assert_eq!(status, 413);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_requests() {
+        let addr = start(0).await;
+        let path = format!("/{}", "a".repeat(MAX_LINE));
+        let (status, _) = request(addr, "GET", &path, &[], "").await.unwrap();
+        assert_eq!(status, 414);
+        let headers = vec![format!("X-Padding: {}", "a".repeat(1024)); MAX_HEADERS / 1024];
+        let (status, _) = request(addr, "GET", "/", &headers, "").await.unwrap();
+        assert_eq!(status, 431);
+    }
+}
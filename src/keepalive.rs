@@ -0,0 +1,113 @@
+use std::{net::SocketAddr, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant, Interval, MissedTickBehavior},
+};
+use tracing::info;
+
+// Keepalives are empty payloads: a zero length datagram for UDP and an empty
+// frame for the length delimited TCP codec.
+pub const KEEPALIVE: &[u8] = b"";
+
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INTERVAL: Duration = Duration::from_secs(120);
+/// How often TCP clients heartbeat their rendezvous session, well below the
+/// server's idle timeout.
+pub const RENDEZVOUS_HEARTBEAT: Duration = Duration::from_secs(10);
+// Typical UDP binding timeout of consumer NATs when nothing was measured.
+const DEFAULT_BINDING_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn is_keepalive(msg: &[u8]) -> bool {
+    msg.is_empty()
+}
+
+/// Enable TCP keepalives on `stream`, probing every `interval` once it has
+/// been idle that long. Best effort, failures are only logged.
+pub fn set_tcp_keepalive(stream: &TcpStream, interval: Duration) {
+    let keepalive = TcpKeepalive::new()
+        .with_time(interval)
+        .with_interval(interval);
+    if let Err(err) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        info!("Failed to enable tcp keepalive: {}", err);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    pub interval: Duration,
+    /// Intervals without any packet from the peer before the path is dead.
+    pub max_missed: u32,
+}
+
+impl KeepaliveConfig {
+    /// Refresh three times per binding timeout so a single lost keepalive
+    /// does not let the mapping expire.
+    pub fn for_binding_timeout(timeout: Duration) -> Self {
+        KeepaliveConfig {
+            interval: (timeout / 3).clamp(MIN_INTERVAL, MAX_INTERVAL),
+            max_missed: 3,
+        }
+    }
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self::for_binding_timeout(DEFAULT_BINDING_TIMEOUT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveEvent {
+    /// Nothing was heard from the peer for `idle`, the mapping is probably
+    /// gone and the caller should punch again.
+    PathDead { peer: SocketAddr, idle: Duration },
+    /// The peer closed the connection.
+    Closed { peer: SocketAddr },
+    /// The peer kept another connection to us and closed this one.
+    Duplicate { peer: SocketAddr },
+}
+
+pub enum KeepaliveAction {
+    Send,
+    Dead(Duration),
+}
+
+/// Keepalive timer driven from a session's `select!` loop: call `record`
+/// for every packet from the peer and act on what `tick` returns.
+pub struct Keepalive {
+    config: KeepaliveConfig,
+    last_seen: Instant,
+    interval: Interval,
+}
+
+impl Keepalive {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        let mut interval = time::interval_at(Instant::now() + config.interval, config.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Keepalive {
+            config,
+            last_seen: Instant::now(),
+            interval,
+        }
+    }
+
+    pub fn config(&self) -> KeepaliveConfig {
+        self.config
+    }
+
+    pub fn record(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    pub async fn tick(&mut self) -> KeepaliveAction {
+        self.interval.tick().await;
+        let idle = self.last_seen.elapsed();
+        if idle >= self.config.interval * self.config.max_missed {
+            KeepaliveAction::Dead(idle)
+        } else {
+            KeepaliveAction::Send
+        }
+    }
+}
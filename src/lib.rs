@@ -1,189 +1,1111 @@
+pub mod admin;
+mod batch;
+pub mod cluster;
+pub mod http;
+pub mod keepalive;
+pub mod lifetime;
+pub mod limits;
+pub mod metrics;
+pub mod mux;
+mod pairing;
+pub mod portmap;
+pub mod relay;
+pub mod reliable;
+pub mod shutdown;
+pub mod store;
+pub mod strategy;
+pub mod supervisor;
 pub mod tcp;
+pub mod traversal;
 pub mod udp;
 
 use std::{
     collections::HashMap,
     net::SocketAddr,
     os::fd::{FromRawFd, IntoRawFd},
-    sync::{Arc, LazyLock, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
     time::Duration,
 };
 
 use futures::{SinkExt, StreamExt};
-use log::info;
 use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
     net::{TcpSocket, TcpStream, UdpSocket},
+    sync::{Notify, mpsc},
     time,
 };
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::{
+    codec::{Framed, LengthDelimitedCodec},
+    task::TaskTracker,
+};
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
+use traversal::{MAPPING_REQUEST, MAPPING_RESPONSE_PREFIX, REQUEST_SIZE, WAITING, canonical};
+
+use crate::{
+    cluster::{ClusterConfig, ClusterEvents, cluster_node},
+    keepalive::{KEEPALIVE, Keepalive, KeepaliveAction, KeepaliveConfig},
+    lifetime::PROBE,
+    limits::{LimitConfig, RateLimiter},
+    metrics::Metrics,
+    pairing::{NatHello, PairingBroker, SessionEvent, TcpRegistration},
+    relay::{RelayIssuer, TICKET_PREFIX},
+    shutdown::Shutdown,
+    store::{MemoryStore, RegistrationStore, UdpRecord},
+};
+
+pub const DEFAULT_ADDR: &str = "[::]:8090";
+// Key of the json frame telling a TCP client that the peer it was introduced
+// to expired before the path was up, e.g. `{"peer_gone": "1.2.3.4:5678"}`.
+pub const PEER_GONE: &str = "peer_gone";
+// Frame a TCP client sends once the path to its peer is up. A paired session
+// that ends without it leaves its peer waiting in vain, the peer is told.
+pub const TRAVERSAL_COMPLETE: &str = "NAT traversal complete!";
+// Key of the json frame a rendezvous client sends right after connecting to
+// register for pairing, e.g. `{"register": true}`.
+pub const REGISTER: &str = "register";
+// Key of the same frame telling whether the client's NAT is symmetric, e.g.
+// `{"register": true, "symmetric": false}`. Introductions pass it on to the
+// peer as `peer_symmetric`.
+pub const SYMMETRIC: &str = "symmetric";
+// Key of the register frame naming an address the client's gateway forwards to
+// it, e.g. `{"candidate": "1.2.3.4:5678"}`, passed on as `peer_candidate`.
+pub const CANDIDATE: &str = "candidate";
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Registrations of one rendezvous server, shared by its listeners, sessions,
+/// cluster node and admin API.
+pub(crate) struct ServerState {
+    pub(crate) tcp: Mutex<PairingBroker>,
+    pub(crate) udp: Mutex<UdpRegistrations>,
+    pub(crate) cluster: Arc<ClusterEvents>,
+    pub(crate) metrics: Arc<Metrics>,
+    // hands introduced peers a room on the relay to fall back on
+    relay: Option<RelayIssuer>,
+    // Unsolicited replies for the running UDP server to send, e.g.
+    // introductions of clients registered on other cluster nodes.
+    udp_outbox: Mutex<Option<mpsc::UnboundedSender<Reply>>>,
+}
+
+impl ServerState {
+    fn new(
+        store: Box<dyn RegistrationStore>,
+        relay: Option<RelayIssuer>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let cluster = Arc::new(ClusterEvents::default());
+        ServerState {
+            tcp: Mutex::new(PairingBroker::new(Arc::clone(&cluster))),
+            udp: Mutex::new(UdpRegistrations::new(
+                store,
+                Arc::clone(&cluster),
+                relay.clone(),
+                Arc::clone(&metrics),
+            )),
+            cluster,
+            metrics,
+            relay,
+            udp_outbox: Mutex::new(None),
+        }
+    }
+
+    /// Have the UDP server send `reply`, dropped when it does not run.
+    pub(crate) fn send_udp(&self, reply: Reply) {
+        if let Some(outbox) = self.udp_outbox.lock().unwrap().as_ref() {
+            let _ = outbox.send(reply);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    tcp_listen: Vec<SocketAddr>,
+    udp_listen: Vec<SocketAddr>,
+    v6_only: bool,
+    udp_workers: usize,
+    idle_timeout: Duration,
+    cluster: Option<ClusterConfig>,
+}
+
+/// A TCP and UDP rendezvous server owning its registrations, so several can
+/// run in one process without seeing each other's clients. Clones are
+/// handles to the same server.
+#[derive(Clone)]
+pub struct RendezvousServer {
+    state: Arc<ServerState>,
+    config: Arc<ServerConfig>,
+    limiter: Arc<RateLimiter>,
+    shutdown: Shutdown,
+}
+
+/// Configures a `RendezvousServer`. Nothing is served until listen
+/// addresses are given for at least one protocol.
+pub struct RendezvousServerBuilder {
+    config: ServerConfig,
+    limits: LimitConfig,
+    shutdown: Shutdown,
+    store: Option<Box<dyn RegistrationStore>>,
+    relay: Option<RelayIssuer>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl RendezvousServer {
+    pub fn builder() -> RendezvousServerBuilder {
+        RendezvousServerBuilder {
+            config: ServerConfig {
+                tcp_listen: Vec::new(),
+                udp_listen: Vec::new(),
+                v6_only: false,
+                udp_workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+                idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                cluster: None,
+            },
+            limits: LimitConfig::default(),
+            shutdown: Shutdown::default(),
+            store: None,
+            relay: None,
+            metrics: None,
+        }
+    }
+
+    /// Triggering it stops the server, see `Shutdown`.
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// What this server counts, see `metrics_server`.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.state.metrics
+    }
+
+    /// Serve until shut down and drained. Panics when a listen address
+    /// cannot be bound.
+    pub async fn run(self) {
+        let tcp = async {
+            if !self.config.tcp_listen.is_empty() {
+                self.tcp_stun_server().await;
+            }
+        };
+        let udp = async {
+            if !self.config.udp_listen.is_empty() {
+                self.udp_stun_server().await;
+            }
+        };
+        let cluster = async {
+            if let Some(config) = self.config.cluster.clone() {
+                cluster_node(config, Arc::clone(&self.state), self.shutdown.clone()).await;
+            }
+        };
+        tokio::join!(tcp, udp, cluster);
+    }
+}
+
+impl RendezvousServerBuilder {
+    /// Serve TCP rendezvous on every address.
+    pub fn tcp_listen(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.config.tcp_listen = addrs.into_iter().collect();
+        self
+    }
+
+    /// Serve UDP rendezvous on every address.
+    pub fn udp_listen(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.config.udp_listen = addrs.into_iter().collect();
+        self
+    }
+
+    /// Keep IPv6 sockets off IPv4, see `server_socket`.
+    pub fn v6_only(mut self, v6_only: bool) -> Self {
+        self.config.v6_only = v6_only;
+        self
+    }
+
+    /// UDP sockets per listen address sharing the port, one per CPU by
+    /// default.
+    pub fn udp_workers(mut self, workers: usize) -> Self {
+        self.config.udp_workers = workers.max(1);
+        self
+    }
+
+    /// How long a TCP rendezvous session may go without a heartbeat.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Share a shutdown handle, e.g. with other servers of the process.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Where UDP registrations are kept, in memory of this server by
+    /// default.
+    pub fn store(mut self, store: Box<dyn RegistrationStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Hand both peers of every pairing a ticket for a room on the relay
+    /// `issuer` issues for.
+    pub fn relay(mut self, issuer: RelayIssuer) -> Self {
+        self.relay = Some(issuer);
+        self
+    }
+
+    /// Join a cluster of rendezvous servers.
+    pub fn cluster(mut self, config: ClusterConfig) -> Self {
+        self.config.cluster = Some(config);
+        self
+    }
 
-const ADDR: &str = "[::]:8090";
-static GLOBAL_STATE: LazyLock<Mutex<HashMap<usize, SocketAddr>>> =
-    LazyLock::new(|| Mutex::new(HashMap::default()));
+    /// Count into `metrics`, e.g. those of the store or of other servers
+    /// of the process, instead of counters of this server alone.
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn build(self) -> RendezvousServer {
+        let store = self
+            .store
+            .unwrap_or_else(|| Box::new(MemoryStore::default()));
+        let metrics = self.metrics.unwrap_or_default();
+        RendezvousServer {
+            state: Arc::new(ServerState::new(store, self.relay, metrics)),
+            config: Arc::new(self.config),
+            limiter: RateLimiter::new(self.limits),
+            shutdown: self.shutdown,
+        }
+    }
+}
 
 pub struct StunSession {
+    state: Arc<ServerState>,
     stream: Framed<TcpStream, LengthDelimitedCodec>,
     session_id: usize,
     addr: SocketAddr,
+    shutdown: Shutdown,
+    idle_timeout: Duration,
 }
 
 impl StunSession {
     pub async fn run(&mut self) {
-        let mut finished = false;
+        // Every client says what it wants in its first frame: lifetime
+        // probes and mapping requests must not be registered for pairing,
+        // rendezvous clients register with what they know about their NAT.
+        let hello = match time::timeout(self.idle_timeout, self.stream.next()).await {
+            Ok(Some(Ok(data))) if data.as_ref() == PROBE => return self.run_probe(data).await,
+            Ok(Some(Ok(data))) if data.as_ref() == MAPPING_REQUEST => {
+                // the client closes once we did, see `query_tcp_mappings`
+                let mapped = format!("{}{}", MAPPING_RESPONSE_PREFIX, self.addr);
+                let _ = self.stream.send(bytes::Bytes::from(mapped)).await;
+                return;
+            }
+            Ok(Some(Ok(data))) => match register_hello(&data) {
+                Some(hello) => hello,
+                None => {
+                    info!(
+                        "Tcp unexpected first frame: {:?}",
+                        String::from_utf8_lossy(&data)
+                    );
+                    self.state.metrics.error("tcp_bad_hello");
+                    return;
+                }
+            },
+            Ok(None) | Ok(Some(Err(_))) => return,
+            Err(_) => {
+                info!("Tcp client {} said nothing", self.addr);
+                self.state.metrics.error("tcp_expired");
+                return;
+            }
+        };
+        // catches registered peers that vanish without a FIN long before the
+        // application level heartbeats would, lifetime probes must see the
+        // NAT binding expire instead
+        keepalive::set_tcp_keepalive(self.stream.get_ref(), self.idle_timeout / 3);
+        let registered = time::Instant::now();
+        let evict = Arc::new(Notify::new());
+        let (events, mut pairing) = mpsc::unbounded_channel();
+        self.state.tcp.lock().unwrap().register(
+            self.session_id,
+            TcpRegistration::new(self.addr, hello, Arc::clone(&evict), events),
+        );
+        self.state
+            .metrics
+            .active_sessions
+            .fetch_add(1, Ordering::Relaxed);
+
+        // clients heartbeat with empty frames, a session that stays silent
+        // for the idle timeout is dead
+        let mut keepalive = Keepalive::new(KeepaliveConfig {
+            interval: self.idle_timeout / 3,
+            max_missed: 3,
+        });
+        let mut paired = None;
         loop {
-            if !finished {
-                let info = { GLOBAL_STATE.lock().unwrap().clone() };
-                for (_, addr) in info.into_iter().filter(|(k, _)| k != &self.session_id) {
-                    let remote_info = bytes::Bytes::from(
-                        serde_json::json!({ "address": addr.to_string() })
+            tokio::select! {
+                Some(event) = pairing.recv() => match event {
+                    SessionEvent::Paired { addr, hello } => {
+                        // the same comparison on both ends, whichever node
+                        // they are registered on
+                        let role = if canonical(self.addr) < canonical(addr) {
+                            "dial"
+                        } else {
+                            "listen"
+                        };
+                        let remote_info = bytes::Bytes::from(
+                            serde_json::json!({
+                                "address": addr.to_string(),
+                                "role": role,
+                                "peer_symmetric": hello.symmetric,
+                                "peer_candidate": hello.candidate.map(|candidate| candidate.to_string()),
+                                "relay": self.state.relay.as_ref().map(|relay| relay.ticket(self.addr, addr).to_string()),
+                            })
                             .to_string()
                             .into_bytes(),
-                    );
-                    self.stream.send(remote_info).await.unwrap();
-                    finished = true;
-                }
-            } else {
-                match self.stream.next().await {
-                    Some(Ok(data)) => {
-                        info!(
-                            "Tcp Received message: {:?} from {}",
-                            String::from_utf8(data.to_vec()).unwrap(),
-                            self.addr
                         );
+                        if let Err(err) = self.stream.send(remote_info).await {
+                            info!("Tcp Error: {}", err);
+                            self.state.metrics.error("tcp_send");
+                            self.unregister(true);
+                            return;
+                        }
+                        self.state.metrics.paired(lifetime::Protocol::Tcp, registered.elapsed());
+                        Span::current().record("paired", field::display(addr));
+                        info!("Tcp sent peer address");
+                        paired = Some(addr);
+                    }
+                    // the client gives up on the pairing, the session with it
+                    SessionEvent::PeerGone(addr) => {
+                        info!("Tcp paired session {} expired", addr);
+                        let gone = serde_json::json!({ PEER_GONE: addr.to_string() });
+                        let _ = self.stream.send(bytes::Bytes::from(gone.to_string())).await;
+                        self.unregister(false);
+                        break;
+                    }
+                },
+                msg = self.stream.next() => match msg {
+                    Some(Ok(data)) if keepalive::is_keepalive(&data) => keepalive.record(),
+                    Some(Ok(data)) => {
+                        info!("Tcp Received message: {:?}", String::from_utf8_lossy(&data));
+                        let completed = data.as_ref() == TRAVERSAL_COMPLETE.as_bytes();
+                        self.unregister(paired.is_some() && !completed);
+                        break;
                     }
                     Some(Err(err)) => {
                         info!("Tcp Error: {}", err);
+                        self.state.metrics.error("tcp_stream");
+                        self.unregister(paired.is_some());
+                        break;
                     }
                     None => {
                         info!("Tcp Connection closed");
+                        self.unregister(paired.is_some());
+                        break;
                     }
+                },
+                action = keepalive.tick() => match action {
+                    KeepaliveAction::Send => {
+                        let _ = self.stream.send(bytes::Bytes::from_static(KEEPALIVE)).await;
+                    }
+                    KeepaliveAction::Dead(idle) => {
+                        info!("Tcp session expired after {:?} idle", idle);
+                        self.state.metrics.error("tcp_expired");
+                        self.unregister(true);
+                        break;
+                    }
+                },
+                _ = evict.notified() => {
+                    info!("Tcp session evicted");
+                    self.unregister(true);
+                    break;
+                }
+                // sessions already introduced to a peer are left to finish
+                _ = self.shutdown.triggered(), if paired.is_none() => {
+                    info!("Tcp sending going away");
+                    let going_away = bytes::Bytes::from(self.shutdown.tcp_message().to_string());
+                    let _ = self.stream.send(going_away).await;
+                    // the broker may have introduced us meanwhile
+                    self.unregister(true);
+                    break;
                 }
-                // cleanup session state
-                GLOBAL_STATE.lock().unwrap().remove(&self.session_id);
+            }
+        }
+    }
+
+    // cleanup session state, telling the peer introduced to this session
+    // unless it finished
+    fn unregister(&self, notify_peer: bool) {
+        self.state
+            .tcp
+            .lock()
+            .unwrap()
+            .unregister(self.session_id, notify_peer);
+        self.state
+            .metrics
+            .active_sessions
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Echo every probe so the client can tell whether its NAT binding
+    // survived the idle period.
+    async fn run_probe(&mut self, mut data: bytes::BytesMut) {
+        info!("Tcp lifetime probe");
+        loop {
+            if self.stream.send(data.freeze()).await.is_err() {
                 break;
             }
-            time::sleep(time::Duration::from_secs(1)).await;
+            let next = tokio::select! {
+                next = self.stream.next() => next,
+                _ = self.shutdown.triggered() => break,
+            };
+            match next {
+                Some(Ok(next)) => data = next,
+                _ => break,
+            }
         }
     }
 }
 
-pub async fn tcp_stun_server() {
-    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)).unwrap();
+// What the client that sent `data` tells about its NAT, `None` unless `data`
+// is a register frame.
+fn register_hello(data: &[u8]) -> Option<NatHello> {
+    let hello = serde_json::from_slice::<serde_json::Value>(data).ok()?;
+    hello[REGISTER].as_bool()?.then_some(())?;
+    Some(NatHello {
+        symmetric: hello[SYMMETRIC].as_bool(),
+        candidate: hello[CANDIDATE]
+            .as_str()
+            .and_then(|candidate| candidate.parse().ok()),
+    })
+}
+
+// Bind a listening socket for the server. IPv6 sockets accept IPv4 too
+// unless `v6_only` is set or the platform refuses dual-stack, in which case
+// the server needs a separate IPv4 address to reach IPv4 clients.
+pub fn server_socket(listen_addr: SocketAddr, ty: Type, v6_only: bool) -> Socket {
+    let domain = Domain::for_address(listen_addr);
+    let protocol = if ty == Type::STREAM {
+        Protocol::TCP
+    } else {
+        Protocol::UDP
+    };
+    let socket = Socket::new(domain, ty, Some(protocol)).unwrap();
     socket.set_reuse_address(true).unwrap();
     socket.set_reuse_port(true).unwrap();
-    socket.set_only_v6(false).unwrap();
+    if domain == Domain::IPV6 {
+        if v6_only {
+            socket.set_only_v6(true).unwrap();
+        } else if let Err(err) = socket.set_only_v6(false) {
+            warn!(
+                "{} cannot accept IPv4, listen on an IPv4 address as well: {}",
+                listen_addr, err
+            );
+        }
+    }
+    socket.bind(&listen_addr.into()).unwrap();
     socket.set_nonblocking(true).unwrap();
-    let socket = unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) };
-    socket.bind(ADDR.parse().unwrap()).unwrap();
-    let listener = socket.listen(1024).unwrap();
-    info!("Tcp listening on: {}", ADDR);
-    let mut next_session_id = 0;
+    socket
+}
 
-    loop {
-        let (stream, addr) = listener.accept().await.unwrap();
-        info!("Tcp Accepted connection from: {}", addr);
-        GLOBAL_STATE.lock().unwrap().insert(next_session_id, addr);
-        tokio::spawn(async move {
-            StunSession {
-                stream: Framed::new(stream, LengthDelimitedCodec::new()),
-                session_id: next_session_id,
-                addr,
-            }
-            .run()
-            .await;
+impl RendezvousServer {
+    // Serve TCP rendezvous on every listen address until shut down, then
+    // drain the sessions already introduced to a peer.
+    async fn tcp_stun_server(&self) {
+        let ServerConfig {
+            v6_only,
+            idle_timeout,
+            ..
+        } = *self.config;
+        let shutdown = &self.shutdown;
+        // session ids are shared by all listeners, pairing spans every address
+        let next_session_id = Arc::new(AtomicUsize::new(0));
+        let sessions = TaskTracker::new();
+        let listeners = self.config.tcp_listen.iter().map(|&listen_addr| {
+            let socket = server_socket(listen_addr, Type::STREAM, v6_only);
+            let socket = unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) };
+            let listener = socket.listen(1024).unwrap();
+            info!("Tcp listening on: {}", listen_addr);
+            let next_session_id = Arc::clone(&next_session_id);
+            let sessions = sessions.clone();
+            let shutdown = shutdown.clone();
+            let limiter = Arc::clone(&self.limiter);
+            let state = Arc::clone(&self.state);
+            async move {
+                loop {
+                    let (stream, addr) = tokio::select! {
+                        res = listener.accept() => res.unwrap(),
+                        _ = shutdown.triggered() => break,
+                    };
+                    info!("Tcp Accepted connection from: {}", addr);
+                    let guard = match limiter
+                        .admit(addr.ip())
+                        .and_then(|_| limiter.open_session(addr.ip()))
+                    {
+                        Ok(guard) => guard,
+                        Err(rejection) => {
+                            info!("Tcp rejected {}: {:?}", addr, rejection);
+                            state.metrics.error(rejection.kind());
+                            continue;
+                        }
+                    };
+                    let session_id = next_session_id.fetch_add(1, Ordering::Relaxed);
+                    let span = info_span!(
+                        "tcp_session",
+                        transport = "tcp",
+                        session = session_id,
+                        peer = %addr,
+                        paired = field::Empty
+                    );
+                    let shutdown = shutdown.clone();
+                    let state = Arc::clone(&state);
+                    sessions.spawn(
+                        async move {
+                            StunSession {
+                                state,
+                                stream: Framed::new(stream, LengthDelimitedCodec::new()),
+                                session_id,
+                                addr,
+                                shutdown,
+                                idle_timeout,
+                            }
+                            .run()
+                            .await;
+                            drop(guard);
+                        }
+                        .instrument(span),
+                    );
+                }
+            }
         });
-        next_session_id += 1;
+        futures::future::join_all(listeners.collect::<Vec<_>>()).await;
+
+        // listeners are closed, wait for sessions already introduced to a peer
+        sessions.close();
+        info!(
+            "Tcp stopped accepting, draining {} sessions",
+            sessions.len()
+        );
+        if time::timeout(shutdown.drain_timeout, sessions.wait())
+            .await
+            .is_err()
+        {
+            info!("Tcp drain timed out with {} sessions left", sessions.len());
+        }
     }
 }
 
-pub async fn udp_stun_server() {
-    let socket = socket2::Socket::new(
-        socket2::Domain::IPV6,
-        socket2::Type::DGRAM,
-        Some(socket2::Protocol::UDP),
-    )
-    .unwrap();
-    socket.set_only_v6(false).unwrap();
-    socket.set_reuse_port(true).unwrap();
-    socket.set_reuse_address(true).unwrap();
-    socket
-        .bind(&ADDR.parse::<SocketAddr>().unwrap().into())
-        .unwrap();
-    socket.set_nonblocking(true).unwrap();
+/// UDP rendezvous clients known to the server. At most two are paired at a
+/// time, registrations are dropped 15 seconds after the first one arrived.
+pub(crate) struct UdpRegistrations {
+    pub(crate) nat_addr: Vec<SocketAddr>,
+    pub(crate) client_ids: HashMap<u64, SocketAddr>,
+    // addresses the clients' gateways forward to them, passed on with theirs
+    pub(crate) candidates: HashMap<SocketAddr, SocketAddr>,
+    // socket index of the server address each client last used
+    via: HashMap<SocketAddr, usize>,
+    pub(crate) registered: HashMap<SocketAddr, time::Instant>,
+    // clients of other cluster nodes paired with ours, by their node
+    remote: HashMap<SocketAddr, SocketAddr>,
+    set_time: Option<time::Instant>,
+    // answer to new registrations once the server is shutting down
+    going_away: Option<String>,
+    store: Box<dyn RegistrationStore>,
+    cluster: Arc<ClusterEvents>,
+    relay: Option<RelayIssuer>,
+    metrics: Arc<Metrics>,
+}
 
-    info!("Udp listening on: {}", ADDR);
+// A datagram for the pairing task to send once the state lock is released.
+pub(crate) struct Reply {
+    sock: usize,
+    to: SocketAddr,
+    payload: Vec<u8>,
+}
 
-    let sock = Arc::new(UdpSocket::from_std(socket.into()).unwrap());
-    let mut buf = [0; 1024];
+impl UdpRegistrations {
+    fn new(
+        store: Box<dyn RegistrationStore>,
+        cluster: Arc<ClusterEvents>,
+        relay: Option<RelayIssuer>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        UdpRegistrations {
+            nat_addr: Vec::new(),
+            client_ids: HashMap::new(),
+            candidates: HashMap::new(),
+            via: HashMap::new(),
+            registered: HashMap::new(),
+            remote: HashMap::new(),
+            set_time: None,
+            going_away: None,
+            store,
+            cluster,
+            relay,
+            metrics,
+        }
+    }
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<(String, SocketAddr)>(8);
-    let sock_clone = Arc::clone(&sock);
+    fn reply(&self, to: SocketAddr, payload: impl Into<Vec<u8>>) -> Reply {
+        Reply {
+            sock: self.via.get(&to).copied().unwrap_or_default(),
+            to,
+            payload: payload.into(),
+        }
+    }
 
-    tokio::spawn(async move {
-        let mut nat_addr: Vec<SocketAddr> = Vec::new();
-        let mut interval = tokio::time::interval(Duration::from_secs(5));
-        let mut set_time = None;
-        loop {
-            tokio::select! {
-                Some((cmd, addr)) = rx.recv() => {
-                    match cmd.as_str() {
-                        "ping" => {
-                            if !nat_addr.contains(&addr) {
-                                nat_addr.push(addr);
-                                if set_time.is_none() {
-                                    set_time = Some(tokio::time::Instant::now());
-                                }
-                                info!("Udp NAT address: {:?}", addr);
-                            }
-                            if nat_addr.len() == 2 {
-                                let peer1 = nat_addr[0];
-                                let peer2 = nat_addr[1];
-                                // exchange peer address
-                                sock_clone
-                                    .send_to(peer2.to_string().as_bytes(), peer1)
-                                    .await
-                                    .unwrap();
-                                sock_clone
-                                    .send_to(peer1.to_string().as_bytes(), peer2)
-                                    .await
-                                    .unwrap();
-                                info!("Udp exchange peer address: {:?} <-> {:?}", peer1, peer2);
-                            }
-                        }
-                        "get" => {
-                            for peer in &nat_addr {
-                                if peer != &addr {
-                                    sock_clone
-                                        .send_to(peer.to_string().as_bytes(), addr)
-                                        .await
-                                        .unwrap();
-                                    info!("Udp re-send peer address: {:?} -> {:?}", peer, addr);
-                                }
-                            }
-                        }
-                        _ => {}
+    fn handle(&mut self, cmd: &str, addr: SocketAddr, index: usize) -> Vec<Reply> {
+        self.via.insert(addr, index);
+        // `ping <id>` lets a client that punches again replace
+        // its previous registration instead of pairing with it,
+        // `ping <id> <candidate>` also names an address its gateway
+        // forwards to it
+        let mut args = cmd.split_whitespace();
+        let cmd = args.next().unwrap_or_default();
+        let client_id = args.next().and_then(|id| id.parse::<u64>().ok());
+        let candidate = args.next().and_then(|c| c.parse::<SocketAddr>().ok());
+        let mut replies = Vec::new();
+        match cmd {
+            "ping" if !self.nat_addr.contains(&addr) && self.going_away.is_some() => {
+                replies.push(self.reply(addr, self.going_away.clone().unwrap()));
+            }
+            "ping" => {
+                let mut changed = false;
+                if let Some(id) = client_id {
+                    let old = self.client_ids.insert(id, addr);
+                    changed = old != Some(addr);
+                    if let Some(old) = old
+                        && old != addr
+                    {
+                        self.remove(old);
+                        info!("Udp re-registered NAT address: {:?} -> {:?}", old, addr);
                     }
                 }
-                _ = interval.tick() => {
-                    if let Some(time) = set_time {
-                        if time.elapsed() > Duration::from_secs(15) {
-                            nat_addr.clear();
-                            set_time = None;
-                            info!("Udp clear NAT address");
+                if let Some(candidate) = candidate {
+                    self.candidates.insert(addr, candidate);
+                }
+                if !self.nat_addr.contains(&addr) {
+                    self.nat_addr.push(addr);
+                    self.registered.insert(addr, time::Instant::now());
+                    if self.set_time.is_none() {
+                        self.set_time = Some(time::Instant::now());
+                    }
+                    changed = true;
+                    info!("Udp NAT address registered");
+                }
+                if changed {
+                    self.persist(addr);
+                    if self.waiting().is_some() {
+                        self.cluster.changed();
+                    }
+                }
+                self.metrics
+                    .udp_registrations
+                    .store(self.nat_addr.len(), Ordering::Relaxed);
+                if self.nat_addr.len() == 2 {
+                    let peer1 = self.nat_addr[0];
+                    let peer2 = self.nat_addr[1];
+                    // exchange peer address
+                    for (to, peer) in [(peer1, peer2), (peer2, peer1)] {
+                        replies.extend(self.ticket(to, peer));
+                        replies.push(self.reply(to, self.introduction(peer)));
+                        if let Some(at) = self.registered.get(&to) {
+                            self.metrics.paired(lifetime::Protocol::Udp, at.elapsed());
                         }
                     }
+                    info!(peer1 = %peer1, peer2 = %peer2, "Udp exchange peer address");
+                }
+                // the client pings until it hears about a peer, one that is
+                // not introduced yet learns that we are alive
+                if !replies.iter().any(|reply| reply.to == addr) {
+                    replies.push(self.reply(addr, WAITING));
+                }
+            }
+            "get" => {
+                for peer in &self.nat_addr {
+                    if peer != &addr {
+                        replies.push(self.reply(addr, self.introduction(*peer)));
+                        info!(paired = %peer, "Udp re-send peer address");
+                    }
                 }
             }
+            _ => self.metrics.error("udp_unknown_command"),
         }
-    });
+        // remote clients hear from their own node
+        replies.retain(|reply| !self.remote.contains_key(&reply.to));
+        replies
+    }
+
+    // What a client learns about `peer`: its address, followed by its
+    // candidate if it named one.
+    fn introduction(&self, peer: SocketAddr) -> String {
+        match self.candidates.get(&peer) {
+            Some(candidate) => format!("{} {}", peer, candidate),
+            None => peer.to_string(),
+        }
+    }
+
+    // The relay ticket of `to` for its pairing with `peer`, sent ahead of the
+    // introduction so the client has it once it starts punching.
+    fn ticket(&self, to: SocketAddr, peer: SocketAddr) -> Option<Reply> {
+        let ticket = self.relay.as_ref()?.ticket(to, peer);
+        Some(self.reply(to, format!("{}{}", TICKET_PREFIX, ticket)))
+    }
+
+    /// The lone local client waiting for a peer.
+    pub(crate) fn waiting(&self) -> Option<SocketAddr> {
+        match self.nat_addr[..] {
+            [addr] if !self.remote.contains_key(&addr) && self.going_away.is_none() => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Pair the waiting `local` client with `remote`, a client of cluster
+    /// node `node`. Returns the replies introducing it, or None when `local`
+    /// is not waiting anymore.
+    pub(crate) fn pair_remote(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        node: SocketAddr,
+    ) -> Option<Vec<Reply>> {
+        if self.waiting() != Some(local) {
+            return None;
+        }
+        self.nat_addr.push(remote);
+        self.remote.insert(remote, node);
+        self.registered.insert(remote, time::Instant::now());
+        self.metrics
+            .udp_registrations
+            .store(self.nat_addr.len(), Ordering::Relaxed);
+        if let Some(at) = self.registered.get(&local) {
+            self.metrics.paired(lifetime::Protocol::Udp, at.elapsed());
+        }
+        info!(peer1 = %local, peer2 = %remote, node = %node, "Udp exchange peer address across the cluster");
+        let introduction = self.reply(local, remote.to_string());
+        Some(
+            self.ticket(local, remote)
+                .into_iter()
+                .chain([introduction])
+                .collect(),
+        )
+    }
+
+    // Write the registration of `addr` through to the store.
+    fn persist(&self, addr: SocketAddr) {
+        let Some(registered) = self.registered.get(&addr) else {
+            return;
+        };
+        self.store.put_udp(UdpRecord {
+            addr,
+            client_id: self
+                .client_ids
+                .iter()
+                .find(|(_, a)| **a == addr)
+                .map(|(id, _)| *id),
+            registered_ms: store::unix_ms(*registered),
+        });
+    }
+
+    /// Take back registrations stored by an earlier run. Their pairing
+    /// window starts over, the clients may have waited out the restart.
+    fn restore(&mut self, records: Vec<UdpRecord>) {
+        for record in records {
+            let registered = store::instant_of(record.registered_ms);
+            if let Some(id) = record.client_id {
+                self.client_ids.insert(id, record.addr);
+            }
+            self.nat_addr.push(record.addr);
+            self.registered.insert(record.addr, registered);
+        }
+        if !self.nat_addr.is_empty() {
+            self.set_time = Some(time::Instant::now());
+        }
+        self.metrics
+            .udp_registrations
+            .store(self.nat_addr.len(), Ordering::Relaxed);
+    }
+
+    /// Forget one client, returns whether it was registered.
+    pub(crate) fn remove(&mut self, addr: SocketAddr) -> bool {
+        let registered = self.nat_addr.contains(&addr);
+        self.nat_addr.retain(|a| a != &addr);
+        self.client_ids.retain(|_, a| a != &addr);
+        self.candidates.remove(&addr);
+        self.registered.remove(&addr);
+        self.remote.remove(&addr);
+        self.store.remove_udp(addr);
+        self.metrics
+            .udp_registrations
+            .store(self.nat_addr.len(), Ordering::Relaxed);
+        registered
+    }
+
+    pub(crate) fn clear(&mut self) {
+        // a server going away keeps refusing registrations
+        self.nat_addr.clear();
+        self.client_ids.clear();
+        self.candidates.clear();
+        self.via.clear();
+        self.registered.clear();
+        self.remote.clear();
+        self.set_time = None;
+        self.store.clear_udp();
+        self.metrics.udp_registrations.store(0, Ordering::Relaxed);
+        info!("Udp clear NAT address");
+    }
 
+    /// Refuse new registrations from now on and send `msg` to a client still
+    /// waiting for a peer. Pairs already introduced stay until they expire.
+    fn go_away(&mut self, msg: String) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        if let [waiting] = self.nat_addr[..] {
+            replies.push(self.reply(waiting, msg.clone()));
+            self.remove(waiting);
+        }
+        self.going_away = Some(msg);
+        replies
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nat_addr.is_empty()
+    }
+
+    fn expire(&mut self) {
+        if let Some(time) = self.set_time
+            && time.elapsed() > Duration::from_secs(15)
+        {
+            self.clear();
+        }
+    }
+}
+
+// Requests answered from the request alone, by the worker that received
+// them without touching the registrations.
+fn handle_stateless(cmd: &str, addr: SocketAddr, index: usize) -> Option<Vec<Reply>> {
+    let (cmd, arg) = match cmd.split_once(' ') {
+        Some((cmd, arg)) => (cmd, Some(arg)),
+        None => (cmd, None),
+    };
+    let mut replies = Vec::new();
+    match cmd {
+        "mapping" => {
+            // reflexive address only, the sender is not registered
+            replies.push(Reply {
+                sock: index,
+                to: addr,
+                payload: format!("{}{}", MAPPING_RESPONSE_PREFIX, addr).into_bytes(),
+            });
+        }
+        "probe" => {
+            // lifetime measurement: poke a mapping of the
+            // requester's own public address, never a third party
+            if let Some(target) = arg
+                .and_then(|target| target.parse::<SocketAddr>().ok())
+                .filter(|target| target.ip().to_canonical() == addr.ip().to_canonical())
+            {
+                replies.push(Reply {
+                    sock: index,
+                    to: target,
+                    payload: PROBE.to_vec(),
+                });
+            }
+        }
+        _ => return None,
+    }
+    Some(replies)
+}
+
+// Replies are never larger than the request that caused them, so spoofed
+// requests cannot turn the server into an amplifier. Clients pad requests.
+fn reply_fits(reply: &Reply, request_len: usize, metrics: &Metrics) -> bool {
+    if reply.payload.len() > request_len {
+        info!(
+            "Udp reply of {} bytes to {} exceeds the {} byte request, dropped",
+            reply.payload.len(),
+            reply.to,
+            request_len
+        );
+        metrics.error("reply_too_large");
+        return false;
+    }
+    true
+}
+
+// Send replies through the socket of their listen address, batched per
+// socket.
+async fn send_replies(socks: &[Arc<UdpSocket>], replies: &[Reply], metrics: &Metrics) {
+    for (index, sock) in socks.iter().enumerate() {
+        let msgs: Vec<(SocketAddr, &[u8])> = replies
+            .iter()
+            .filter(|reply| reply.sock == index)
+            .map(|reply| (reply.to, reply.payload.as_slice()))
+            .collect();
+        if msgs.is_empty() {
+            continue;
+        }
+        for (to, err) in batch::send(sock, &msgs).await {
+            info!("Udp send to {} failed: {}", to, err);
+            metrics.error("udp_send");
+        }
+    }
+}
+
+// One SO_REUSEPORT worker: receives on `socks[index]`, the kernel spreads
+// clients over the workers by their address, and answers through its own
+// sockets. Only registrations go through the shared state.
+async fn udp_worker(
+    state: Arc<ServerState>,
+    socks: Vec<Arc<UdpSocket>>,
+    index: usize,
+    limiter: Arc<RateLimiter>,
+) {
+    let mut batch = batch::RecvBatch::default();
+    let mut replies = Vec::with_capacity(batch::BATCH);
     loop {
-        let (len, addr) = sock.recv_from(&mut buf).await.unwrap();
-        info!("Udp {:?} bytes received from {:?}", len, addr);
+        if let Err(err) = batch.recv(&socks[index]).await {
+            info!("Udp receive failed: {}", err);
+            state.metrics.error("udp_recv");
+            continue;
+        }
+        for _ in 0..batch.truncated() {
+            state.metrics.error("udp_truncated");
+        }
+        replies.clear();
+        for (data, addr) in batch.iter() {
+            debug!("Udp {:?} bytes received from {:?}", data.len(), addr);
+            // rejected sources get no answer at all
+            if let Err(rejection) = limiter.admit(addr.ip()) {
+                state.metrics.error(rejection.kind());
+                continue;
+            }
+            let request = String::from_utf8_lossy(data);
+            // requests are padded with trailing spaces
+            let cmd = request.trim_end();
+            let handled = handle_stateless(cmd, addr, index).unwrap_or_else(|| {
+                let span =
+                    info_span!("udp_request", transport = "udp", peer = %addr, id = field::Empty);
+                if let Some(id) = cmd
+                    .strip_prefix("ping ")
+                    .and_then(|args| args.split(' ').next())
+                    .and_then(|id| id.parse::<u64>().ok())
+                {
+                    span.record("id", id);
+                }
+                // Registrations cannot be sharded per worker: any two
+                // clients pair, their ids are picked at random and have
+                // nothing in common, and the one waiting client must be
+                // seen by every worker. They share one lock, see `udp_pps`.
+                span.in_scope(|| state.udp.lock().unwrap().handle(cmd, addr, index))
+            });
+            replies.extend(
+                handled
+                    .into_iter()
+                    .filter(|reply| reply_fits(reply, data.len(), &state.metrics)),
+            );
+        }
+        send_replies(&socks, &replies, &state.metrics).await;
+    }
+}
 
-        tx.send((String::from_utf8_lossy(&buf[..len]).to_string(), addr))
-            .await
-            .unwrap();
+impl RendezvousServer {
+    // Serve UDP rendezvous on every listen address with `udp_workers`
+    // sockets per address sharing the port through SO_REUSEPORT.
+    async fn udp_stun_server(&self) {
+        let listen_addrs = &self.config.udp_listen;
+        let shutdown = &self.shutdown;
+        let state = &self.state;
+        // socks[worker][index], replies leave through the socket of the listen
+        // address the client last used
+        let socks: Vec<Vec<Arc<UdpSocket>>> = (0..self.config.udp_workers)
+            .map(|_| {
+                listen_addrs
+                    .iter()
+                    .map(|listen_addr| {
+                        let socket = server_socket(*listen_addr, Type::DGRAM, self.config.v6_only);
+                        Arc::new(UdpSocket::from_std(socket.into()).unwrap())
+                    })
+                    .collect()
+            })
+            .collect();
+        for listen_addr in listen_addrs {
+            info!(
+                "Udp listening on: {} with {} workers",
+                listen_addr,
+                socks.len()
+            );
+        }
+        {
+            let mut udp_state = state.udp.lock().unwrap();
+            let records = udp_state.store.load().udp;
+            if !records.is_empty() {
+                info!("Udp restoring {} registrations", records.len());
+                udp_state.restore(records);
+            }
+        }
+        let receivers: Vec<_> = socks
+            .iter()
+            .flat_map(|worker| {
+                (0..worker.len()).map(|index| {
+                    tokio::spawn(udp_worker(
+                        Arc::clone(state),
+                        worker.clone(),
+                        index,
+                        Arc::clone(&self.limiter),
+                    ))
+                })
+            })
+            .collect();
+
+        let (outbox, mut unsolicited) = mpsc::unbounded_channel();
+        *state.udp_outbox.lock().unwrap() = Some(outbox);
+
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        let mut drain_deadline = None;
+        loop {
+            tokio::select! {
+                Some(reply) = unsolicited.recv() => {
+                    if reply_fits(&reply, REQUEST_SIZE, &state.metrics) {
+                        send_replies(&socks[0], &[reply], &state.metrics).await;
+                    }
+                }
+                _ = interval.tick() => {
+                    let mut udp_state = state.udp.lock().unwrap();
+                    udp_state.expire();
+                    if drain_deadline.is_some() && udp_state.is_empty() {
+                        break;
+                    }
+                }
+                _ = shutdown.triggered(), if drain_deadline.is_none() => {
+                    let replies = state.udp.lock().unwrap().go_away(shutdown.udp_message());
+                    // unsolicited, the going-away message fits any padded request
+                    let replies: Vec<_> = replies
+                        .into_iter()
+                        .filter(|reply| reply_fits(reply, REQUEST_SIZE, &state.metrics))
+                        .collect();
+                    send_replies(&socks[0], &replies, &state.metrics).await;
+                    info!("Udp refusing new registrations, draining");
+                    drain_deadline = Some(time::Instant::now() + shutdown.drain_timeout);
+                    if state.udp.lock().unwrap().is_empty() {
+                        break;
+                    }
+                }
+                _ = time::sleep_until(drain_deadline.unwrap_or_else(time::Instant::now)), if drain_deadline.is_some() => {
+                    info!("Udp drain timed out");
+                    break;
+                }
+            }
+        }
+        *state.udp_outbox.lock().unwrap() = None;
+        for receiver in receivers {
+            receiver.abort();
+        }
     }
 }
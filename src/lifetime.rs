@@ -0,0 +1,193 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::info;
+
+use crate::{
+    traversal::{MAPPING_REQUEST, any_addr_for, padded, parse_mapping_response},
+    udp,
+};
+
+// Payload of lifetime probes. Over UDP the server sends it to the mapping
+// named in `probe <addr>`, over TCP it echoes it back.
+pub const PROBE: &[u8] = b"probe";
+
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+const REQUEST_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Udp => write!(f, "Udp"),
+            Protocol::Tcp => write!(f, "Tcp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LifetimeConfig {
+    /// Longest idle period tried.
+    pub max: Duration,
+    /// Search stops once the bounds are this close.
+    pub resolution: Duration,
+}
+
+impl Default for LifetimeConfig {
+    fn default() -> Self {
+        LifetimeConfig {
+            max: Duration::from_secs(300),
+            resolution: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The binding survived `alive` seconds of idleness and was gone after
+/// `expired`, or outlived the longest period tried when `expired` is `None`.
+#[derive(Debug, Clone, Copy)]
+pub struct LifetimeReport {
+    pub protocol: Protocol,
+    pub alive: Duration,
+    pub expired: Option<Duration>,
+}
+
+impl fmt::Display for LifetimeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expired {
+            Some(expired) => write!(
+                f,
+                "{} binding timeout between {}s and {}s",
+                self.protocol,
+                self.alive.as_secs(),
+                expired.as_secs()
+            ),
+            None => write!(
+                f,
+                "{} binding timeout above {}s",
+                self.protocol,
+                self.alive.as_secs()
+            ),
+        }
+    }
+}
+
+/// Binary search the idle period after which the NAT drops a binding
+/// towards `server`.
+pub async fn measure(
+    server: SocketAddr,
+    protocol: Protocol,
+    config: LifetimeConfig,
+) -> std::io::Result<LifetimeReport> {
+    let mut alive = Duration::ZERO;
+    let mut expired = config.max;
+    if probe(server, protocol, expired).await? {
+        return Ok(LifetimeReport {
+            protocol,
+            alive: expired,
+            expired: None,
+        });
+    }
+    while expired - alive > config.resolution {
+        let idle = (alive + expired) / 2;
+        if probe(server, protocol, idle).await? {
+            alive = idle;
+        } else {
+            expired = idle;
+        }
+    }
+    Ok(LifetimeReport {
+        protocol,
+        alive,
+        expired: Some(expired),
+    })
+}
+
+async fn probe(server: SocketAddr, protocol: Protocol, idle: Duration) -> std::io::Result<bool> {
+    let alive = match protocol {
+        Protocol::Udp => probe_udp(server, idle).await?,
+        Protocol::Tcp => probe_tcp(server, idle).await?,
+    };
+    info!(
+        "{} binding {} after {}s idle",
+        protocol,
+        if alive { "alive" } else { "expired" },
+        idle.as_secs()
+    );
+    Ok(alive)
+}
+
+// Open a mapping, leave it idle, then have the server send to it on behalf
+// of a second socket behind the same NAT.
+async fn probe_udp(server: SocketAddr, idle: Duration) -> std::io::Result<bool> {
+    let sock = udp::create_socket(any_addr_for(server))?;
+    let mut buf = [0; 1024];
+
+    let mut mapped = None;
+    for _ in 0..REQUEST_RETRIES {
+        sock.send_to(&padded(MAPPING_REQUEST), server).await?;
+        if let Ok(Ok((len, _))) = time::timeout(ANSWER_TIMEOUT, sock.recv_from(&mut buf)).await {
+            mapped = parse_mapping_response(&buf[..len]);
+            break;
+        }
+    }
+    let Some(mapped) = mapped else {
+        return Err(std::io::Error::other("no mapping response from server"));
+    };
+
+    time::sleep(idle).await;
+
+    let trigger = udp::create_socket(any_addr_for(server))?;
+    for _ in 0..REQUEST_RETRIES {
+        trigger
+            .send_to(&padded(format!("probe {}", mapped).as_bytes()), server)
+            .await?;
+        if wait_probe(&sock, &mut buf).await {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn wait_probe(sock: &UdpSocket, buf: &mut [u8]) -> bool {
+    let deadline = time::Instant::now() + ANSWER_TIMEOUT;
+    while let Ok(Ok((len, _))) = time::timeout_at(deadline, sock.recv_from(buf)).await {
+        if &buf[..len] == PROBE {
+            return true;
+        }
+    }
+    false
+}
+
+// Leave an established connection idle, then check the server still
+// echoes: once the NAT forgot the binding our segment goes nowhere.
+async fn probe_tcp(server: SocketAddr, idle: Duration) -> std::io::Result<bool> {
+    let stream = TcpStream::connect(server).await?;
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    stream.send(bytes::Bytes::from_static(PROBE)).await?;
+    match time::timeout(ANSWER_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(data))) if data.as_ref() == PROBE => {}
+        _ => return Err(std::io::Error::other("server does not answer tcp probes")),
+    }
+
+    time::sleep(idle).await;
+
+    if stream.send(bytes::Bytes::from_static(PROBE)).await.is_err() {
+        return Ok(false);
+    }
+    Ok(matches!(
+        time::timeout(ANSWER_TIMEOUT * 2, stream.next()).await,
+        Ok(Some(Ok(data))) if data.as_ref() == PROBE
+    ))
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+// Buckets are pruned once this many sources are tracked, full buckets carry
+// no state worth keeping. Pruning walks every bucket of a shard, so it runs
+// at most once per `PRUNE_INTERVAL` however many sources show up meanwhile.
+const MAX_TRACKED: usize = 4096;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+// Sources are spread by prefix over this many separately locked shards, so
+// UDP workers seldom wait for each other. A source and its prefix share one.
+const SHARDS: usize = 16;
+
+/// An address block such as `10.0.0.0/8` or `2001:db8::/32`, a bare address
+/// is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask_v4(ip.to_bits(), self.prefix) == mask_v4(net.to_bits(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask_v6(ip.to_bits(), self.prefix) == mask_v6(net.to_bits(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("{}: {}", s, err))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("{}: prefix must be at most {}", s, max))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    bits & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    bits & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+// Block of `prefix_v4`/`prefix_v6` bits that `ip` is rate limited with.
+fn prefix_of(ip: IpAddr, prefix_v4: u8, prefix_v6: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(mask_v4(ip.to_bits(), prefix_v4).into()),
+        IpAddr::V6(ip) => IpAddr::V6(mask_v6(ip.to_bits(), prefix_v6).into()),
+    }
+}
+
+/// Requests allowed per second on average, with bursts up to `burst`.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    pub per_ip: Rate,
+    /// Shared by every address in the same `prefix_v4`/`prefix_v6` block.
+    pub per_prefix: Rate,
+    pub prefix_v4: u8,
+    pub prefix_v6: u8,
+    /// Concurrent TCP rendezvous sessions per address.
+    pub max_sessions_per_ip: usize,
+    /// When not empty only these blocks are served.
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            per_ip: Rate {
+                per_sec: 10.0,
+                burst: 20.0,
+            },
+            per_prefix: Rate {
+                per_sec: 50.0,
+                burst: 100.0,
+            },
+            prefix_v4: 24,
+            prefix_v6: 48,
+            max_sessions_per_ip: 8,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Denied,
+    IpRateLimited,
+    PrefixRateLimited,
+    TooManySessions,
+}
+
+impl Rejection {
+    /// Error kind counted in the metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Rejection::Denied => "denied",
+            Rejection::IpRateLimited => "rate_limited_ip",
+            Rejection::PrefixRateLimited => "rate_limited_prefix",
+            Rejection::TooManySessions => "too_many_sessions",
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        TokenBucket {
+            tokens: rate.burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: Rate) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst);
+        self.last = now;
+    }
+
+    fn is_full(&mut self, rate: Rate) -> bool {
+        self.refill(rate);
+        self.tokens >= rate.burst
+    }
+}
+
+struct LimiterState {
+    ips: HashMap<IpAddr, TokenBucket>,
+    prefixes: HashMap<IpAddr, TokenBucket>,
+    sessions: HashMap<IpAddr, usize>,
+    pruned: Instant,
+}
+
+impl LimiterState {
+    fn new() -> Self {
+        LimiterState {
+            ips: HashMap::new(),
+            prefixes: HashMap::new(),
+            sessions: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    fn prune(&mut self, per_ip: Rate, per_prefix: Rate) {
+        let max = MAX_TRACKED / SHARDS;
+        let crowded = self.ips.len() > max || self.prefixes.len() > max;
+        if !crowded || self.pruned.elapsed() < PRUNE_INTERVAL {
+            return;
+        }
+        self.ips.retain(|_, bucket| !bucket.is_full(per_ip));
+        self.prefixes
+            .retain(|_, bucket| !bucket.is_full(per_prefix));
+        self.pruned = Instant::now();
+    }
+}
+
+/// Decides which sources the rendezvous servers answer. Shared by the TCP
+/// and UDP servers, so a source cannot double its budget by using both.
+pub struct RateLimiter {
+    config: LimitConfig,
+    shards: Vec<Mutex<LimiterState>>,
+    hasher: RandomState,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitConfig) -> Arc<Self> {
+        Arc::new(RateLimiter {
+            config,
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(LimiterState::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        })
+    }
+
+    // The shard keeping the buckets and sessions of `ip`, with its prefix.
+    fn shard(&self, ip: IpAddr) -> (IpAddr, MutexGuard<'_, LimiterState>) {
+        let prefix = prefix_of(ip, self.config.prefix_v4, self.config.prefix_v6);
+        let shard = self.hasher.hash_one(prefix) as usize % SHARDS;
+        (prefix, self.shards[shard].lock().unwrap())
+    }
+
+    /// Whether `ip` is listed at all, without spending a token.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        (self.config.allow.is_empty() || self.config.allow.iter().any(|net| net.contains(ip)))
+            && !self.config.deny.iter().any(|net| net.contains(ip))
+    }
+
+    /// Spend a token of `ip` and its prefix for one request.
+    pub fn admit(&self, ip: IpAddr) -> Result<(), Rejection> {
+        if !self.is_allowed(ip) {
+            return Err(Rejection::Denied);
+        }
+        let ip = ip.to_canonical();
+        let (per_ip, per_prefix) = (self.config.per_ip, self.config.per_prefix);
+        let (prefix, mut state) = self.shard(ip);
+        state.prune(per_ip, per_prefix);
+
+        let ip_bucket = state
+            .ips
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(per_ip));
+        ip_bucket.refill(per_ip);
+        if ip_bucket.tokens < 1.0 {
+            return Err(Rejection::IpRateLimited);
+        }
+        let prefix_bucket = state
+            .prefixes
+            .entry(prefix)
+            .or_insert_with(|| TokenBucket::new(per_prefix));
+        prefix_bucket.refill(per_prefix);
+        if prefix_bucket.tokens < 1.0 {
+            return Err(Rejection::PrefixRateLimited);
+        }
+        prefix_bucket.tokens -= 1.0;
+        state.ips.get_mut(&ip).unwrap().tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Count a TCP session of `ip` until the returned guard is dropped.
+    pub fn open_session(self: &Arc<Self>, ip: IpAddr) -> Result<SessionGuard, Rejection> {
+        let ip = ip.to_canonical();
+        let (_, mut state) = self.shard(ip);
+        let sessions = state.sessions.entry(ip).or_default();
+        if *sessions >= self.config.max_sessions_per_ip {
+            return Err(Rejection::TooManySessions);
+        }
+        *sessions += 1;
+        Ok(SessionGuard {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+pub struct SessionGuard {
+    limiter: Arc<RateLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let (_, mut state) = self.limiter.shard(self.ip);
+        if let Some(sessions) = state.sessions.get_mut(&self.ip) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                state.sessions.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(n.into())
+    }
+
+    fn tracked(limiter: &RateLimiter) -> usize {
+        limiter
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().ips.len())
+            .sum()
+    }
+
+    #[test]
+    fn prunes_full_buckets_once_per_interval() {
+        let limiter = RateLimiter::new(LimitConfig::default());
+        let sources = 2 * MAX_TRACKED as u32;
+        for n in 0..sources {
+            limiter.admit(ip(n << 8)).unwrap();
+        }
+        // crowded, but pruned too recently
+        assert_eq!(tracked(&limiter), sources as usize);
+
+        // every bucket refilled, the next request to a shard prunes it
+        std::thread::sleep(Duration::from_millis(150));
+        for shard in &limiter.shards {
+            shard.lock().unwrap().pruned -= PRUNE_INTERVAL;
+        }
+        let last = ip(u32::MAX);
+        limiter.admit(last).unwrap();
+        assert_eq!(limiter.shard(last).1.ips.len(), 1);
+        assert!(tracked(&limiter) < sources as usize);
+    }
+
+    #[test]
+    fn counts_sessions_until_dropped() {
+        let limiter = RateLimiter::new(LimitConfig::default());
+        let guards: Vec<_> = (0..8)
+            .map(|_| limiter.open_session(ip(1)).unwrap())
+            .collect();
+        assert!(matches!(
+            limiter.open_session(ip(1)),
+            Err(Rejection::TooManySessions)
+        ));
+        drop(guards);
+        assert!(limiter.open_session(ip(1)).is_ok());
+        assert!(limiter.shard(ip(1)).1.sessions.is_empty());
+    }
+
+    #[test]
+    fn limits_sources_and_prefixes() {
+        let limiter = RateLimiter::new(LimitConfig::default());
+        for _ in 0..20 {
+            limiter.admit(ip(1)).unwrap();
+        }
+        assert_eq!(limiter.admit(ip(1)), Err(Rejection::IpRateLimited));
+        // the rest of the /24 shares 100 requests
+        for n in 2..6 {
+            for _ in 0..20 {
+                limiter.admit(ip(n)).unwrap();
+            }
+        }
+        assert_eq!(limiter.admit(ip(7)), Err(Rejection::PrefixRateLimited));
+        assert_eq!(limiter.admit(ip(1 << 8)), Ok(()));
+    }
+}
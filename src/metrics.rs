@@ -0,0 +1,181 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{
+    http::{self, Response},
+    lifetime::Protocol,
+};
+
+// Upper bounds of the pairing latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    // microseconds, so the sum stays an integer
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                labels,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Counters of a rendezvous or relay server, exported in the Prometheus text
+/// format by `metrics_server`. Every server counts into its own unless it is
+/// built with the `Arc<Metrics>` of another, then they add up.
+#[derive(Default)]
+pub struct Metrics {
+    pub active_sessions: AtomicUsize,
+    pub udp_registrations: AtomicUsize,
+    pub tcp_pairings: AtomicU64,
+    pub udp_pairings: AtomicU64,
+    pub relay_bytes: AtomicU64,
+    pub tcp_pairing_latency: Histogram,
+    pub udp_pairing_latency: Histogram,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    /// A peer was handed the address of another peer `latency` after it
+    /// registered.
+    pub fn paired(&self, protocol: Protocol, latency: Duration) {
+        let (pairings, histogram) = match protocol {
+            Protocol::Tcp => (&self.tcp_pairings, &self.tcp_pairing_latency),
+            Protocol::Udp => (&self.udp_pairings, &self.udp_pairing_latency),
+        };
+        pairings.fetch_add(1, Ordering::Relaxed);
+        histogram.observe(latency);
+    }
+
+    pub fn error(&self, kind: &'static str) {
+        *self.errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP nat_active_sessions Registered TCP rendezvous sessions."
+        );
+        let _ = writeln!(out, "# TYPE nat_active_sessions gauge");
+        let _ = writeln!(
+            out,
+            "nat_active_sessions {}",
+            self.active_sessions.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nat_udp_registrations Registered UDP NAT addresses."
+        );
+        let _ = writeln!(out, "# TYPE nat_udp_registrations gauge");
+        let _ = writeln!(
+            out,
+            "nat_udp_registrations {}",
+            self.udp_registrations.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nat_pairings_total Peers handed the address of another peer."
+        );
+        let _ = writeln!(out, "# TYPE nat_pairings_total counter");
+        let _ = writeln!(
+            out,
+            "nat_pairings_total{{protocol=\"tcp\"}} {}",
+            self.tcp_pairings.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "nat_pairings_total{{protocol=\"udp\"}} {}",
+            self.udp_pairings.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nat_relay_bytes_total Bytes forwarded by the relay."
+        );
+        let _ = writeln!(out, "# TYPE nat_relay_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "nat_relay_bytes_total {}",
+            self.relay_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP nat_request_errors_total Failed or rejected requests by kind."
+        );
+        let _ = writeln!(out, "# TYPE nat_request_errors_total counter");
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "nat_request_errors_total{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP nat_pairing_latency_seconds Time from registration until the peer address was sent."
+        );
+        let _ = writeln!(out, "# TYPE nat_pairing_latency_seconds histogram");
+        self.tcp_pairing_latency.render(
+            &mut out,
+            "nat_pairing_latency_seconds",
+            "protocol=\"tcp\"",
+        );
+        self.udp_pairing_latency.render(
+            &mut out,
+            "nat_pairing_latency_seconds",
+            "protocol=\"udp\"",
+        );
+        out
+    }
+}
+
+/// Serve `metrics` on `GET /metrics`.
+pub async fn metrics_server(listen_addr: SocketAddr, metrics: Arc<Metrics>) {
+    http::serve(listen_addr, move |request| match request.path.as_str() {
+        "/metrics" => Response::ok("text/plain; version=0.0.4", metrics.render()),
+        _ => Response::not_found(),
+    })
+    .await
+}
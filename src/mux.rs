@@ -0,0 +1,695 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+use tracing::info;
+
+// Frames follow yamux: a 12 byte header of version, type, flags, stream id
+// and length, data frames carry `length` bytes of payload after it. Every
+// frame travels in a frame of its own on the underlying transport.
+const VERSION: u8 = 0;
+const HEADER_SIZE: usize = 12;
+/// Credit every stream starts with in both directions.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+// Largest payload of one data frame.
+const MAX_DATA: usize = 16 * 1024;
+// Opened streams nobody accepted yet, further ones are reset.
+const ACCEPT_BACKLOG: usize = 256;
+
+const SYN: u16 = 1;
+const ACK: u16 = 2;
+const FIN: u16 = 4;
+const RST: u16 = 8;
+
+// GoAway code of a session closed on purpose.
+const NORMAL: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Type {
+    Data = 0,
+    WindowUpdate = 1,
+    Ping = 2,
+    GoAway = 3,
+}
+
+impl Type {
+    fn parse(ty: u8) -> Option<Self> {
+        match ty {
+            0 => Some(Type::Data),
+            1 => Some(Type::WindowUpdate),
+            2 => Some(Type::Ping),
+            3 => Some(Type::GoAway),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    ty: Type,
+    flags: u16,
+    stream: u32,
+    length: u32,
+}
+
+impl Header {
+    fn parse(frame: &[u8]) -> Option<Self> {
+        let mut frame = frame.get(..HEADER_SIZE)?;
+        if frame.get_u8() != VERSION {
+            return None;
+        }
+        Some(Header {
+            ty: Type::parse(frame.get_u8())?,
+            flags: frame.get_u16(),
+            stream: frame.get_u32(),
+            length: frame.get_u32(),
+        })
+    }
+
+    fn encode(&self, payload: &[u8]) -> Bytes {
+        let mut frame = BytesMut::with_capacity(HEADER_SIZE + payload.len());
+        frame.put_u8(VERSION);
+        frame.put_u8(self.ty as u8);
+        frame.put_u16(self.flags);
+        frame.put_u32(self.stream);
+        frame.put_u32(self.length);
+        frame.put_slice(payload);
+        frame.freeze()
+    }
+}
+
+fn control(ty: Type, flags: u16, stream: u32, length: u32) -> Bytes {
+    Header {
+        ty,
+        flags,
+        stream,
+        length,
+    }
+    .encode(&[])
+}
+
+/// Whether `frame` is a multiplexer frame rather than other traffic of the
+/// connection, e.g. keepalives.
+pub fn is_frame(frame: &[u8]) -> bool {
+    Header::parse(frame).is_some()
+}
+
+/// Which stream ids an end allocates, the two ends must differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Odd stream ids.
+    Client,
+    /// Even stream ids.
+    Server,
+}
+
+#[derive(Debug)]
+struct StreamState {
+    // received payloads not read yet
+    received: VecDeque<Bytes>,
+    // how much the peer may still send before we grant more
+    recv_window: u32,
+    // bytes read since the last window update
+    consumed: u32,
+    // how much we may still send
+    send_window: u32,
+    local_closed: bool,
+    remote_closed: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            received: VecDeque::new(),
+            recv_window: INITIAL_WINDOW,
+            consumed: 0,
+            send_window: INITIAL_WINDOW,
+            local_closed: false,
+            remote_closed: false,
+            reset: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    mode: Mode,
+    // no new streams, we or the peer sent GoAway
+    going_away: bool,
+    // the driver is gone, nothing moves anymore
+    closed: bool,
+}
+
+impl Shared {
+    fn close(&mut self) {
+        self.closed = true;
+        for stream in self.streams.values_mut() {
+            stream.wake();
+        }
+    }
+}
+
+/// Opens streams and says goodbye on a multiplexed session, can be cloned
+/// into every task that opens streams.
+#[derive(Debug, Clone)]
+pub struct MuxControl {
+    shared: Arc<Mutex<Shared>>,
+    outbound: mpsc::UnboundedSender<Bytes>,
+}
+
+impl MuxControl {
+    /// Open a new stream. The peer learns about it with the first frame, the
+    /// stream can be written to right away.
+    pub fn open(&self) -> io::Result<MuxStream> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.closed || shared.going_away {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "session is going away",
+            ));
+        }
+        let id = shared.next_id;
+        shared.next_id = id
+            .checked_add(2)
+            .ok_or_else(|| io::Error::other("stream ids exhausted"))?;
+        // the lock is held, nothing of the stream can arrive before it exists
+        self.outbound
+            .send(control(Type::WindowUpdate, SYN, id, 0))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        shared.streams.insert(id, StreamState::new());
+        Ok(MuxStream {
+            id,
+            shared: Arc::clone(&self.shared),
+            outbound: self.outbound.clone(),
+        })
+    }
+
+    /// Tell the peer no new streams will be opened or accepted, streams
+    /// already open carry on.
+    pub fn close(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.going_away {
+            shared.going_away = true;
+            let _ = self.outbound.send(control(Type::GoAway, 0, 0, NORMAL));
+        }
+    }
+}
+
+/// The application's side of a multiplexed session: open streams to the
+/// peer and accept the ones it opens. The session itself is moved by a
+/// `MuxDriver` over whatever transport carries it.
+#[derive(Debug)]
+pub struct Mux {
+    control: MuxControl,
+    incoming: mpsc::Receiver<MuxStream>,
+}
+
+impl Mux {
+    /// A session whose frames `driver` exchanges with the peer. The transport
+    /// must deliver them reliably and in order.
+    pub fn new(mode: Mode) -> (Mux, MuxDriver) {
+        let (outbound, frames) = mpsc::unbounded_channel();
+        let (accepted, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(Mutex::new(Shared {
+            streams: HashMap::new(),
+            next_id: match mode {
+                Mode::Client => 1,
+                Mode::Server => 2,
+            },
+            mode,
+            going_away: false,
+            closed: false,
+        }));
+        let control = MuxControl {
+            shared: Arc::clone(&shared),
+            outbound,
+        };
+        let driver = MuxDriver {
+            control: control.clone(),
+            frames,
+            accepted,
+        };
+        (Mux { control, incoming }, driver)
+    }
+
+    pub fn open(&self) -> io::Result<MuxStream> {
+        self.control.open()
+    }
+
+    /// The next stream the peer opened, `None` once the session ended.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+
+    pub fn control(&self) -> MuxControl {
+        self.control.clone()
+    }
+}
+
+/// The transport's side of a multiplexed session. The transport loop sends
+/// what `outbound` yields and passes every frame `is_frame` recognizes to
+/// `inbound`. Dropping the driver ends the session, streams fail from then on.
+#[derive(Debug)]
+pub struct MuxDriver {
+    control: MuxControl,
+    frames: mpsc::UnboundedReceiver<Bytes>,
+    accepted: mpsc::Sender<MuxStream>,
+}
+
+impl MuxDriver {
+    /// The next frame to send to the peer.
+    pub async fn outbound(&mut self) -> Bytes {
+        // the driver holds a sender itself, the channel never closes
+        self.frames.recv().await.unwrap()
+    }
+
+    /// Handle a frame from the peer. Errors are protocol violations, after
+    /// which the transport should be closed.
+    pub fn inbound(&mut self, frame: &[u8]) -> io::Result<()> {
+        let header = Header::parse(frame)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a mux frame"))?;
+        self.handle(header, Bytes::copy_from_slice(&frame[HEADER_SIZE..]))
+    }
+
+    fn send(&self, frame: Bytes) {
+        // the driver holds the receiver, this cannot fail
+        let _ = self.control.outbound.send(frame);
+    }
+
+    fn handle(&mut self, header: Header, payload: Bytes) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        match header.ty {
+            Type::Ping => {
+                if header.flags & SYN != 0 {
+                    self.send(control(Type::Ping, ACK, 0, header.length));
+                }
+                return Ok(());
+            }
+            Type::GoAway => {
+                info!("Peer going away, code {}", header.length);
+                self.control.shared.lock().unwrap().going_away = true;
+                return Ok(());
+            }
+            Type::Data if payload.len() != header.length as usize => {
+                return Err(invalid("data length does not match the frame"));
+            }
+            Type::Data | Type::WindowUpdate => {}
+        }
+        if header.stream == 0 {
+            return Err(invalid("stream frame for stream 0"));
+        }
+
+        let mut shared = self.control.shared.lock().unwrap();
+        if header.flags & SYN != 0 {
+            let ours = match shared.mode {
+                Mode::Client => !header.stream.is_multiple_of(2),
+                Mode::Server => header.stream.is_multiple_of(2),
+            };
+            if ours || shared.streams.contains_key(&header.stream) {
+                return Err(invalid("peer opened a stream with one of our ids"));
+            }
+            if shared.going_away {
+                self.send(control(Type::WindowUpdate, RST, header.stream, 0));
+                return Ok(());
+            }
+            let stream = MuxStream {
+                id: header.stream,
+                shared: Arc::clone(&self.control.shared),
+                outbound: self.control.outbound.clone(),
+            };
+            shared.streams.insert(header.stream, StreamState::new());
+            match self.accepted.try_send(stream) {
+                Ok(()) => self.send(control(Type::WindowUpdate, ACK, header.stream, 0)),
+                Err(err) => {
+                    // dropping the stream resets it
+                    info!("Refusing stream {}, nobody accepts", header.stream);
+                    drop(shared);
+                    drop(err);
+                    return Ok(());
+                }
+            }
+        }
+
+        // frames of streams we dropped are late, not wrong
+        let Some(stream) = shared.streams.get_mut(&header.stream) else {
+            return Ok(());
+        };
+        match header.ty {
+            Type::WindowUpdate => {
+                stream.send_window = stream.send_window.saturating_add(header.length);
+            }
+            Type::Data if !payload.is_empty() => {
+                if header.length > stream.recv_window {
+                    return Err(invalid("peer exceeded the stream window"));
+                }
+                if stream.remote_closed {
+                    return Err(invalid("data after the peer closed the stream"));
+                }
+                stream.recv_window -= header.length;
+                stream.received.push_back(payload);
+            }
+            _ => {}
+        }
+        if header.flags & FIN != 0 {
+            stream.remote_closed = true;
+        }
+        if header.flags & RST != 0 {
+            stream.reset = true;
+        }
+        stream.wake();
+        Ok(())
+    }
+}
+
+impl Drop for MuxDriver {
+    fn drop(&mut self) {
+        self.control.shared.lock().unwrap().close();
+    }
+}
+
+/// One logical channel of a multiplexed session, read and written like a
+/// socket. Shutting it down closes our direction, dropping it before the
+/// peer closed resets the stream.
+#[derive(Debug)]
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Mutex<Shared>>,
+    outbound: mpsc::UnboundedSender<Bytes>,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        let closed = shared.closed;
+        let stream = shared.streams.get_mut(&self.id).unwrap();
+        if let Some(front) = stream.received.front_mut() {
+            let len = front.len().min(buf.remaining());
+            buf.put_slice(&front.split_to(len));
+            if front.is_empty() {
+                stream.received.pop_front();
+            }
+            // grant the peer more once half the window was read
+            stream.consumed += len as u32;
+            if stream.consumed >= INITIAL_WINDOW / 2 && !stream.remote_closed && !closed {
+                let update = stream.consumed;
+                stream.recv_window += update;
+                stream.consumed = 0;
+                let _ = self
+                    .outbound
+                    .send(control(Type::WindowUpdate, 0, self.id, update));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if stream.remote_closed {
+            return Poll::Ready(Ok(()));
+        }
+        if stream.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if closed {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+        }
+        stream.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        let closed = shared.closed;
+        let stream = shared.streams.get_mut(&self.id).unwrap();
+        if stream.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if stream.local_closed || closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if stream.send_window == 0 {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.len().min(stream.send_window as usize).min(MAX_DATA);
+        stream.send_window -= len as u32;
+        let header = Header {
+            ty: Type::Data,
+            flags: 0,
+            stream: self.id,
+            length: len as u32,
+        };
+        match self.outbound.send(header.encode(&buf[..len])) {
+            Ok(()) => Poll::Ready(Ok(len)),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    // frames leave as soon as the driver gets to them
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        let stream = shared.streams.get_mut(&self.id).unwrap();
+        if !stream.local_closed && !stream.reset {
+            stream.local_closed = true;
+            let _ = self.outbound.send(control(Type::Data, FIN, self.id, 0));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        let Some(stream) = shared.streams.remove(&self.id) else {
+            return;
+        };
+        if stream.reset {
+            return;
+        }
+        // unread data would stall the peer, it must stop sending
+        let flags = if !stream.remote_closed {
+            RST
+        } else if !stream.local_closed {
+            FIN
+        } else {
+            return;
+        };
+        let _ = self
+            .outbound
+            .send(control(Type::WindowUpdate, flags, self.id, 0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        time,
+    };
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    use super::*;
+
+    // Move the frames of `driver` over `io` until either side gives up.
+    async fn pump(mut driver: MuxDriver, io: DuplexStream) {
+        let mut framed = Framed::new(io, LengthDelimitedCodec::new());
+        loop {
+            tokio::select! {
+                frame = driver.outbound() => {
+                    if framed.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+                frame = framed.next() => match frame {
+                    Some(Ok(frame)) if driver.inbound(&frame).is_ok() => {}
+                    _ => return,
+                },
+            }
+        }
+    }
+
+    // Two ends of a session over an in-memory transport.
+    fn pair() -> (Mux, Mux) {
+        let (client, client_driver) = Mux::new(Mode::Client);
+        let (server, server_driver) = Mux::new(Mode::Server);
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(pump(client_driver, a));
+        tokio::spawn(pump(server_driver, b));
+        (client, server)
+    }
+
+    async fn within<T>(future: impl Future<Output = T>) -> T {
+        time::timeout(Duration::from_secs(2), future).await.unwrap()
+    }
+
+    fn frame(ty: Type, flags: u16, stream: u32, payload: &[u8]) -> Bytes {
+        Header {
+            ty,
+            flags,
+            stream,
+            length: payload.len() as u32,
+        }
+        .encode(payload)
+    }
+
+    #[tokio::test]
+    async fn accepted_streams_carry_data_both_ways() {
+        let (client, mut server) = pair();
+        let mut opened = client.open().unwrap();
+        opened.write_all(b"hello").await.unwrap();
+        opened.shutdown().await.unwrap();
+
+        let mut accepted = within(server.accept()).await.unwrap();
+        assert_eq!(accepted.id(), opened.id());
+        assert_eq!(accepted.id() % 2, 1);
+        let mut buf = Vec::new();
+        within(accepted.read_to_end(&mut buf)).await.unwrap();
+        assert_eq!(buf, b"hello");
+        accepted.write_all(b"world").await.unwrap();
+        accepted.shutdown().await.unwrap();
+
+        buf.clear();
+        within(opened.read_to_end(&mut buf)).await.unwrap();
+        assert_eq!(buf, b"world");
+        // the server allocates even ids
+        assert_eq!(server.open().unwrap().id(), 2);
+    }
+
+    #[tokio::test]
+    async fn dropping_an_open_stream_resets_it() {
+        let (client, mut server) = pair();
+        let mut opened = client.open().unwrap();
+        opened.write_all(b"unread").await.unwrap();
+        drop(within(server.accept()).await.unwrap());
+
+        let mut buf = [0; 16];
+        let err = within(opened.read(&mut buf)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let err = opened.write(b"more").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn writes_wait_for_window_updates() {
+        let (client, mut server) = pair();
+        let mut opened = client.open().unwrap();
+        let data = vec![7; INITIAL_WINDOW as usize];
+        within(opened.write_all(&data)).await.unwrap();
+        // the window is used up until the peer reads
+        assert!(
+            time::timeout(Duration::from_millis(200), opened.write(b"x"))
+                .await
+                .is_err()
+        );
+
+        let mut accepted = within(server.accept()).await.unwrap();
+        let mut buf = vec![0; INITIAL_WINDOW as usize / 2];
+        within(accepted.read_exact(&mut buf)).await.unwrap();
+        assert_eq!(within(opened.write(b"x")).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn resets_streams_beyond_the_accept_backlog() {
+        let (client, _server) = pair();
+        let mut streams = (0..=ACCEPT_BACKLOG)
+            .map(|_| client.open().unwrap())
+            .collect::<Vec<_>>();
+        let mut buf = [0; 16];
+
+        let mut refused = streams.pop().unwrap();
+        let err = within(refused.read(&mut buf)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        // the ones waiting to be accepted are fine
+        assert!(
+            time::timeout(Duration::from_millis(200), streams[0].read(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_protocol_violations() {
+        let (_mux, mut driver) = Mux::new(Mode::Client);
+        let mut short = frame(Type::Data, 0, 2, b"abc").to_vec();
+        short.truncate(HEADER_SIZE + 2);
+        for bad in [
+            b"hello".to_vec(),
+            frame(Type::Data, 0, 0, b"abc").to_vec(),
+            short,
+            // odd ids are ours
+            frame(Type::WindowUpdate, SYN, 1, b"").to_vec(),
+        ] {
+            let err = driver.inbound(&bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        driver
+            .inbound(&frame(Type::WindowUpdate, SYN, 2, b""))
+            .unwrap();
+        let err = driver
+            .inbound(&frame(Type::WindowUpdate, SYN, 2, b""))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let data = vec![0; INITIAL_WINDOW as usize + 1];
+        let err = driver.inbound(&frame(Type::Data, 0, 2, &data)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn streams_fail_once_the_driver_is_gone() {
+        let (mux, driver) = Mux::new(Mode::Client);
+        let mut stream = mux.open().unwrap();
+        drop(driver);
+
+        assert!(mux.open().is_err());
+        let mut buf = [0; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(stream.write(b"late").await.is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tokio::{
+    sync::{Notify, mpsc},
+    time,
+};
+
+use crate::cluster::ClusterEvents;
+
+/// What a TCP client may tell about itself right after connecting, passed
+/// on to the peer it is introduced to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct NatHello {
+    /// Whether its NAT is symmetric.
+    pub(crate) symmetric: Option<bool>,
+    /// An address its gateway forwards to it on request.
+    pub(crate) candidate: Option<SocketAddr>,
+}
+
+/// What the broker tells a TCP rendezvous session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SessionEvent {
+    /// Send the address of this peer to the client, with what the peer said
+    /// about itself.
+    Paired { addr: SocketAddr, hello: NatHello },
+    /// The peer introduced earlier left before finishing, the client gives
+    /// up.
+    PeerGone(SocketAddr),
+}
+
+// Who a session was introduced to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Peer {
+    Local(usize),
+    /// A client of another cluster node, its address is in `paired`.
+    Remote {
+        node: SocketAddr,
+    },
+}
+
+/// A TCP rendezvous client known to the server.
+pub(crate) struct TcpRegistration {
+    pub(crate) addr: SocketAddr,
+    pub(crate) registered: time::Instant,
+    /// What the client said about its NAT.
+    pub(crate) hello: NatHello,
+    /// The peer whose address was sent to this client.
+    pub(crate) paired: Option<SocketAddr>,
+    /// Closes the session, e.g. on request of an operator.
+    pub(crate) evict: Arc<Notify>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+    peer: Option<Peer>,
+}
+
+impl TcpRegistration {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        hello: NatHello,
+        evict: Arc<Notify>,
+        events: mpsc::UnboundedSender<SessionEvent>,
+    ) -> Self {
+        TcpRegistration {
+            addr,
+            registered: time::Instant::now(),
+            hello,
+            paired: None,
+            evict,
+            events,
+            peer: None,
+        }
+    }
+}
+
+/// Pairs TCP sessions as they register: a new session is matched with the
+/// longest waiting one and both are woken through their event channel, so
+/// nobody polls the registry.
+pub(crate) struct PairingBroker {
+    pub(crate) sessions: HashMap<usize, TcpRegistration>,
+    // unpaired sessions in registration order, entries of sessions that left
+    // meanwhile are skipped when they reach the front
+    waiting: VecDeque<usize>,
+    cluster: Arc<ClusterEvents>,
+}
+
+impl PairingBroker {
+    pub(crate) fn new(cluster: Arc<ClusterEvents>) -> Self {
+        PairingBroker {
+            sessions: HashMap::new(),
+            waiting: VecDeque::new(),
+            cluster,
+        }
+    }
+
+    pub(crate) fn register(&mut self, id: usize, registration: TcpRegistration) {
+        self.sessions.insert(id, registration);
+        self.pair(id);
+    }
+
+    /// Remove session `id`. Its peer learns that it is gone and is paired
+    /// again when `notify_peer` is set, i.e. the session ended without
+    /// finishing the traversal.
+    pub(crate) fn unregister(&mut self, id: usize, notify_peer: bool) {
+        let Some(registration) = self.sessions.remove(&id) else {
+            return;
+        };
+        if !notify_peer {
+            return;
+        }
+        if let Some(Peer::Remote { node }) = registration.peer
+            && let Some(paired) = registration.paired
+        {
+            self.cluster.gone(node, registration.addr, paired);
+        }
+        if let Some(Peer::Local(peer)) = registration.peer
+            && let Some(peer_registration) = self.sessions.get_mut(&peer)
+            && peer_registration.peer == Some(Peer::Local(id))
+        {
+            peer_registration.peer = None;
+            peer_registration.paired = None;
+            let _ = peer_registration
+                .events
+                .send(SessionEvent::PeerGone(registration.addr));
+        }
+    }
+
+    // Introduce `id` and the longest waiting session to each other, or queue
+    // `id` when nobody waits.
+    fn pair(&mut self, id: usize) {
+        while let Some(other) = self.waiting.pop_front() {
+            let Some((other_addr, other_hello)) = self
+                .sessions
+                .get(&other)
+                .filter(|registration| registration.peer.is_none())
+                .map(|registration| (registration.addr, registration.hello))
+            else {
+                continue;
+            };
+            let (addr, hello) = {
+                let registration = &self.sessions[&id];
+                (registration.addr, registration.hello)
+            };
+            self.introduce(id, Peer::Local(other), other_addr, other_hello);
+            self.introduce(other, Peer::Local(id), addr, hello);
+            return;
+        }
+        self.waiting.push_back(id);
+        // nobody here, maybe on another node
+        self.cluster.changed();
+    }
+
+    /// Addresses of the sessions waiting for a peer, longest waiting first.
+    pub(crate) fn waiting(&self) -> Vec<SocketAddr> {
+        self.waiting
+            .iter()
+            .filter_map(|id| self.sessions.get(id))
+            .filter(|registration| registration.peer.is_none())
+            .map(|registration| registration.addr)
+            .collect()
+    }
+
+    /// Introduce the waiting session of `local` to `remote`, a client of
+    /// cluster node `node`. Returns false when `local` is not waiting.
+    pub(crate) fn pair_remote(
+        &mut self,
+        local: SocketAddr,
+        remote: SocketAddr,
+        node: SocketAddr,
+    ) -> bool {
+        let Some(id) = self.waiting.iter().copied().find(|id| {
+            self.sessions
+                .get(id)
+                .is_some_and(|r| r.addr == local && r.peer.is_none())
+        }) else {
+            return false;
+        };
+        // it may wait again later, its entry must not come back to life
+        self.waiting.retain(|waiting| *waiting != id);
+        // gossip carries addresses only, the remote client's NAT is unknown
+        self.introduce(id, Peer::Remote { node }, remote, NatHello::default());
+        true
+    }
+
+    /// The remote peer `remote` of `local` left or was taken by another
+    /// session, `local` gives up.
+    pub(crate) fn remote_gone(&mut self, local: SocketAddr, remote: SocketAddr) {
+        let Some(registration) = self.sessions.values_mut().find(|r| {
+            r.addr == local
+                && matches!(r.peer, Some(Peer::Remote { .. }))
+                && r.paired == Some(remote)
+        }) else {
+            return;
+        };
+        registration.peer = None;
+        registration.paired = None;
+        let _ = registration.events.send(SessionEvent::PeerGone(remote));
+    }
+
+    fn introduce(&mut self, id: usize, peer: Peer, peer_addr: SocketAddr, peer_hello: NatHello) {
+        let registration = self.sessions.get_mut(&id).unwrap();
+        registration.peer = Some(peer);
+        registration.paired = Some(peer_addr);
+        let _ = registration.events.send(SessionEvent::Paired {
+            addr: peer_addr,
+            hello: peer_hello,
+        });
+    }
+}
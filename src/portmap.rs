@@ -0,0 +1,723 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time};
+use tracing::{info, warn};
+
+use crate::{http, lifetime::Protocol};
+
+/// Port NAT-PMP and PCP servers listen on.
+pub const PCP_PORT: u16 = 5351;
+/// Port of SSDP, the discovery of UPnP devices.
+pub const SSDP_PORT: u16 = 1900;
+const SSDP_MULTICAST: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+// NAT-PMP and PCP retransmit after 250ms, doubling the wait each time.
+const FIRST_RETRY: Duration = Duration::from_millis(250);
+const REQUEST_TRIES: u32 = 3;
+const SSDP_WAIT: Duration = Duration::from_secs(2);
+const IGD: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+// PCP responses set the high bit of the opcode.
+const RESPONSE: u8 = 0x80;
+// Common header of every PCP answer, error answers may end after it.
+const PCP_HEADER_SIZE: usize = 24;
+const PCP_MAP_SIZE: usize = 60;
+const DESCRIPTION: &str = "nat_traversal";
+
+/// How a port mapping is requested from the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingProtocol {
+    /// Port Control Protocol, RFC 6887.
+    Pcp,
+    /// NAT Port Mapping Protocol, RFC 6886, the predecessor of PCP.
+    NatPmp,
+    /// UPnP Internet Gateway Device, WANIPConnection or WANPPPConnection.
+    Upnp,
+}
+
+impl MappingProtocol {
+    pub const ALL: [MappingProtocol; 3] = [
+        MappingProtocol::Pcp,
+        MappingProtocol::NatPmp,
+        MappingProtocol::Upnp,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MappingProtocol::Pcp => "pcp",
+            MappingProtocol::NatPmp => "nat-pmp",
+            MappingProtocol::Upnp => "upnp",
+        }
+    }
+}
+
+impl fmt::Display for MappingProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MappingProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MappingProtocol::ALL
+            .into_iter()
+            .find(|protocol| protocol.as_str() == s)
+            .ok_or_else(|| format!("unknown port mapping protocol: {}", s))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PortMapConfig {
+    /// The gateway to ask, the default route's when `None`.
+    pub gateway: Option<Ipv4Addr>,
+    /// Requested lifetime of the mapping, gateways may grant less.
+    pub lifetime: Duration,
+    /// Tried in order until one maps the port.
+    pub protocols: Vec<MappingProtocol>,
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        PortMapConfig {
+            gateway: None,
+            lifetime: Duration::from_secs(3600),
+            protocols: MappingProtocol::ALL.to_vec(),
+        }
+    }
+}
+
+// What removing or renewing a mapping needs besides the ports.
+#[derive(Debug, Clone)]
+enum Control {
+    Pcp {
+        nonce: [u8; 12],
+        client: Ipv4Addr,
+    },
+    NatPmp,
+    Upnp {
+        control: SocketAddr,
+        path: String,
+        service: String,
+        client: Ipv4Addr,
+    },
+}
+
+/// A port the gateway forwards to one of our sockets.
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    pub transport: Protocol,
+    pub gateway: Ipv4Addr,
+    pub internal_port: u16,
+    /// Where peers reach the socket from outside.
+    pub external: SocketAddr,
+    /// Granted lifetime, renew before it runs out.
+    pub lifetime: Duration,
+    control: Control,
+}
+
+/// Ask the gateway to forward a port to `internal_port` of this host, trying
+/// the protocols of `config` in turn.
+pub async fn map_port(
+    transport: Protocol,
+    internal_port: u16,
+    config: &PortMapConfig,
+) -> io::Result<PortMapping> {
+    let gateway = match config.gateway {
+        Some(gateway) => gateway,
+        None => default_gateway()?,
+    };
+    let client = local_ip(gateway).await?;
+    let mut failures = Vec::new();
+    for protocol in &config.protocols {
+        let mapped = match protocol {
+            MappingProtocol::Pcp => {
+                pcp_map(gateway, client, transport, internal_port, config.lifetime).await
+            }
+            MappingProtocol::NatPmp => {
+                nat_pmp_map(gateway, transport, internal_port, config.lifetime).await
+            }
+            MappingProtocol::Upnp => {
+                upnp_map(gateway, client, transport, internal_port, config.lifetime).await
+            }
+        };
+        match mapped {
+            Ok(mapping) => {
+                info!(
+                    "Gateway {} maps {} {} to port {} via {}",
+                    gateway, transport, mapping.external, internal_port, protocol
+                );
+                if is_private(mapping.external.ip()) {
+                    // the gateway sits behind another NAT, the mapping only
+                    // helps peers on the same network
+                    warn!("Mapped address {} is not public", mapping.external);
+                }
+                return Ok(mapping);
+            }
+            Err(err) => {
+                info!("Port mapping via {} failed: {}", protocol, err);
+                failures.push(format!("{}: {}", protocol, err));
+            }
+        }
+    }
+    Err(io::Error::other(format!(
+        "gateway {} mapped no port ({})",
+        gateway,
+        failures.join(", ")
+    )))
+}
+
+impl PortMapping {
+    /// Request the mapping again for the lifetime granted last, before it
+    /// runs out.
+    pub async fn renew(&mut self) -> io::Result<()> {
+        let lifetime = self.lifetime;
+        let renewed = match &self.control {
+            Control::Pcp { nonce, client } => {
+                pcp_request(
+                    self.gateway,
+                    *client,
+                    *nonce,
+                    self.transport,
+                    self.internal_port,
+                    self.external,
+                    lifetime,
+                )
+                .await?
+            }
+            Control::NatPmp => {
+                let (port, lifetime) = nat_pmp_request(
+                    self.gateway,
+                    self.transport,
+                    self.internal_port,
+                    self.external.port(),
+                    lifetime,
+                )
+                .await?;
+                (SocketAddr::new(self.external.ip(), port), lifetime)
+            }
+            Control::Upnp {
+                control,
+                path,
+                service,
+                client,
+            } => {
+                upnp_add(
+                    *control,
+                    path,
+                    service,
+                    *client,
+                    self.transport,
+                    self.internal_port,
+                    self.external.port(),
+                    lifetime,
+                )
+                .await?;
+                (self.external, lifetime)
+            }
+        };
+        (self.external, self.lifetime) = renewed;
+        Ok(())
+    }
+
+    /// Ask the gateway to stop forwarding the port.
+    pub async fn remove(&self) -> io::Result<()> {
+        match &self.control {
+            Control::Pcp { nonce, client } => {
+                pcp_request(
+                    self.gateway,
+                    *client,
+                    *nonce,
+                    self.transport,
+                    self.internal_port,
+                    self.external,
+                    Duration::ZERO,
+                )
+                .await?;
+            }
+            Control::NatPmp => {
+                nat_pmp_request(
+                    self.gateway,
+                    self.transport,
+                    self.internal_port,
+                    0,
+                    Duration::ZERO,
+                )
+                .await?;
+            }
+            Control::Upnp {
+                control,
+                path,
+                service,
+                ..
+            } => {
+                let args = format!(
+                    "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol>",
+                    self.external.port(),
+                    upnp_protocol(self.transport)
+                );
+                soap(*control, path, service, "DeletePortMapping", &args).await?;
+            }
+        }
+        info!(
+            "Removed port mapping {} via {}",
+            self.external, self.protocol
+        );
+        Ok(())
+    }
+}
+
+/// The IPv4 gateway of the default route.
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            (fields.get(1) == Some(&"00000000")).then(|| fields.get(2).copied())?
+        })
+        // the kernel prints the address as a host order integer
+        .find_map(|gateway| u32::from_str_radix(gateway, 16).ok())
+        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no default gateway"))
+}
+
+// Our address on the network of `gateway`, which mappings forward to.
+async fn local_ip(gateway: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((gateway, PCP_PORT)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(io::Error::other(format!("no IPv4 address: {}", ip))),
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64/10 is carrier grade NAT
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified(),
+    }
+}
+
+// Send `request` to the NAT-PMP/PCP port of `gateway` until an answer of at
+// least `min_size` bytes comes back.
+async fn exchange(gateway: Ipv4Addr, request: &[u8], min_size: usize) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((gateway, PCP_PORT)).await?;
+    let mut buf = [0; 1100];
+    let mut wait = FIRST_RETRY;
+    for _ in 0..REQUEST_TRIES {
+        socket.send(request).await?;
+        let deadline = time::Instant::now() + wait;
+        loop {
+            match time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(Ok(len)) if len >= min_size => return Ok(buf[..len].to_vec()),
+                // e.g. a NAT-PMP gateway rejecting a PCP request
+                Ok(Ok(len)) if len >= 4 => return Ok(buf[..len].to_vec()),
+                Ok(Ok(_)) => continue,
+                Ok(Err(err)) => return Err(err),
+                Err(_) => break,
+            }
+        }
+        wait *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "gateway did not answer",
+    ))
+}
+
+fn nat_pmp_opcode(transport: Protocol) -> u8 {
+    match transport {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    }
+}
+
+fn nat_pmp_result(response: &[u8], opcode: u8) -> io::Result<()> {
+    if response[0] != NAT_PMP_VERSION || response[1] != RESPONSE | opcode {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected NAT-PMP answer",
+        ));
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(()),
+        result => Err(io::Error::other(format!("NAT-PMP result code {}", result))),
+    }
+}
+
+async fn nat_pmp_map(
+    gateway: Ipv4Addr,
+    transport: Protocol,
+    internal_port: u16,
+    lifetime: Duration,
+) -> io::Result<PortMapping> {
+    // NAT-PMP maps ports only, the address is asked for separately
+    let response = exchange(gateway, &[NAT_PMP_VERSION, 0], 12).await?;
+    nat_pmp_result(&response, 0)?;
+    if response.len() < 12 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "short NAT-PMP answer",
+        ));
+    }
+    let ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+    let (port, lifetime) =
+        nat_pmp_request(gateway, transport, internal_port, internal_port, lifetime).await?;
+    Ok(PortMapping {
+        protocol: MappingProtocol::NatPmp,
+        transport,
+        gateway,
+        internal_port,
+        external: SocketAddr::new(ip.into(), port),
+        lifetime,
+        control: Control::NatPmp,
+    })
+}
+
+// Map, renew or, with a zero lifetime, delete a NAT-PMP mapping. Returns the
+// external port and the granted lifetime.
+async fn nat_pmp_request(
+    gateway: Ipv4Addr,
+    transport: Protocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<(u16, Duration)> {
+    let opcode = nat_pmp_opcode(transport);
+    let mut request = vec![NAT_PMP_VERSION, opcode, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    let response = exchange(gateway, &request, 16).await?;
+    nat_pmp_result(&response, opcode)?;
+    if response.len() < 16 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "short NAT-PMP answer",
+        ));
+    }
+    let port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((port, Duration::from_secs(lifetime.into())))
+}
+
+async fn pcp_map(
+    gateway: Ipv4Addr,
+    client: Ipv4Addr,
+    transport: Protocol,
+    internal_port: u16,
+    lifetime: Duration,
+) -> io::Result<PortMapping> {
+    let nonce = rand::random();
+    // suggest the internal port and no address
+    let suggested = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), internal_port);
+    let (external, lifetime) = pcp_request(
+        gateway,
+        client,
+        nonce,
+        transport,
+        internal_port,
+        suggested,
+        lifetime,
+    )
+    .await?;
+    Ok(PortMapping {
+        protocol: MappingProtocol::Pcp,
+        transport,
+        gateway,
+        internal_port,
+        external,
+        lifetime,
+        control: Control::Pcp { nonce, client },
+    })
+}
+
+fn pcp_ip(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// Send a PCP MAP request, a zero lifetime deletes the mapping of `nonce`.
+// Returns the assigned address and the granted lifetime.
+async fn pcp_request(
+    gateway: Ipv4Addr,
+    client: Ipv4Addr,
+    nonce: [u8; 12],
+    transport: Protocol,
+    internal_port: u16,
+    suggested: SocketAddr,
+    lifetime: Duration,
+) -> io::Result<(SocketAddr, Duration)> {
+    let mut request = Vec::with_capacity(PCP_MAP_SIZE);
+    request.extend_from_slice(&[PCP_VERSION, PCP_MAP, 0, 0]);
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request.extend_from_slice(&pcp_ip(client.into()));
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[pcp_protocol(transport), 0, 0, 0]);
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&suggested.port().to_be_bytes());
+    request.extend_from_slice(&pcp_ip(suggested.ip()));
+
+    let response = exchange(gateway, &request, PCP_MAP_SIZE).await?;
+    if response[0] != PCP_VERSION {
+        return Err(io::Error::other("gateway does not speak PCP"));
+    }
+    if response[1] != RESPONSE | PCP_MAP || response.len() < PCP_HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected PCP answer",
+        ));
+    }
+    if response[3] != 0 {
+        return Err(io::Error::other(format!("PCP result code {}", response[3])));
+    }
+    if response.len() < PCP_MAP_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated PCP answer",
+        ));
+    }
+    if response[24..36] != nonce {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "PCP answer for another mapping",
+        ));
+    }
+    let lifetime = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+    let port = u16::from_be_bytes([response[42], response[43]]);
+    let ip: [u8; 16] = response[44..60].try_into().unwrap();
+    let ip = Ipv6Addr::from(ip).to_canonical();
+    Ok((
+        SocketAddr::new(ip, port),
+        Duration::from_secs(lifetime.into()),
+    ))
+}
+
+fn pcp_protocol(transport: Protocol) -> u8 {
+    match transport {
+        Protocol::Tcp => 6,
+        Protocol::Udp => 17,
+    }
+}
+
+fn upnp_protocol(transport: Protocol) -> &'static str {
+    match transport {
+        Protocol::Tcp => "TCP",
+        Protocol::Udp => "UDP",
+    }
+}
+
+async fn upnp_map(
+    gateway: Ipv4Addr,
+    client: Ipv4Addr,
+    transport: Protocol,
+    internal_port: u16,
+    lifetime: Duration,
+) -> io::Result<PortMapping> {
+    let location = ssdp_search(gateway).await?;
+    let (control, path, service) = upnp_service(&location).await?;
+    upnp_add(
+        control,
+        &path,
+        &service,
+        client,
+        transport,
+        internal_port,
+        internal_port,
+        lifetime,
+    )
+    .await?;
+    let answer = soap(control, &path, &service, "GetExternalIPAddress", "").await?;
+    let ip = xml_element(&answer, "NewExternalIPAddress")
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no external address"))?;
+    Ok(PortMapping {
+        protocol: MappingProtocol::Upnp,
+        transport,
+        gateway,
+        internal_port,
+        external: SocketAddr::new(ip, internal_port),
+        lifetime,
+        control: Control::Upnp {
+            control,
+            path,
+            service,
+            client,
+        },
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upnp_add(
+    control: SocketAddr,
+    path: &str,
+    service: &str,
+    client: Ipv4Addr,
+    transport: Protocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<()> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol><NewInternalPort>{}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+        external_port,
+        upnp_protocol(transport),
+        internal_port,
+        client,
+        DESCRIPTION,
+        lifetime.as_secs()
+    );
+    soap(control, path, service, "AddPortMapping", &args).await?;
+    Ok(())
+}
+
+// Find the gateway's device description. The search goes to the gateway
+// directly as well as to the SSDP group, some gateways answer only one.
+async fn ssdp_search(gateway: Ipv4Addr) -> io::Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+        SSDP_MULTICAST, SSDP_PORT, IGD
+    );
+    socket
+        .send_to(search.as_bytes(), (gateway, SSDP_PORT))
+        .await?;
+    if let Err(err) = socket
+        .send_to(search.as_bytes(), (SSDP_MULTICAST, SSDP_PORT))
+        .await
+    {
+        info!("Failed to send SSDP search to the group: {}", err);
+    }
+    let mut buf = [0; 2048];
+    let deadline = time::Instant::now() + SSDP_WAIT;
+    loop {
+        let Ok(res) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await else {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no UPnP gateway answered",
+            ));
+        };
+        let (len, from) = res?;
+        // other devices answer the group too
+        if from.ip() != IpAddr::V4(gateway) {
+            continue;
+        }
+        let answer = String::from_utf8_lossy(&buf[..len]);
+        if let Some(location) = answer.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        }) {
+            return Ok(location);
+        }
+    }
+}
+
+// Split an `http://host:port/path` URL.
+fn parse_url(url: &str) -> io::Result<(SocketAddr, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("bad URL: {}", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let addr = match host.parse() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(host.parse().map_err(|_| invalid())?, 80),
+    };
+    Ok((addr, path.to_string()))
+}
+
+// The control endpoint and type of the gateway's WAN connection service.
+async fn upnp_service(location: &str) -> io::Result<(SocketAddr, String, String)> {
+    let (addr, path) = parse_url(location)?;
+    let (status, description) = http::request(addr, "GET", &path, &[], "").await?;
+    if status != 200 {
+        return Err(io::Error::other(format!(
+            "device description status {}",
+            status
+        )));
+    }
+    let mut rest = description.as_str();
+    while let Some(service) = xml_element(rest, "serviceType") {
+        let end = service.as_ptr() as usize - rest.as_ptr() as usize + service.len();
+        rest = &rest[end..];
+        if !service.contains(":WANIPConnection:") && !service.contains(":WANPPPConnection:") {
+            continue;
+        }
+        let control = xml_element(rest, "controlURL")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no control URL"))?;
+        let control = if control.starts_with("http://") {
+            parse_url(control)?
+        } else {
+            (addr, control.to_string())
+        };
+        return Ok((control.0, control.1, service.to_string()));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "gateway has no WAN connection service",
+    ))
+}
+
+// Call `action` of the WAN connection service, returning the answer.
+async fn soap(
+    control: SocketAddr,
+    path: &str,
+    service: &str,
+    action: &str,
+    args: &str,
+) -> io::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>\r\n"
+    );
+    let headers = [
+        "Content-Type: text/xml; charset=\"utf-8\"".to_string(),
+        format!("SOAPAction: \"{}#{}\"", service, action),
+    ];
+    let (status, answer) = http::request(control, "POST", path, &headers, &body).await?;
+    if status != 200 {
+        let code = xml_element(&answer, "errorCode").unwrap_or("unknown");
+        return Err(io::Error::other(format!(
+            "{} failed with status {}, error {}",
+            action, status, code
+        )));
+    }
+    Ok(answer)
+}
+
+/// Text of the first element named `name`, whatever its namespace prefix.
+pub fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let open = rest.find('<')?;
+        rest = &rest[open + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        let local = tag.rsplit(':').next().unwrap_or(tag);
+        if local == name {
+            let text = &rest[end + 1..];
+            return Some(&text[..text.find("</")?]);
+        }
+    }
+}
@@ -0,0 +1,609 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac_sha256::HMAC;
+use socket2::Type;
+use tokio::{
+    net::UdpSocket,
+    time::{self, Instant},
+};
+use tracing::info;
+
+use crate::{
+    limits::{LimitConfig, RateLimiter},
+    metrics::Metrics,
+    server_socket,
+    store::{MemoryStore, RegistrationStore, RelayRecord},
+    traversal::{canonical, padded},
+};
+
+// A peer allocates by sending `join <room> <token>` with the room and token
+// of a `RelayTicket`, padded like rendezvous requests since the relay never
+// answers with more than it got; once two peers joined the same room every
+// other datagram from one is forwarded to the other.
+pub const JOIN_PREFIX: &str = "join ";
+pub const JOINED_PREFIX: &str = "joined ";
+// A UDP rendezvous server sends both peers of a pairing their ticket ahead
+// of the introduction, `relay <relay address> <room> <token>`.
+pub const TICKET_PREFIX: &str = "relay ";
+
+const ALLOCATION_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a ticket admits joins after it was issued.
+const TICKET_LIFETIME: Duration = Duration::from_secs(300);
+// MAC bytes kept in room names and tokens, so a ticket fits a UDP reply.
+const ROOM_BYTES: usize = 8;
+const MAC_BYTES: usize = 16;
+// Joins sent before a peer gives up, each answered within `JOIN_TIMEOUT`.
+const JOIN_ATTEMPTS: usize = 3;
+const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+// Sent through the relay until the peer answers, and once more when it did,
+// so a peer that joined first learns that the path is up as well. Both peers
+// give up punching at about the same time, the one joining later gets
+// `CONFIRM_ATTEMPTS` times `JOIN_TIMEOUT` to follow.
+const RELAYED: &[u8] = b"relayed";
+const CONFIRM_ATTEMPTS: usize = 5;
+
+/// Admission to a relay room, handed to both peers of a pairing by a
+/// rendezvous server sharing the relay's secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayTicket {
+    pub relay: SocketAddr,
+    pub room: String,
+    /// Expiry and MAC of the room, checked by the relay.
+    pub token: String,
+}
+
+impl fmt::Display for RelayTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.relay, self.room, self.token)
+    }
+}
+
+impl FromStr for RelayTicket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (Some(relay), Some(room), Some(token), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("{}: expected relay address, room and token", s));
+        };
+        Ok(RelayTicket {
+            relay: relay.parse().map_err(|err| format!("{}: {}", s, err))?,
+            room: room.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// The ticket in a datagram from a UDP rendezvous server, if it is one.
+pub fn parse_ticket(msg: &[u8]) -> Option<RelayTicket> {
+    std::str::from_utf8(msg)
+        .ok()?
+        .strip_prefix(TICKET_PREFIX)?
+        .parse()
+        .ok()
+}
+
+/// Issues tickets for the relay at `relay` sharing `secret`.
+#[derive(Clone)]
+pub struct RelayIssuer {
+    relay: SocketAddr,
+    secret: Arc<[u8]>,
+}
+
+impl RelayIssuer {
+    pub fn new(relay: SocketAddr, secret: impl AsRef<[u8]>) -> Self {
+        RelayIssuer {
+            relay,
+            secret: Arc::from(secret.as_ref()),
+        }
+    }
+
+    /// The ticket of one end of the pairing of `a` and `b`, both ends get
+    /// the same room whichever is given first.
+    pub fn ticket(&self, a: SocketAddr, b: SocketAddr) -> RelayTicket {
+        let (a, b) = (canonical(a), canonical(b));
+        let (low, high) = if a < b { (a, b) } else { (b, a) };
+        let room = hex(&HMAC::mac(format!("room {} {}", low, high), &self.secret)[..ROOM_BYTES]);
+        let expires = unix_secs() + TICKET_LIFETIME.as_secs();
+        let token = format!(
+            "{}.{}",
+            expires,
+            hex(&token_mac(&self.secret, &room, expires))
+        );
+        RelayTicket {
+            relay: self.relay,
+            room,
+            token,
+        }
+    }
+}
+
+fn token_mac(secret: &[u8], room: &str, expires: u64) -> [u8; MAC_BYTES] {
+    let mac = HMAC::mac(format!("join {} {}", room, expires), secret);
+    mac[..MAC_BYTES].try_into().unwrap()
+}
+
+// Whether `token` admits joins to `room` right now.
+fn is_valid(secret: &[u8], room: &str, token: &str) -> bool {
+    let Some((expires, mac)) = token.split_once('.') else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<u64>() else {
+        return false;
+    };
+    if expires < unix_secs() {
+        return false;
+    }
+    // compared in constant time, tokens could be guessed byte by byte
+    // otherwise
+    same_mac(&hex(&token_mac(secret, room, expires)), mac)
+}
+
+// Whether `mac` is `expected`, in time independent of where they differ.
+pub(crate) fn same_mac(expected: &str, mac: &str) -> bool {
+    expected.len() == mac.len()
+        && expected
+            .bytes()
+            .zip(mac.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Join the room of `ticket` from `sock`. Once the peer joined as well,
+/// what is sent to `ticket.relay` reaches it.
+pub async fn join(sock: &UdpSocket, ticket: &RelayTicket) -> io::Result<()> {
+    let join = padded(format!("{}{} {}", JOIN_PREFIX, ticket.room, ticket.token).as_bytes());
+    let joined = format!("{}{}", JOINED_PREFIX, ticket.room);
+    let mut buf = [0; 1024];
+    for _ in 0..JOIN_ATTEMPTS {
+        sock.send_to(&join, ticket.relay).await?;
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        while let Ok(received) = time::timeout_at(deadline, sock.recv_from(&mut buf)).await {
+            let (len, from) = received?;
+            if canonical(from) == canonical(ticket.relay) && buf[..len] == *joined.as_bytes() {
+                return Ok(());
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("relay {} did not admit us", ticket.relay),
+    ))
+}
+
+/// Join the room of `ticket` from `sock` and wait until the peer, joining
+/// as well, answers through the relay. `sock` is connected to the relay
+/// afterwards, what is sent on it reaches the peer.
+pub async fn connect(sock: &UdpSocket, ticket: &RelayTicket) -> io::Result<()> {
+    join(sock, ticket).await?;
+    sock.connect(ticket.relay).await?;
+    let joined = format!("{}{}", JOINED_PREFIX, ticket.room);
+    let mut buf = [0; 1024];
+    for _ in 0..CONFIRM_ATTEMPTS {
+        sock.send(RELAYED).await?;
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        while let Ok(received) = time::timeout_at(deadline, sock.recv_from(&mut buf)).await {
+            let (len, from) = received?;
+            // punches queued before we connected and answers to joins we
+            // repeated are not the peer
+            if canonical(from) == canonical(ticket.relay) && buf[..len] != *joined.as_bytes() {
+                sock.send(RELAYED).await?;
+                return Ok(());
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("peer did not answer through relay {}", ticket.relay),
+    ))
+}
+
+pub(crate) struct Allocation {
+    pub(crate) room: String,
+    pub(crate) last_seen: Instant,
+}
+
+/// Allocations by the address they were joined from, along with the members
+/// of every room, so forwarding finds the other peer without a scan.
+#[derive(Default)]
+pub(crate) struct Allocations {
+    by_addr: HashMap<SocketAddr, Allocation>,
+    rooms: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl Allocations {
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &Allocation)> {
+        self.by_addr.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+
+    // Whether `addr` may join `room`: a room takes two peers, a member may
+    // join again.
+    fn admits(&self, addr: SocketAddr, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .is_none_or(|members| members.len() < 2 || members.contains(&addr))
+    }
+
+    // Allocate `addr` in `allocation.room`, leaving the room it was in.
+    fn insert(&mut self, addr: SocketAddr, allocation: Allocation) {
+        let room = allocation.room.clone();
+        if let Some(old) = self.by_addr.insert(addr, allocation) {
+            leave(&mut self.rooms, addr, &old.room);
+        }
+        self.rooms.entry(room).or_default().push(addr);
+    }
+
+    // The room of `addr` and the other peer in it, if any, counting
+    // `addr` as seen now.
+    fn forward(&mut self, addr: SocketAddr) -> Option<(String, Option<SocketAddr>)> {
+        let allocation = self.by_addr.get_mut(&addr)?;
+        allocation.last_seen = Instant::now();
+        let other = self.rooms[&allocation.room]
+            .iter()
+            .find(|member| **member != addr)
+            .copied();
+        Some((allocation.room.clone(), other))
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&SocketAddr, &Allocation) -> bool) {
+        let rooms = &mut self.rooms;
+        self.by_addr.retain(|addr, a| {
+            let kept = keep(addr, a);
+            if !kept {
+                leave(rooms, *addr, &a.room);
+            }
+            kept
+        });
+    }
+}
+
+fn leave(rooms: &mut HashMap<String, Vec<SocketAddr>>, addr: SocketAddr, room: &str) {
+    if let Some(members) = rooms.get_mut(room) {
+        members.retain(|member| *member != addr);
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+    }
+}
+
+/// Allocations of one relay server, shared with its admin API.
+pub(crate) struct RelayState {
+    pub(crate) allocations: Mutex<Allocations>,
+    store: Box<dyn RegistrationStore>,
+    metrics: Arc<Metrics>,
+}
+
+impl RelayState {
+    /// Drop every allocation of `room`, returns how many there were.
+    pub(crate) fn clear_room(&self, room: &str) -> usize {
+        let mut allocations = self.allocations.lock().unwrap();
+        let before = allocations.len();
+        allocations.retain(|addr, a| {
+            let keep = a.room != room;
+            if !keep {
+                self.store.remove_relay(*addr);
+            }
+            keep
+        });
+        let cleared = before - allocations.len();
+        if cleared > 0 {
+            info!(transport = "relay", room, "Relay room cleared");
+        }
+        cleared
+    }
+}
+
+/// A UDP relay owning its allocations, so several can run in one process.
+/// It admits joins with a ticket issued under its secret only. Clones are
+/// handles to the same relay.
+#[derive(Clone)]
+pub struct RelayServer {
+    pub(crate) state: Arc<RelayState>,
+    listen: Option<SocketAddr>,
+    secret: Option<Arc<[u8]>>,
+    limiter: Arc<RateLimiter>,
+}
+
+/// Configures a `RelayServer`. Nothing is served until a listen address is
+/// given, and no join is admitted without a secret.
+pub struct RelayServerBuilder {
+    listen: Option<SocketAddr>,
+    secret: Option<Arc<[u8]>>,
+    limits: LimitConfig,
+    store: Option<Box<dyn RegistrationStore>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl RelayServer {
+    pub fn builder() -> RelayServerBuilder {
+        RelayServerBuilder {
+            listen: None,
+            secret: None,
+            limits: LimitConfig::default(),
+            store: None,
+            metrics: None,
+        }
+    }
+
+    /// What this relay counts, see `metrics_server`.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.state.metrics
+    }
+
+    // Whether a join of `room` with `token` is allowed.
+    fn admits(&self, room: &str, token: &str) -> bool {
+        self.secret
+            .as_ref()
+            .is_some_and(|secret| is_valid(secret, room, token))
+    }
+
+    /// Forward between the peers of each room forever. Panics when the
+    /// listen address cannot be bound.
+    pub async fn run(self) {
+        let Some(listen_addr) = self.listen else {
+            return;
+        };
+        let socket = server_socket(listen_addr, Type::DGRAM, false);
+        let sock = UdpSocket::from_std(socket.into()).unwrap();
+        info!("Relay listening on: {}", listen_addr);
+        // restored allocations get a full timeout to send again
+        let records = self.state.store.load().relay;
+        if !records.is_empty() {
+            info!("Relay restoring {} allocations", records.len());
+            let mut allocations = self.state.allocations.lock().unwrap();
+            for record in records {
+                allocations.insert(
+                    record.addr,
+                    Allocation {
+                        room: record.room,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let mut interval = tokio::time::interval(ALLOCATION_TIMEOUT / 2);
+        let mut buf = [0; 2048];
+
+        loop {
+            tokio::select! {
+                res = sock.recv_from(&mut buf) => {
+                    let (len, addr) = match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            info!("Relay receive error: {}", err);
+                            continue;
+                        }
+                    };
+                    let msg = &buf[..len];
+                    if let Some(join) = std::str::from_utf8(msg)
+                        .ok()
+                        .and_then(|msg| msg.strip_prefix(JOIN_PREFIX))
+                    {
+                        // only joins are answered, so only they are limited
+                        if let Err(rejection) = self.limiter.admit(addr.ip()) {
+                            self.state.metrics.error(rejection.kind());
+                            continue;
+                        }
+                        let mut args = join.split_whitespace();
+                        let room = args.next().unwrap_or_default();
+                        if !self.admits(room, args.next().unwrap_or_default()) {
+                            info!(transport = "relay", room, peer = %addr, "Relay rejecting join without a valid ticket");
+                            self.state.metrics.error("relay_bad_ticket");
+                            continue;
+                        }
+                        let joined = format!("{}{}", JOINED_PREFIX, room);
+                        if joined.len() > len {
+                            self.state.metrics.error("reply_too_large");
+                            continue;
+                        }
+                        {
+                            let mut allocations = self.state.allocations.lock().unwrap();
+                            if !allocations.admits(addr, room) {
+                                info!(transport = "relay", room, peer = %addr, "Relay room is full, rejecting");
+                                self.state.metrics.error("relay_room_full");
+                                continue;
+                            }
+                            allocations.insert(addr, Allocation { room: room.to_string(), last_seen: Instant::now() });
+                            self.state.store.put_relay(RelayRecord { addr, room: room.to_string() });
+                        }
+                        info!(transport = "relay", room, peer = %addr, "Relay joined room");
+                        let _ = sock.send_to(joined.as_bytes(), addr).await;
+                        continue;
+                    }
+
+                    let Some((room, other)) = self.state.allocations.lock().unwrap().forward(addr) else {
+                        self.state.metrics.error("relay_unallocated");
+                        continue;
+                    };
+                    if let Some(other) = other {
+                        match sock.send_to(msg, other).await {
+                            Ok(sent) => {
+                                self.state.metrics.relay_bytes.fetch_add(sent as u64, Ordering::Relaxed);
+                            }
+                            Err(err) => {
+                                info!(transport = "relay", room, peer = %addr, other = %other, "Relay forward failed: {}", err);
+                                self.state.metrics.error("relay_forward");
+                            }
+                        }
+                    }
+                }
+                _ = interval.tick() => {
+                    self.state.allocations.lock().unwrap().retain(|addr, a| {
+                        let alive = a.last_seen.elapsed() < ALLOCATION_TIMEOUT;
+                        if !alive {
+                            info!(transport = "relay", room = a.room, peer = %addr, "Relay allocation expired");
+                            self.state.store.remove_relay(*addr);
+                        }
+                        alive
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl RelayServerBuilder {
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listen = Some(addr);
+        self
+    }
+
+    /// Secret shared with the rendezvous servers issuing tickets, see
+    /// `RelayIssuer`.
+    pub fn secret(mut self, secret: impl AsRef<[u8]>) -> Self {
+        self.secret = Some(Arc::from(secret.as_ref()));
+        self
+    }
+
+    /// Limits applied to joins.
+    pub fn limits(mut self, limits: LimitConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Where allocations are kept, in memory of this relay by default.
+    pub fn store(mut self, store: Box<dyn RegistrationStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Count into `metrics` instead of counters of this relay alone.
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn build(self) -> RelayServer {
+        let store = self
+            .store
+            .unwrap_or_else(|| Box::new(MemoryStore::default()));
+        RelayServer {
+            state: Arc::new(RelayState {
+                allocations: Mutex::new(Allocations::default()),
+                store,
+                metrics: self.metrics.unwrap_or_default(),
+            }),
+            listen: self.listen,
+            secret: self.secret,
+            limiter: RateLimiter::new(self.limits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn both_ends_share_a_room() {
+        let issuer = RelayIssuer::new(addr("192.0.2.1:8091"), SECRET);
+        let (a, b) = (
+            addr("198.51.100.1:1000"),
+            addr("[::ffff:198.51.100.2]:2000"),
+        );
+        let ticket = issuer.ticket(a, b);
+        assert_eq!(ticket.room, issuer.ticket(b, a).room);
+        assert_ne!(
+            ticket.room,
+            issuer.ticket(a, addr("198.51.100.3:3000")).room
+        );
+        assert!(is_valid(SECRET, &ticket.room, &ticket.token));
+        assert!(!is_valid(b"other", &ticket.room, &ticket.token));
+    }
+
+    #[test]
+    fn tickets_round_trip() {
+        let ticket = RelayIssuer::new(addr("[2001:db8::1]:8091"), SECRET)
+            .ticket(addr("[2001:db8::2]:1000"), addr("[2001:db8::3]:2000"));
+        let datagram = format!("{}{}", TICKET_PREFIX, ticket);
+        assert_eq!(parse_ticket(datagram.as_bytes()), Some(ticket));
+        assert_eq!(parse_ticket(b"relay 192.0.2.1:8091 room"), None);
+    }
+
+    #[test]
+    fn expired_and_tampered_tokens_are_rejected() {
+        let past = unix_secs() - 1;
+        let expired = format!("{}.{}", past, hex(&token_mac(SECRET, "room", past)));
+        assert!(!is_valid(SECRET, "room", &expired));
+
+        let future = unix_secs() + 60;
+        let mac = hex(&token_mac(SECRET, "room", future));
+        assert!(is_valid(SECRET, "room", &format!("{}.{}", future, mac)));
+        assert!(!is_valid(
+            SECRET,
+            "room",
+            &format!("{}.{}", future + 1, mac)
+        ));
+        assert!(!is_valid(
+            SECRET,
+            "room",
+            &format!("{}.{}", future, &mac[1..])
+        ));
+        assert!(!is_valid(SECRET, "room", &mac));
+    }
+
+    fn allocation(room: &str) -> Allocation {
+        Allocation {
+            room: room.to_string(),
+            last_seen: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn rooms_know_their_members() {
+        let (a, b, c) = (
+            addr("198.51.100.1:1000"),
+            addr("198.51.100.2:2000"),
+            addr("198.51.100.3:3000"),
+        );
+        let mut allocations = Allocations::default();
+        allocations.insert(a, allocation("room"));
+        assert_eq!(allocations.forward(a), Some(("room".to_string(), None)));
+        allocations.insert(b, allocation("room"));
+        assert_eq!(allocations.forward(a), Some(("room".to_string(), Some(b))));
+        assert_eq!(allocations.forward(b), Some(("room".to_string(), Some(a))));
+        assert!(!allocations.admits(c, "room"));
+        assert!(allocations.admits(a, "room"));
+        assert_eq!(allocations.forward(c), None);
+
+        // moving to another room makes space in the first
+        allocations.insert(b, allocation("other"));
+        assert_eq!(allocations.forward(a), Some(("room".to_string(), None)));
+        assert!(allocations.admits(c, "room"));
+        allocations.insert(c, allocation("room"));
+        allocations.retain(|addr, _| *addr != a);
+        assert_eq!(allocations.forward(c), Some(("room".to_string(), None)));
+        assert_eq!(allocations.len(), 2);
+    }
+}
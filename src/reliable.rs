@@ -0,0 +1,573 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::time::Instant;
+
+// Every packet starts with this byte, followed by its kind, flags, sequence
+// number, the cumulative ack of the sender and a bitmap of the packets after
+// the ack that arrived already. Keepalives and the datagrams of traversal
+// never start with it.
+const MAGIC: u8 = 0xd7;
+const HEADER_SIZE: usize = 19;
+// Whole packets stay below the MTU of about every path.
+const MAX_PACKET: usize = 1200;
+const MAX_PAYLOAD: usize = MAX_PACKET - HEADER_SIZE;
+/// Largest message a `Link` carries, longer ones are fragmented up to it.
+pub const MAX_MESSAGE: usize = 64 * 1024;
+// Packets not acked yet at most, and how far ahead of the next expected one
+// the receiver keeps packets.
+const WINDOW: u32 = 256;
+// Packets in flight the congestion window starts with.
+const INITIAL_CWND: usize = 10;
+// The congestion window never shrinks below this.
+const MIN_CWND: usize = 2;
+// Packets after the cumulative ack a selective ack covers.
+const SACK_BITS: usize = 64;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(10);
+// Retransmissions of one packet before the peer counts as gone.
+const MAX_RETRIES: u32 = 10;
+// Later packets acked selectively before a missing one is sent again without
+// waiting for its timeout.
+const FAST_RETRANSMIT: usize = 3;
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+
+// flag of fragments followed by more of the same message
+const MORE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    flags: u8,
+    seq: u32,
+    ack: u32,
+    sack: u64,
+}
+
+impl Header {
+    fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        let (mut header, payload) = packet.split_at_checked(HEADER_SIZE)?;
+        if header.get_u8() != MAGIC {
+            return None;
+        }
+        let header = Header {
+            kind: header.get_u8(),
+            flags: header.get_u8(),
+            seq: header.get_u32(),
+            ack: header.get_u32(),
+            sack: header.get_u64(),
+        };
+        match header.kind {
+            DATA => Some((header, payload)),
+            ACK if payload.is_empty() => Some((header, payload)),
+            _ => None,
+        }
+    }
+
+    fn encode(&self, payload: &[u8]) -> Bytes {
+        let mut packet = BytesMut::with_capacity(HEADER_SIZE + payload.len());
+        packet.put_u8(MAGIC);
+        packet.put_u8(self.kind);
+        packet.put_u8(self.flags);
+        packet.put_u32(self.seq);
+        packet.put_u32(self.ack);
+        packet.put_u64(self.sack);
+        packet.put_slice(payload);
+        packet.freeze()
+    }
+}
+
+/// Whether `packet` belongs to a `Link` rather than other traffic of the
+/// path, e.g. keepalives.
+pub fn is_packet(packet: &[u8]) -> bool {
+    Header::parse(packet).is_some()
+}
+
+#[derive(Debug)]
+struct Sent {
+    seq: u32,
+    flags: u8,
+    payload: Bytes,
+    first_sent: Instant,
+    sent_at: Instant,
+    retries: u32,
+    // acked selectively, only the cumulative ack releases it
+    sacked: bool,
+    // sent again already because later packets arrived
+    fast: bool,
+    // waits to be sent again, oldest first, and is not in flight
+    lost: bool,
+}
+
+/// Reliable, ordered delivery of messages over datagrams that may be lost,
+/// duplicated or reordered. The link does no I/O itself: the transport loop
+/// sends what `transmit` yields, passes every packet `is_packet` recognizes
+/// to `receive` and calls `on_timeout` at the `deadline`.
+///
+/// Lost packets are sent again after a timeout derived from the round trip
+/// time, or as soon as three later packets were acked selectively. Packets
+/// in flight are limited by a congestion window like TCP Reno's: it grows
+/// with every ack, halves on a loss and starts over after a timeout.
+#[derive(Debug)]
+pub struct Link {
+    next_seq: u32,
+    // fragments waiting for room in the window
+    queued: VecDeque<(u8, Bytes)>,
+    // in flight, consecutive sequence numbers from the oldest
+    unacked: VecDeque<Sent>,
+    cwnd: usize,
+    ssthresh: usize,
+    // acks counted towards growing `cwnd` past `ssthresh`
+    grown: usize,
+    // the window was cut for a loss, until the packet sent next is acked
+    recovery: Option<u32>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    // sequence number of the next packet to deliver
+    expected: u32,
+    // received ahead of `expected`
+    buffered: HashMap<u32, (u8, Bytes)>,
+    // fragments of a message not complete yet
+    partial: BytesMut,
+    delivered: VecDeque<Bytes>,
+    ack_due: bool,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Link {
+            next_seq: 0,
+            queued: VecDeque::new(),
+            unacked: VecDeque::new(),
+            cwnd: INITIAL_CWND,
+            ssthresh: WINDOW as usize,
+            grown: 0,
+            recovery: None,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            expected: 0,
+            buffered: HashMap::new(),
+            partial: BytesMut::new(),
+            delivered: VecDeque::new(),
+            ack_due: false,
+        }
+    }
+
+    /// Queue `msg` for the peer.
+    ///
+    /// # Panics
+    ///
+    /// If `msg` is longer than `MAX_MESSAGE`.
+    pub fn send(&mut self, msg: &[u8]) {
+        assert!(msg.len() <= MAX_MESSAGE, "message of {} bytes", msg.len());
+        if msg.is_empty() {
+            self.queued.push_back((0, Bytes::new()));
+            return;
+        }
+        let mut chunks = msg.chunks(MAX_PAYLOAD).peekable();
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_some() { MORE } else { 0 };
+            self.queued
+                .push_back((flags, Bytes::copy_from_slice(chunk)));
+        }
+    }
+
+    /// Whether everything sent so far went out at least once. Until then
+    /// further messages are better left in the transport's own queue.
+    pub fn ready(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    /// The next packet to send to the peer, `None` once nothing is due.
+    pub fn transmit(&mut self, now: Instant) -> Option<Bytes> {
+        let room = self.in_flight() < self.cwnd;
+        if room && let Some(sent) = self.unacked.iter_mut().find(|sent| sent.lost) {
+            sent.lost = false;
+            sent.sent_at = now;
+            sent.retries += 1;
+            let (seq, flags, payload) = (sent.seq, sent.flags, sent.payload.clone());
+            return Some(self.encode(DATA, flags, seq, &payload));
+        }
+        if room
+            && self.unacked.len() < WINDOW as usize
+            && let Some((flags, payload)) = self.queued.pop_front()
+        {
+            let seq = self.next_seq;
+            self.next_seq = seq.wrapping_add(1);
+            let packet = self.encode(DATA, flags, seq, &payload);
+            self.unacked.push_back(Sent {
+                seq,
+                flags,
+                payload,
+                first_sent: now,
+                sent_at: now,
+                retries: 0,
+                sacked: false,
+                fast: false,
+                lost: false,
+            });
+            return Some(packet);
+        }
+        if self.ack_due {
+            return Some(self.encode(ACK, 0, 0, &[]));
+        }
+        None
+    }
+
+    /// When the oldest packet in flight times out, `None` with nothing in
+    /// flight.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.unacked
+            .iter()
+            .filter(|sent| !sent.sacked && !sent.lost)
+            .map(|sent| sent.sent_at + self.rto)
+            .min()
+    }
+
+    /// Once the `deadline` passed, take everything in flight for lost and
+    /// send it again as the window allows. Fails with how long the peer
+    /// acked nothing once a packet was sent too often.
+    pub fn on_timeout(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.deadline().is_none_or(|deadline| deadline > now) {
+            return Ok(());
+        }
+        let mut in_flight = 0;
+        for sent in &mut self.unacked {
+            if sent.sacked || sent.lost {
+                continue;
+            }
+            if sent.retries >= MAX_RETRIES {
+                return Err(now - sent.first_sent);
+            }
+            in_flight += 1;
+            sent.lost = true;
+        }
+        self.ssthresh = (in_flight / 2).max(MIN_CWND);
+        self.cwnd = MIN_CWND;
+        self.grown = 0;
+        self.recovery = Some(self.next_seq);
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        Ok(())
+    }
+
+    /// Handle a packet from the peer. Errors are protocol violations, after
+    /// which the link is of no use anymore.
+    pub fn receive(&mut self, packet: &[u8], now: Instant) -> io::Result<()> {
+        let (header, payload) = Header::parse(packet)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a link packet"))?;
+        self.on_ack(header.ack, header.sack, now);
+        if header.kind != DATA {
+            return Ok(());
+        }
+        // duplicates are acked again, their ack may have been lost
+        self.ack_due = true;
+        let ahead = header.seq.wrapping_sub(self.expected);
+        if ahead >= WINDOW {
+            return Ok(());
+        }
+        if ahead > 0 {
+            self.buffered
+                .entry(header.seq)
+                .or_insert_with(|| (header.flags, Bytes::copy_from_slice(payload)));
+            return Ok(());
+        }
+        self.deliver(header.flags, payload)?;
+        while let Some((flags, payload)) = self.buffered.remove(&self.expected) {
+            self.deliver(flags, &payload)?;
+        }
+        Ok(())
+    }
+
+    /// The next message from the peer, in the order it was sent.
+    pub fn recv(&mut self) -> Option<Bytes> {
+        self.delivered.pop_front()
+    }
+
+    fn deliver(&mut self, flags: u8, payload: &[u8]) -> io::Result<()> {
+        self.expected = self.expected.wrapping_add(1);
+        if self.partial.len() + payload.len() > MAX_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message exceeds the limit",
+            ));
+        }
+        self.partial.put_slice(payload);
+        if flags & MORE == 0 {
+            self.delivered.push_back(self.partial.split().freeze());
+        }
+        Ok(())
+    }
+
+    fn on_ack(&mut self, ack: u32, sack: u64, now: Instant) {
+        let Some(oldest) = self.unacked.front().map(|sent| sent.seq) else {
+            return;
+        };
+        // stale acks, or ones for packets we never sent
+        let acked = ack.wrapping_sub(oldest) as usize;
+        if acked > self.unacked.len() {
+            return;
+        }
+        // Karn: packets sent again do not tell the round trip time
+        let mut sample = None;
+        let mut arrived = 0;
+        for sent in self.unacked.drain(..acked) {
+            if !sent.sacked {
+                arrived += 1;
+                if sent.retries == 0 {
+                    sample = Some(now - sent.sent_at);
+                }
+            }
+        }
+        for (bit, sent) in self.unacked.iter_mut().skip(1).take(SACK_BITS).enumerate() {
+            if sack & (1 << bit) != 0 && !sent.sacked {
+                sent.sacked = true;
+                sent.lost = false;
+                arrived += 1;
+                if sent.retries == 0 {
+                    sample = Some(now - sent.sent_at);
+                }
+            }
+        }
+        if let Some(sample) = sample {
+            self.update_rto(sample);
+        }
+        if self
+            .recovery
+            .is_some_and(|end| ack.wrapping_sub(end) < u32::MAX / 2)
+        {
+            self.recovery = None;
+        }
+        self.grow(arrived);
+
+        // every gap followed by enough packets that made it is a loss, or
+        // by all there are when nothing more follows (RFC 5827)
+        let threshold = if self.queued.is_empty() {
+            FAST_RETRANSMIT
+                .min(self.unacked.len().saturating_sub(1))
+                .max(1)
+        } else {
+            FAST_RETRANSMIT
+        };
+        let mut later = 0;
+        let mut lost = false;
+        for sent in self.unacked.iter_mut().take(SACK_BITS + 1).rev() {
+            if sent.sacked {
+                later += 1;
+            } else if later >= threshold && !sent.fast && !sent.lost {
+                sent.fast = true;
+                sent.lost = true;
+                lost = true;
+            }
+        }
+        // one cut per window of packets
+        if lost && self.recovery.is_none() {
+            self.ssthresh = (self.cwnd / 2).max(MIN_CWND);
+            self.cwnd = self.ssthresh;
+            self.grown = 0;
+            self.recovery = Some(self.next_seq);
+        }
+    }
+
+    // Slow start below `ssthresh`, one more packet per window above it.
+    fn grow(&mut self, arrived: usize) {
+        for _ in 0..arrived {
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+            } else {
+                self.grown += 1;
+                if self.grown >= self.cwnd {
+                    self.cwnd += 1;
+                    self.grown = 0;
+                }
+            }
+        }
+        self.cwnd = self.cwnd.min(WINDOW as usize);
+    }
+
+    // Packets sent and neither acked nor taken for lost.
+    fn in_flight(&self) -> usize {
+        self.unacked
+            .iter()
+            .filter(|sent| !sent.sacked && !sent.lost)
+            .count()
+    }
+
+    // RFC 6298
+    fn update_rto(&mut self, sample: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = sample / 2;
+                sample
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(sample)) / 4;
+                (srtt * 7 + sample) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // Every packet acks what arrived so far.
+    fn encode(&mut self, kind: u8, flags: u8, seq: u32, payload: &[u8]) -> Bytes {
+        self.ack_due = false;
+        let mut sack = 0;
+        for bit in 0..SACK_BITS {
+            let seq = self.expected.wrapping_add(bit as u32 + 1);
+            if self.buffered.contains_key(&seq) {
+                sack |= 1 << bit;
+            }
+        }
+        Header {
+            kind,
+            flags,
+            seq,
+            ack: self.expected,
+            sack,
+        }
+        .encode(payload)
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Exchange packets between `a` and `b` until both have nothing left in
+    // flight, dropping those `lose` picks and reversing the order of every
+    // batch. The clock jumps to the next timeout whenever nothing moves.
+    fn run(a: &mut Link, b: &mut Link, mut lose: impl FnMut() -> bool) {
+        let mut now = Instant::now();
+        for _ in 0..100_000 {
+            let moved = deliver(a, b, now, &mut lose) | deliver(b, a, now, &mut lose);
+            if moved {
+                continue;
+            }
+            let Some(deadline) = a.deadline().into_iter().chain(b.deadline()).min() else {
+                return;
+            };
+            now = deadline;
+            a.on_timeout(now).unwrap();
+            b.on_timeout(now).unwrap();
+        }
+        panic!("links never settled");
+    }
+
+    // One batch of `run`, whether anything arrived.
+    fn deliver(
+        from: &mut Link,
+        to: &mut Link,
+        now: Instant,
+        lose: &mut impl FnMut() -> bool,
+    ) -> bool {
+        let mut batch = Vec::new();
+        while let Some(packet) = from.transmit(now) {
+            if !lose() {
+                batch.push(packet);
+            }
+        }
+        for packet in batch.iter().rev() {
+            to.receive(packet, now).unwrap();
+        }
+        !batch.is_empty()
+    }
+
+    fn messages() -> Vec<Vec<u8>> {
+        (0..600u32)
+            .map(|i| vec![i as u8; (i as usize * 37) % (3 * MAX_PAYLOAD)])
+            .collect()
+    }
+
+    fn received(link: &mut Link) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| link.recv().map(|msg| msg.to_vec())).collect()
+    }
+
+    #[test]
+    fn delivers_in_order_despite_loss_and_reordering() {
+        let (mut a, mut b) = (Link::new(), Link::new());
+        for msg in messages() {
+            a.send(&msg);
+            b.send(&msg);
+        }
+        // a fifth of the packets, picked by a fixed xorshift sequence
+        let mut state = 0x2545_f491_u32;
+        run(&mut a, &mut b, || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state.is_multiple_of(5)
+        });
+        assert_eq!(received(&mut a), messages());
+        assert_eq!(received(&mut b), messages());
+    }
+
+    #[test]
+    fn delivers_duplicates_once() {
+        let (mut a, mut b) = (Link::new(), Link::new());
+        let now = Instant::now();
+        a.send(b"hello");
+        let packet = a.transmit(now).unwrap();
+        b.receive(&packet, now).unwrap();
+        b.receive(&packet, now).unwrap();
+        assert_eq!(received(&mut b), vec![b"hello".to_vec()]);
+        // the ack releases the packet
+        a.receive(&b.transmit(now).unwrap(), now).unwrap();
+        assert_eq!(a.deadline(), None);
+    }
+
+    #[test]
+    fn gives_up_on_a_silent_peer() {
+        let mut link = Link::new();
+        let start = Instant::now();
+        link.send(b"hello");
+        let mut now = start;
+        let mut retransmissions = 0;
+        while link.transmit(now).is_some() {
+            now = link.deadline().unwrap();
+            match link.on_timeout(now) {
+                Ok(()) => retransmissions += 1,
+                Err(idle) => {
+                    assert_eq!(idle, now - start);
+                    assert_eq!(retransmissions, MAX_RETRIES);
+                    return;
+                }
+            }
+        }
+        panic!("nothing left to send");
+    }
+
+    #[test]
+    fn rejects_messages_beyond_the_limit() {
+        let mut link = Link::new();
+        let now = Instant::now();
+        let payload = vec![0; MAX_PAYLOAD];
+        let mut result = Ok(());
+        for seq in 0..=(MAX_MESSAGE / MAX_PAYLOAD) as u32 {
+            let header = Header {
+                kind: DATA,
+                flags: MORE,
+                seq,
+                ack: 0,
+                sack: 0,
+            };
+            result = link.receive(&header.encode(&payload), now);
+        }
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(link.receive(b"yes", now).is_err());
+    }
+}
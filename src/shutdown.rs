@@ -0,0 +1,69 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio_util::sync::CancellationToken;
+
+// Sent to clients that are still waiting for a peer when the server stops:
+// a `going-away [<alternative>]` datagram over UDP, a json frame over TCP.
+pub const GOING_AWAY: &str = "going-away";
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shutdown handle shared by the rendezvous servers. Once triggered they stop
+/// accepting clients, send waiting clients to `alternative` and give pairings
+/// already in flight `drain_timeout` to finish before returning.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    token: CancellationToken,
+    pub alternative: Option<SocketAddr>,
+    pub drain_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(alternative: Option<SocketAddr>, drain_timeout: Duration) -> Self {
+        Shutdown {
+            token: CancellationToken::new(),
+            alternative,
+            drain_timeout,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn udp_message(&self) -> String {
+        match self.alternative {
+            Some(alternative) => format!("{} {}", GOING_AWAY, alternative),
+            None => GOING_AWAY.to_string(),
+        }
+    }
+
+    pub fn tcp_message(&self) -> serde_json::Value {
+        serde_json::json!({
+            GOING_AWAY: true,
+            "alternative": self.alternative.map(|addr| addr.to_string()),
+        })
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new(None, DRAIN_TIMEOUT)
+    }
+}
+
+/// Alternative server named in a `going-away` datagram, `Some(None)` when the
+/// server gave none and `None` when `msg` is not a going-away message.
+pub fn parse_udp_going_away(msg: &[u8]) -> Option<Option<SocketAddr>> {
+    let msg = std::str::from_utf8(msg).ok()?;
+    let rest = msg.strip_prefix(GOING_AWAY)?;
+    Some(rest.trim().parse().ok())
+}
@@ -0,0 +1,299 @@
+use std::{
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+/// A UDP rendezvous client, with the id it pings with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UdpRecord {
+    pub addr: SocketAddr,
+    pub client_id: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    pub registered_ms: u64,
+}
+
+/// A relay allocation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayRecord {
+    pub addr: SocketAddr,
+    pub room: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Records {
+    pub udp: Vec<UdpRecord>,
+    pub relay: Vec<RelayRecord>,
+}
+
+/// Where the servers keep registrations that outlive a connection: UDP
+/// clients with their ids and relay rooms. The servers write through on
+/// every change and load the records back when they start. TCP sessions
+/// end with their connection and are never stored.
+///
+/// Writes happen under the servers' state locks and cannot fail, a backend
+/// reports its own errors.
+pub trait RegistrationStore: Send + Sync {
+    fn load(&self) -> Records;
+    fn put_udp(&self, record: UdpRecord);
+    fn remove_udp(&self, addr: SocketAddr);
+    fn clear_udp(&self);
+    fn put_relay(&self, record: RelayRecord);
+    fn remove_relay(&self, addr: SocketAddr);
+}
+
+impl Records {
+    fn put_udp(&mut self, record: UdpRecord) {
+        self.udp.retain(|r| r.addr != record.addr);
+        self.udp.push(record);
+    }
+
+    fn put_relay(&mut self, record: RelayRecord) {
+        self.relay.retain(|r| r.addr != record.addr);
+        self.relay.push(record);
+    }
+}
+
+/// Records kept in the process, lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<Records>,
+}
+
+impl RegistrationStore for MemoryStore {
+    fn load(&self) -> Records {
+        self.records.lock().unwrap().clone()
+    }
+
+    fn put_udp(&self, record: UdpRecord) {
+        self.records.lock().unwrap().put_udp(record);
+    }
+
+    fn remove_udp(&self, addr: SocketAddr) {
+        self.records.lock().unwrap().udp.retain(|r| r.addr != addr);
+    }
+
+    fn clear_udp(&self) {
+        self.records.lock().unwrap().udp.clear();
+    }
+
+    fn put_relay(&self, record: RelayRecord) {
+        self.records.lock().unwrap().put_relay(record);
+    }
+
+    fn remove_relay(&self, addr: SocketAddr) {
+        self.records
+            .lock()
+            .unwrap()
+            .relay
+            .retain(|r| r.addr != addr);
+    }
+}
+
+/// Records in a JSON file, rewritten atomically after every change so it can
+/// be read with any JSON tool while the server runs. A writer thread does the
+/// writing, changes made meanwhile go out together, and every write is synced
+/// to disk with its directory before the next one starts. The file belongs
+/// to one server: `open` fails while another store has it open, since
+/// servers sharing a file would overwrite each other's records.
+pub struct FileStore {
+    records: Arc<Mutex<Records>>,
+    changed: Option<mpsc::Sender<()>>,
+    writer: Option<thread::JoinHandle<()>>,
+    // held for the lifetime of the store, closing it releases the lock
+    _lock: fs::File,
+}
+
+impl FileStore {
+    /// Open `path`, starting empty when it does not exist yet. Failed
+    /// writes are counted in `metrics`, usually those of the server using
+    /// the store.
+    pub fn open(path: impl Into<PathBuf>, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let path = path.into();
+        let lock = lock(&path)?;
+        let records = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Records::default(),
+            Err(err) => return Err(err),
+        };
+        let records = Arc::new(Mutex::new(records));
+        let (changed, pending) = mpsc::channel();
+        let writer = thread::Builder::new().name("store-writer".into()).spawn({
+            let records = Arc::clone(&records);
+            move || {
+                while pending.recv().is_ok() {
+                    while pending.try_recv().is_ok() {}
+                    let records = records.lock().unwrap().clone();
+                    if let Err(err) = write(&path, &records) {
+                        warn!("Failed to write {}: {}", path.display(), err);
+                        metrics.error("store_write");
+                    }
+                }
+            }
+        })?;
+        Ok(FileStore {
+            records,
+            changed: Some(changed),
+            writer: Some(writer),
+            _lock: lock,
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Records)) {
+        let mut records = self.records.lock().unwrap();
+        let before = records.clone();
+        f(&mut records);
+        if *records != before
+            && let Some(changed) = &self.changed
+        {
+            let _ = changed.send(());
+        }
+    }
+}
+
+impl Drop for FileStore {
+    // the last changes are written before the store is gone
+    fn drop(&mut self) {
+        self.changed.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// Take the lock of the store at `path`, a file next to it.
+fn lock(path: &Path) -> io::Result<fs::File> {
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("lock"))?;
+    // SAFETY: flock only takes the descriptor, which `lock` keeps open
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} is in use by another server", path.display()),
+            ),
+            _ => err,
+        });
+    }
+    Ok(lock)
+}
+
+// Replace `path` with `records`, on disk once this returns.
+fn write(path: &Path, records: &Records) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(records)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // the rename is only durable once the directory is synced as well
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+impl RegistrationStore for FileStore {
+    fn load(&self) -> Records {
+        self.records.lock().unwrap().clone()
+    }
+
+    fn put_udp(&self, record: UdpRecord) {
+        self.update(|records| records.put_udp(record));
+    }
+
+    fn remove_udp(&self, addr: SocketAddr) {
+        self.update(|records| records.udp.retain(|r| r.addr != addr));
+    }
+
+    fn clear_udp(&self) {
+        self.update(|records| records.udp.clear());
+    }
+
+    fn put_relay(&self, record: RelayRecord) {
+        self.update(|records| records.put_relay(record));
+    }
+
+    fn remove_relay(&self, addr: SocketAddr) {
+        self.update(|records| records.relay.retain(|r| r.addr != addr));
+    }
+}
+
+/// Wall clock time of `at`, for records that outlive the process.
+pub(crate) fn unix_ms(at: Instant) -> u64 {
+    let wall = SystemTime::now() - at.elapsed();
+    wall.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The instant of a stored wall clock time, clamped to now.
+pub(crate) fn instant_of(unix_ms: u64) -> Instant {
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_millis(unix_ms))
+        .unwrap_or_default();
+    Instant::now().checked_sub(age).unwrap_or_else(Instant::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own for each test, removed up front so reruns start empty.
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nat-store-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn record(port: u16) -> UdpRecord {
+        UdpRecord {
+            addr: SocketAddr::from(([192, 0, 2, 1], port)),
+            client_id: Some(port.into()),
+            registered_ms: 1,
+        }
+    }
+
+    #[test]
+    fn writes_records_before_closing() {
+        let path = dir("reopen").join("registrations.json");
+        let store = FileStore::open(&path, Arc::default()).unwrap();
+        store.put_udp(record(1));
+        store.put_udp(record(2));
+        store.remove_udp(record(1).addr);
+        drop(store);
+
+        let on_disk: Records = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(on_disk.udp, vec![record(2)]);
+        assert_eq!(
+            FileStore::open(&path, Arc::default()).unwrap().load(),
+            on_disk
+        );
+    }
+
+    #[test]
+    fn belongs_to_one_store_at_a_time() {
+        let path = dir("lock").join("registrations.json");
+        let store = FileStore::open(&path, Arc::default()).unwrap();
+        let err = FileStore::open(&path, Arc::default()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(store);
+        FileStore::open(&path, Arc::default()).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+use std::{io, net::SocketAddr};
+
+use tracing::info;
+
+/// Ports tried after the announced one by `PortSweep` unless told otherwise.
+pub const DEFAULT_SWEEP_WIDTH: u16 = 16;
+// Refused connects in a row before the listening end of a simultaneous open
+// stops dialing.
+const LISTEN_AFTER_REFUSED: u32 = 3;
+
+/// Which end of a TCP introduction dials, assigned by the rendezvous server:
+/// the client with the lower public address dials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Dial,
+    Listen,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "dial" => Some(Role::Dial),
+            "listen" => Some(Role::Listen),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Dial => "dial",
+            Role::Listen => "listen",
+        }
+    }
+}
+
+/// What a client knows once the rendezvous server introduced a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Introduction {
+    /// The peer's address as seen by the rendezvous server.
+    pub peer: SocketAddr,
+    /// `None` from servers that do not assign roles.
+    pub role: Option<Role>,
+    /// Our NAT allocates a mapping per destination, `None` when the mapping
+    /// probe could not tell.
+    pub symmetric: Option<bool>,
+    /// The same for the peer's NAT, `None` when the peer did not say or was
+    /// introduced by another cluster node.
+    pub peer_symmetric: Option<bool>,
+    /// An address the peer's gateway forwards to it, if it mapped one.
+    pub peer_candidate: Option<SocketAddr>,
+}
+
+/// What one punch attempt does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Dial this address.
+    Dial(SocketAddr),
+    /// Only open our mapping towards this address with a low TTL SYN, the
+    /// peer dials us and `nat_server` accepts it.
+    Listen(SocketAddr),
+}
+
+/// Decides what each attempt of `tcp::nat_client` does. Every strategy runs
+/// in the same punch loop, so traversal results look alike whichever formed
+/// the connection.
+pub trait PunchStrategy: Send {
+    /// Name in logs and traversal reports.
+    fn name(&self) -> &'static str;
+
+    /// The next attempt, `last` is how the previous one failed.
+    fn next(&mut self, last: Option<io::ErrorKind>) -> Step;
+}
+
+/// Both ends dial the address the other was announced with until their SYNs
+/// cross or one gets through to the other's listener.
+#[derive(Debug, Clone)]
+pub struct SimultaneousOpen {
+    peer: SocketAddr,
+    listen_after_refused: Option<u32>,
+    refused: u32,
+    listening: bool,
+}
+
+impl SimultaneousOpen {
+    pub fn new(peer: SocketAddr) -> Self {
+        SimultaneousOpen {
+            peer,
+            listen_after_refused: None,
+            refused: 0,
+            listening: false,
+        }
+    }
+
+    /// Stop dialing once `refused` connects in a row were refused. The peer's
+    /// NAT resets SYNs it did not expect, so one end keeps its mapping open
+    /// and leaves dialing to the other.
+    pub fn listen_after_refused(mut self, refused: u32) -> Self {
+        self.listen_after_refused = Some(refused);
+        self
+    }
+}
+
+impl PunchStrategy for SimultaneousOpen {
+    fn name(&self) -> &'static str {
+        if self.listening {
+            "listen_only"
+        } else {
+            "simultaneous_open"
+        }
+    }
+
+    fn next(&mut self, last: Option<io::ErrorKind>) -> Step {
+        if self.listening {
+            return Step::Listen(self.peer);
+        }
+        if last == Some(io::ErrorKind::ConnectionRefused) {
+            self.refused += 1;
+        } else {
+            self.refused = 0;
+        }
+        if let Some(limit) = self.listen_after_refused
+            && self.refused >= limit
+        {
+            info!(
+                "Connects refused {} times in a row, listening",
+                self.refused
+            );
+            self.listening = true;
+            return Step::Listen(self.peer);
+        }
+        Step::Dial(self.peer)
+    }
+}
+
+/// Never dial, keep our mapping towards the peer open and wait for it.
+#[derive(Debug, Clone)]
+pub struct ListenOnly {
+    peer: SocketAddr,
+}
+
+impl ListenOnly {
+    pub fn new(peer: SocketAddr) -> Self {
+        ListenOnly { peer }
+    }
+}
+
+impl PunchStrategy for ListenOnly {
+    fn name(&self) -> &'static str {
+        "listen_only"
+    }
+
+    fn next(&mut self, _last: Option<io::ErrorKind>) -> Step {
+        Step::Listen(self.peer)
+    }
+}
+
+/// Dial the announced port and the ones after it in turn. NATs that allocate
+/// a mapping per destination mostly hand them out sequentially, so the
+/// peer's mapping towards us is close above the one the server saw. Close
+/// to the top of the port range the sweep narrows, ports past 65535 do not
+/// exist.
+#[derive(Debug, Clone)]
+pub struct PortSweep {
+    peer: SocketAddr,
+    width: u16,
+    offset: u16,
+}
+
+impl PortSweep {
+    pub fn new(peer: SocketAddr, width: u16) -> Self {
+        let above = u16::MAX - peer.port();
+        PortSweep {
+            peer,
+            width: width.clamp(1, above.saturating_add(1)),
+            offset: 0,
+        }
+    }
+}
+
+impl PunchStrategy for PortSweep {
+    fn name(&self) -> &'static str {
+        "port_sweep"
+    }
+
+    fn next(&mut self, _last: Option<io::ErrorKind>) -> Step {
+        let mut target = self.peer;
+        target.set_port(self.peer.port() + self.offset);
+        self.offset = (self.offset + 1) % self.width;
+        Step::Dial(target)
+    }
+}
+
+/// Alternate dials of the peer's candidate with the attempts of another
+/// strategy. The gateway forwards the candidate without any punching, so
+/// the first dial usually connects, the other strategy covers gateways that
+/// granted a mapping but do not forward it.
+pub struct Mapped {
+    candidate: SocketAddr,
+    fallback: Box<dyn PunchStrategy>,
+    // steps taken, the candidate is dialed first and then every other step
+    steps: u64,
+    // how the fallback's own last attempt failed
+    fallback_last: Option<io::ErrorKind>,
+}
+
+impl Mapped {
+    pub fn new(candidate: SocketAddr, fallback: Box<dyn PunchStrategy>) -> Self {
+        Mapped {
+            candidate,
+            fallback,
+            steps: 0,
+            fallback_last: None,
+        }
+    }
+}
+
+impl PunchStrategy for Mapped {
+    fn name(&self) -> &'static str {
+        if self.steps == 0 || !self.steps.is_multiple_of(2) {
+            "port_mapping"
+        } else {
+            self.fallback.name()
+        }
+    }
+
+    fn next(&mut self, last: Option<io::ErrorKind>) -> Step {
+        let candidate = self.steps.is_multiple_of(2);
+        self.steps += 1;
+        if candidate {
+            self.fallback_last = last;
+            Step::Dial(self.candidate)
+        } else {
+            self.fallback.next(self.fallback_last.take())
+        }
+    }
+}
+
+/// Pick the strategy for an introduction from the NAT classification of
+/// both ends and our role, dialing the peer's candidate in between when it
+/// has one.
+pub fn select(intro: &Introduction) -> Box<dyn PunchStrategy> {
+    let strategy = punch_strategy(intro);
+    match intro.peer_candidate {
+        Some(candidate) if candidate != intro.peer => Box::new(Mapped::new(candidate, strategy)),
+        _ => strategy,
+    }
+}
+
+fn punch_strategy(intro: &Introduction) -> Box<dyn PunchStrategy> {
+    let dials = intro.role != Some(Role::Listen);
+    match (intro.symmetric, intro.peer_symmetric) {
+        // the peer's mapping towards us is not the announced one, search for
+        // it unless ours is just as unpredictable and the peer searches
+        (Some(false) | None, Some(true)) => {
+            Box::new(PortSweep::new(intro.peer, DEFAULT_SWEEP_WIDTH))
+        }
+        (Some(true), Some(true)) if dials => {
+            Box::new(PortSweep::new(intro.peer, DEFAULT_SWEEP_WIDTH))
+        }
+        // our mapping is the unpredictable one, dialing the same address
+        // keeps it in place while the peer searches
+        (Some(true), _) => Box::new(SimultaneousOpen::new(intro.peer)),
+        _ if dials => Box::new(SimultaneousOpen::new(intro.peer)),
+        _ => {
+            let open = SimultaneousOpen::new(intro.peer);
+            Box::new(open.listen_after_refused(LISTEN_AFTER_REFUSED))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn intro(
+        symmetric: Option<bool>,
+        peer_symmetric: Option<bool>,
+        role: Option<Role>,
+    ) -> Introduction {
+        Introduction {
+            peer: addr("198.51.100.1:4000"),
+            role,
+            symmetric,
+            peer_symmetric,
+            peer_candidate: None,
+        }
+    }
+
+    #[test]
+    fn selects_by_nat_types_and_role() {
+        let (dial, listen) = (Some(Role::Dial), Some(Role::Listen));
+        for (symmetric, peer_symmetric, role, expected) in [
+            (None, None, None, "simultaneous_open"),
+            (Some(false), Some(false), dial, "simultaneous_open"),
+            (Some(false), Some(false), listen, "simultaneous_open"),
+            (Some(false), None, listen, "simultaneous_open"),
+            // the peer's mapping towards us is searched for
+            (Some(false), Some(true), dial, "port_sweep"),
+            (Some(false), Some(true), listen, "port_sweep"),
+            (None, Some(true), listen, "port_sweep"),
+            // both unpredictable, only the dialing end searches
+            (Some(true), Some(true), dial, "port_sweep"),
+            (Some(true), Some(true), None, "port_sweep"),
+            (Some(true), Some(true), listen, "simultaneous_open"),
+            // ours is the unpredictable one, the peer searches
+            (Some(true), Some(false), dial, "simultaneous_open"),
+            (Some(true), None, listen, "simultaneous_open"),
+        ] {
+            let intro = intro(symmetric, peer_symmetric, role);
+            let mut strategy = select(&intro);
+            assert_eq!(strategy.name(), expected, "{:?}", intro);
+            assert_eq!(strategy.next(None), Step::Dial(intro.peer), "{:?}", intro);
+        }
+    }
+
+    #[test]
+    fn listening_end_stops_dialing_once_refused() {
+        let intro = intro(Some(false), Some(false), Some(Role::Listen));
+        let mut strategy = select(&intro);
+        let refused = Some(io::ErrorKind::ConnectionRefused);
+        let steps: Vec<_> = [None, refused, refused, refused, None]
+            .into_iter()
+            .map(|last| strategy.next(last))
+            .collect();
+        let (dial, listen) = (Step::Dial(intro.peer), Step::Listen(intro.peer));
+        assert_eq!(steps, [dial, dial, dial, listen, listen]);
+        assert_eq!(strategy.name(), "listen_only");
+        // the dialing end never gives up
+        let mut strategy = select(&Introduction {
+            role: Some(Role::Dial),
+            ..intro
+        });
+        for _ in 0..5 {
+            assert_eq!(strategy.next(refused), dial);
+        }
+    }
+
+    #[test]
+    fn mapped_alternates_candidate_and_fallback() {
+        let candidate = addr("203.0.113.1:5000");
+        let intro = Introduction {
+            peer_candidate: Some(candidate),
+            ..intro(Some(false), Some(true), Some(Role::Dial))
+        };
+        let mut strategy = select(&intro);
+        assert_eq!(strategy.name(), "port_mapping");
+        let mut swept = intro.peer;
+        for n in 0..4 {
+            assert_eq!(strategy.next(None), Step::Dial(candidate));
+            assert_eq!(strategy.name(), "port_mapping");
+            swept.set_port(intro.peer.port() + n);
+            assert_eq!(strategy.next(None), Step::Dial(swept));
+            assert_eq!(strategy.name(), "port_sweep");
+        }
+        // the peer's own address needs no candidate
+        let intro = Introduction {
+            peer_candidate: Some(intro.peer),
+            ..intro
+        };
+        assert_eq!(select(&intro).name(), "port_sweep");
+    }
+
+    #[test]
+    fn mapped_fallback_sees_its_own_failures() {
+        let peer = addr("198.51.100.1:4000");
+        let candidate = addr("203.0.113.1:5000");
+        let fallback = SimultaneousOpen::new(peer).listen_after_refused(2);
+        let mut strategy = Mapped::new(candidate, Box::new(fallback));
+        let (refused, timed_out) = (
+            Some(io::ErrorKind::ConnectionRefused),
+            Some(io::ErrorKind::TimedOut),
+        );
+        // `last` is how the step before failed: the fallback is refused,
+        // the candidate times out
+        let steps: Vec<_> = [None, timed_out, refused, timed_out, refused, timed_out]
+            .into_iter()
+            .map(|last| strategy.next(last))
+            .collect();
+        let (dial, listen) = (Step::Dial(peer), Step::Listen(peer));
+        let candidate = Step::Dial(candidate);
+        assert_eq!(steps, [candidate, dial, candidate, dial, candidate, listen]);
+    }
+
+    #[test]
+    fn port_sweep_stays_in_the_port_range() {
+        let peer = addr("198.51.100.1:65534");
+        let mut sweep = PortSweep::new(peer, DEFAULT_SWEEP_WIDTH);
+        let ports: Vec<_> = (0..5)
+            .map(|_| match sweep.next(None) {
+                Step::Dial(target) => target.port(),
+                Step::Listen(_) => unreachable!("a sweep always dials"),
+            })
+            .collect();
+        assert_eq!(ports, [65534, 65535, 65534, 65535, 65534]);
+        let mut sweep = PortSweep::new(addr("198.51.100.1:65535"), 4);
+        assert_eq!(sweep.next(None), Step::Dial(addr("198.51.100.1:65535")));
+        assert_eq!(sweep.next(None), Step::Dial(addr("198.51.100.1:65535")));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Instant};
+
+use tokio::net::UdpSocket;
+use tracing::info;
+
+use crate::{
+    keepalive::{Keepalive, KeepaliveConfig, KeepaliveEvent},
+    traversal::TraversalResult,
+    udp,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SupervisorConfig {
+    pub keepalive: KeepaliveConfig,
+    /// Re-punches allowed after the first traversal before giving up.
+    pub max_migrations: u32,
+    /// Address a gateway forwards to `bind_addr`, passed on to every peer.
+    pub candidate: Option<SocketAddr>,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            keepalive: KeepaliveConfig::default(),
+            max_migrations: 3,
+            candidate: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MigrationStats {
+    pub migrations: u32,
+    pub last_event: Option<KeepaliveEvent>,
+    pub last_migration: Option<Instant>,
+    /// Peer address of every path used, oldest first.
+    pub peers: Vec<SocketAddr>,
+}
+
+/// UDP path to a peer that survives NAT rebinding: when keepalives stop
+/// coming back it registers with the rendezvous servers again, punches a
+/// new path and carries on, so `send`/`recv` callers never see the switch.
+pub struct SupervisedUdp {
+    servers: Vec<SocketAddr>,
+    bind_addr: SocketAddr,
+    id: u64,
+    config: SupervisorConfig,
+    sock: Arc<UdpSocket>,
+    keepalive: Keepalive,
+    result: TraversalResult,
+    stats: MigrationStats,
+}
+
+impl SupervisedUdp {
+    pub async fn connect(
+        servers: Vec<SocketAddr>,
+        bind_addr: SocketAddr,
+        config: SupervisorConfig,
+    ) -> io::Result<Self> {
+        let id = rand::random();
+        let (sock, result) =
+            udp::nat_client(servers.clone(), bind_addr, id, config.candidate).await?;
+        let stats = MigrationStats {
+            peers: vec![sock.peer_addr()?],
+            ..Default::default()
+        };
+        Ok(SupervisedUdp {
+            servers,
+            bind_addr,
+            id,
+            config,
+            sock,
+            keepalive: Keepalive::new(config.keepalive),
+            result,
+            stats,
+        })
+    }
+
+    pub fn result(&self) -> &TraversalResult {
+        &self.result
+    }
+
+    pub fn stats(&self) -> &MigrationStats {
+        &self.stats
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.sock.peer_addr().unwrap()
+    }
+
+    /// Send to the peer over the current path. A datagram sent while the
+    /// path is dead is lost, like any other UDP datagram.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.send(buf).await
+    }
+
+    /// Receive the next payload from the peer, migrating to a new path as
+    /// often as allowed when the current one dies.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match udp::recv_alive(&self.sock, &mut self.keepalive, buf).await {
+                Ok(len) => return Ok(len),
+                Err(event) => self.migrate(event).await?,
+            }
+        }
+    }
+
+    async fn migrate(&mut self, event: KeepaliveEvent) -> io::Result<()> {
+        self.stats.last_event = Some(event);
+        if self.stats.migrations >= self.config.max_migrations {
+            return Err(io::Error::other(format!(
+                "path lost after {} migrations: {:?}",
+                self.stats.migrations, event
+            )));
+        }
+        info!("Path lost ({:?}), punching again", event);
+
+        let (sock, result) = udp::nat_client(
+            self.servers.clone(),
+            self.bind_addr,
+            self.id,
+            self.config.candidate,
+        )
+        .await?;
+        self.sock = sock;
+        self.result = result;
+        self.keepalive = Keepalive::new(self.config.keepalive);
+        self.stats.migrations += 1;
+        self.stats.last_migration = Some(Instant::now());
+        self.stats.peers.push(self.peer_addr());
+        info!(
+            "Migrated to {} (migration {} of {})",
+            self.peer_addr(),
+            self.stats.migrations,
+            self.config.max_migrations
+        );
+        Ok(())
+    }
+}
@@ -1,20 +1,293 @@
 use std::{
     collections::HashMap,
+    io,
     net::SocketAddr,
     os::fd::{FromRawFd, IntoRawFd},
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use futures::{SinkExt, StreamExt};
-use log::info;
 use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
-    net::{TcpSocket, TcpStream},
+    net::{TcpSocket, TcpStream, UdpSocket},
+    sync::{mpsc, watch},
     time,
 };
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{Instrument, Span, field, info, info_span, instrument};
 
-pub async fn nat_server(addr: SocketAddr) {
+use crate::{
+    CANDIDATE, PEER_GONE, REGISTER, SYMMETRIC, TRAVERSAL_COMPLETE,
+    keepalive::{
+        KEEPALIVE, Keepalive, KeepaliveAction, KeepaliveConfig, KeepaliveEvent,
+        RENDEZVOUS_HEARTBEAT, is_keepalive, set_tcp_keepalive,
+    },
+    mux::{self, Mode, Mux, MuxDriver},
+    relay::{self, RelayTicket},
+    shutdown::GOING_AWAY,
+    strategy::{self, Introduction, PunchStrategy, Role, Step},
+    traversal::{
+        MappingConsensus, PUNCH_TIMEOUT, TraversalResult, any_addr_for, connect_first, no_servers,
+        query_tcp_mappings, rendezvous_order,
+    },
+    udp,
+};
+
+// Both rounds of the session handshake must complete within this, or the
+// connection is half-broken.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+// Sent by the peer with the lower session id on a connection it drops
+// because another one to us is already up.
+const DUPLICATE: &[u8] = br#"{"duplicate":true}"#;
+// Sent by the same peer on the connection it keeps, before anything else of
+// a multiplexed session.
+const KEPT: &[u8] = br#"{"kept":true}"#;
+// How long a low TTL SYN gets to leave, and to connect when nothing
+// between us and the peer counts down its TTL, before the real connect.
+const LOW_TTL_SYN_WAIT: Duration = Duration::from_millis(50);
+// TTL of the SYN keeping our mapping open while listening, unless `low_ttl`
+// is set: enough to leave a home NAT, too little for most paths to the peer.
+const PRIME_TTL: u32 = 2;
+
+/// How punch attempts open their connection.
+#[derive(Debug, Clone, Copy)]
+pub struct PunchConfig {
+    /// Precede the connect of an attempt with a SYN of this TTL. It opens
+    /// our NAT's mapping towards the peer but expires before reaching the
+    /// peer's NAT, which may answer unsolicited SYNs with a reset that kills
+    /// the peer's own mapping.
+    pub low_ttl: Option<u32>,
+    /// Only every `low_ttl_every`-th attempt, starting with the first, sends
+    /// the low TTL SYN. 0 and 1 mean all of them.
+    pub low_ttl_every: u32,
+    /// Picks the strategy driving the attempts once a peer is introduced,
+    /// `strategy::select` by default.
+    pub strategy: fn(&Introduction) -> Box<dyn PunchStrategy>,
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        PunchConfig {
+            low_ttl: None,
+            low_ttl_every: 1,
+            strategy: strategy::select,
+        }
+    }
+}
+
+impl PunchConfig {
+    /// TTL of the SYN preceding punch attempt `attempt`, counted from 1.
+    pub fn syn_ttl(&self, attempt: u32) -> Option<u32> {
+        let every = self.low_ttl_every.max(1);
+        self.low_ttl
+            .filter(|_| attempt.saturating_sub(1).is_multiple_of(every))
+    }
+}
+
+/// How a punched TCP connection came about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Formation {
+    /// Both sides dialed and their SYNs crossed, no listener took part.
+    SimultaneousOpen,
+    /// Our dial was accepted by the peer's `nat_server`.
+    Outbound,
+    /// The peer's dial was accepted by our `nat_server`.
+    Inbound,
+}
+
+impl Formation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Formation::SimultaneousOpen => "simultaneous_open",
+            Formation::Outbound => "outbound",
+            Formation::Inbound => "inbound",
+        }
+    }
+}
+
+/// A punched connection that passed the session handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connection {
+    pub peer: SocketAddr,
+    pub formation: Formation,
+    /// The session id the peer announced.
+    pub peer_session: u64,
+}
+
+/// A punched connection carrying multiplexed streams, see
+/// `Endpoint::multiplexed`.
+#[derive(Debug)]
+pub struct MuxSession {
+    pub connection: Connection,
+    pub mux: Mux,
+}
+
+/// Our side of TCP traversal, shared by `nat_server` and `nat_client`: the
+/// session id we announce in handshakes and the connections that passed
+/// one, so that a single connection per peer survives.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    session: u64,
+    peers: Arc<Mutex<Peers>>,
+    candidate: Option<SocketAddr>,
+    mux: Option<mpsc::UnboundedSender<MuxSession>>,
+}
+
+#[derive(Debug, Default)]
+struct Peers {
+    next_id: u64,
+    // peer session -> id of the connection kept for it
+    live: HashMap<u64, u64>,
+}
+
+// What became of a connection that passed the handshake.
+enum Admission {
+    // the only connection to the peer, registered until the claim drops
+    First(Claim),
+    // another connection to the peer is up and the peer, whose session id is
+    // lower, tells us which one to close
+    PeerDecides,
+    // another connection to the peer is up and this one was closed
+    Closed,
+}
+
+struct Claim {
+    peers: Arc<Mutex<Peers>>,
+    peer_session: u64,
+    id: u64,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.live.get(&self.peer_session) == Some(&self.id) {
+            peers.live.remove(&self.peer_session);
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn new() -> Self {
+        Self::with_session(rand::random())
+    }
+
+    pub fn with_session(session: u64) -> Self {
+        Endpoint {
+            session,
+            peers: Arc::default(),
+            candidate: None,
+            mux: None,
+        }
+    }
+
+    /// Carry multiplexed streams over every connection kept, instead of the
+    /// greeting and keepalives only. Each one is announced with the `Mux` to
+    /// open and accept its streams on the returned channel once both ends
+    /// agreed to keep it, duplicates are never announced. Both ends must be
+    /// multiplexed.
+    pub fn multiplexed(mut self) -> (Self, mpsc::UnboundedReceiver<MuxSession>) {
+        let (sessions, receiver) = mpsc::unbounded_channel();
+        self.mux = Some(sessions);
+        (self, receiver)
+    }
+
+    /// Tell peers to dial `candidate` too, an address a gateway forwards to
+    /// the port `nat_server` listens on.
+    pub fn with_candidate(mut self, candidate: SocketAddr) -> Self {
+        self.candidate = Some(candidate);
+        self
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    pub fn candidate(&self) -> Option<SocketAddr> {
+        self.candidate
+    }
+
+    // Run the session of a connection that passed `admit` until the path is
+    // gone, multiplexed if asked for. The lower session id allocates odd
+    // stream ids and, deciding which connection survives, tells the peer
+    // with `KEPT` before announcing the session. The peer announces it when
+    // `KEPT` arrives, a `DUPLICATE` ends it unannounced.
+    async fn run_session(
+        &self,
+        mut stream: Framed<TcpStream, LengthDelimitedCodec>,
+        connection: Connection,
+        keepalive: KeepaliveConfig,
+    ) -> KeepaliveEvent {
+        let Some(sessions) = &self.mux else {
+            return run_session(stream, keepalive).await;
+        };
+        if self.session >= connection.peer_session {
+            let (mux, driver) = Mux::new(Mode::Server);
+            let pending = Some((MuxSession { connection, mux }, sessions));
+            return drive_mux(stream, keepalive, driver, pending).await;
+        }
+        if let Err(err) = stream.send(bytes::Bytes::from_static(KEPT)).await {
+            info!("Failed to send kept notice: {}", err);
+            return KeepaliveEvent::Closed {
+                peer: connection.peer,
+            };
+        }
+        let (mux, driver) = Mux::new(Mode::Client);
+        let _ = sessions.send(MuxSession { connection, mux });
+        drive_mux(stream, keepalive, driver, None).await
+    }
+
+    // Keep one connection per peer. Both ends may see the two connections
+    // complete in different orders, so only the end with the lower session
+    // id decides: it keeps the connection that came first and closes later
+    // ones with a `DUPLICATE` notice, the other end keeps everything until
+    // told so.
+    async fn admit(
+        &self,
+        stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
+        connection: &Connection,
+    ) -> Admission {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if !peers.live.contains_key(&connection.peer_session) {
+                let id = peers.next_id;
+                peers.next_id += 1;
+                peers.live.insert(connection.peer_session, id);
+                return Admission::First(Claim {
+                    peers: Arc::clone(&self.peers),
+                    peer_session: connection.peer_session,
+                    id,
+                });
+            }
+        }
+        if self.session >= connection.peer_session {
+            info!("Duplicate connection, the peer decides which one survives");
+            return Admission::PeerDecides;
+        }
+        info!("Closing duplicate connection");
+        if let Err(err) = stream.send(bytes::Bytes::from_static(DUPLICATE)).await {
+            info!("Failed to send duplicate notice: {}", err);
+        }
+        let _ = stream.close().await;
+        Admission::Closed
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accept punched connections from peers and announce each one that passes
+/// the handshake for `endpoint` on `accepted`, unless another connection to
+/// the same peer is up already.
+pub async fn nat_server(
+    addr: SocketAddr,
+    endpoint: Endpoint,
+    keepalive: KeepaliveConfig,
+    accepted: mpsc::UnboundedSender<Connection>,
+) {
     let domain = Domain::for_address(addr);
     let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).unwrap();
     socket.set_reuse_address(true).unwrap();
@@ -31,25 +304,290 @@ pub async fn nat_server(addr: SocketAddr) {
     loop {
         let (stream, addr) = listener.accept().await.unwrap();
         info!("Accepted connection from: {}", addr);
-        tokio::spawn(async move {
-            let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
-            stream
-                .send(bytes::Bytes::from("Hello, world!"))
-                .await
-                .unwrap();
-
-            while let Some(msg) = stream.next().await {
-                let msg = msg.unwrap();
+        let accepted = accepted.clone();
+        let endpoint = endpoint.clone();
+        tokio::spawn(
+            async move {
+                let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+                let connection = match handshake(&mut stream, endpoint.session, false).await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        info!("Discarding half-broken connection: {}", err);
+                        return;
+                    }
+                };
                 info!(
-                    "Received message: {:?}",
-                    String::from_utf8(msg.to_vec()).unwrap()
+                    formation = connection.formation.as_str(),
+                    peer_session = connection.peer_session,
+                    "Handshake complete"
                 );
+                let _claim = match endpoint.admit(&mut stream, &connection).await {
+                    Admission::First(claim) => {
+                        let _ = accepted.send(connection);
+                        Some(claim)
+                    }
+                    Admission::PeerDecides => None,
+                    Admission::Closed => return,
+                };
+                let event = endpoint.run_session(stream, connection, keepalive).await;
+                info!("Session ended: {:?}", event);
+            }
+            .instrument(info_span!("session", transport = "tcp", peer = %addr, inbound = true)),
+        );
+    }
+}
+
+// Exchange session ids over a fresh punched connection: each side sends its
+// id and whether it dialed, then echoes the id it got. A connection that
+// cannot carry both rounds is half-broken, e.g. reset by a NAT meanwhile.
+async fn handshake(
+    stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    session: u64,
+    dialed: bool,
+) -> io::Result<Connection> {
+    let exchange = async {
+        let peer = stream.get_ref().peer_addr()?;
+        let hello = serde_json::json!({ "session": session, "dialed": dialed });
+        stream.send(bytes::Bytes::from(hello.to_string())).await?;
+        let hello = next_json(stream).await?;
+        let (Some(peer_session), Some(peer_dialed)) =
+            (hello["session"].as_u64(), hello["dialed"].as_bool())
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a session handshake",
+            ));
+        };
+        let ack = serde_json::json!({ "ack": peer_session });
+        stream.send(bytes::Bytes::from(ack.to_string())).await?;
+        if next_json(stream).await?["ack"].as_u64() != Some(session) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer echoed another session",
+            ));
+        }
+        let formation = match (dialed, peer_dialed) {
+            (true, true) => Formation::SimultaneousOpen,
+            (true, false) => Formation::Outbound,
+            (false, true) => Formation::Inbound,
+            (false, false) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "neither side dialed",
+                ));
+            }
+        };
+        Ok(Connection {
+            peer,
+            formation,
+            peer_session,
+        })
+    };
+    time::timeout(HANDSHAKE_TIMEOUT, exchange)
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "handshake timed out",
+            ))
+        })
+}
+
+// Next frame of a punched connection as json, keepalives skipped.
+async fn next_json(
+    stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
+) -> io::Result<serde_json::Value> {
+    loop {
+        match stream.next().await {
+            Some(Ok(msg)) if is_keepalive(&msg) => {}
+            Some(Ok(msg)) => return serde_json::from_slice(&msg).map_err(Into::into),
+            Some(Err(err)) => return Err(err),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Greet the peer and keep the punched connection's NAT bindings alive until
+/// the peer closes it, drops it as a duplicate or stops answering.
+pub async fn run_session(
+    mut stream: Framed<TcpStream, LengthDelimitedCodec>,
+    config: KeepaliveConfig,
+) -> KeepaliveEvent {
+    let peer = stream.get_ref().peer_addr().unwrap();
+    let mut keepalive = Keepalive::new(config);
+    if let Err(err) = stream.send(bytes::Bytes::from("Hello, world!")).await {
+        info!("Failed to send message: {}", err);
+        return KeepaliveEvent::Closed { peer };
+    }
+
+    loop {
+        tokio::select! {
+            action = keepalive.tick() => match action {
+                KeepaliveAction::Send => {
+                    if let Err(err) = stream.send(bytes::Bytes::from_static(KEEPALIVE)).await {
+                        info!("Failed to send keepalive: {}", err);
+                        return KeepaliveEvent::Closed { peer };
+                    }
+                }
+                KeepaliveAction::Dead(idle) => return KeepaliveEvent::PathDead { peer, idle },
+            },
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) if &msg[..] == DUPLICATE => {
+                    info!("Peer kept another connection, closing this one");
+                    let _ = stream.close().await;
+                    return KeepaliveEvent::Duplicate { peer };
+                }
+                Some(Ok(msg)) => {
+                    keepalive.record();
+                    if !is_keepalive(&msg) {
+                        info!(
+                            "Received message: {:?}, from: {}",
+                            String::from_utf8_lossy(&msg),
+                            peer
+                        );
+                    }
+                }
+                Some(Err(err)) => {
+                    info!("Failed to receive message: {}", err);
+                    return KeepaliveEvent::Closed { peer };
+                }
+                None => {
+                    info!("Connection closed by remote: {}", peer);
+                    return KeepaliveEvent::Closed { peer };
+                }
+            },
+        }
+    }
+}
+
+/// Like `run_session`, but the connection carries the streams of `driver`
+/// instead of a greeting. The session ends with the path, failing every
+/// stream still open.
+pub async fn run_mux_session(
+    stream: Framed<TcpStream, LengthDelimitedCodec>,
+    config: KeepaliveConfig,
+    driver: MuxDriver,
+) -> KeepaliveEvent {
+    drive_mux(stream, config, driver, None).await
+}
+
+// `run_mux_session` of a session announced on `pending`'s channel once the
+// peer sends `KEPT`.
+async fn drive_mux(
+    mut stream: Framed<TcpStream, LengthDelimitedCodec>,
+    config: KeepaliveConfig,
+    mut driver: MuxDriver,
+    mut pending: Option<(MuxSession, &mpsc::UnboundedSender<MuxSession>)>,
+) -> KeepaliveEvent {
+    let peer = stream.get_ref().peer_addr().unwrap();
+    let mut keepalive = Keepalive::new(config);
+
+    loop {
+        tokio::select! {
+            action = keepalive.tick() => match action {
+                KeepaliveAction::Send => {
+                    if let Err(err) = stream.send(bytes::Bytes::from_static(KEEPALIVE)).await {
+                        info!("Failed to send keepalive: {}", err);
+                        return KeepaliveEvent::Closed { peer };
+                    }
+                }
+                KeepaliveAction::Dead(idle) => return KeepaliveEvent::PathDead { peer, idle },
+            },
+            frame = driver.outbound() => {
+                if let Err(err) = stream.send(frame).await {
+                    info!("Failed to send mux frame: {}", err);
+                    return KeepaliveEvent::Closed { peer };
+                }
             }
-        });
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) if &msg[..] == DUPLICATE => {
+                    info!("Peer kept another connection, closing this one");
+                    let _ = stream.close().await;
+                    return KeepaliveEvent::Duplicate { peer };
+                }
+                Some(Ok(msg)) if &msg[..] == KEPT => {
+                    keepalive.record();
+                    if let Some((session, sessions)) = pending.take() {
+                        let _ = sessions.send(session);
+                    }
+                }
+                Some(Ok(msg)) => {
+                    keepalive.record();
+                    if mux::is_frame(&msg) {
+                        if let Err(err) = driver.inbound(&msg) {
+                            info!("Mux protocol error: {}", err);
+                            return KeepaliveEvent::Closed { peer };
+                        }
+                    } else if !is_keepalive(&msg) {
+                        info!(
+                            "Received message: {:?}, from: {}",
+                            String::from_utf8_lossy(&msg),
+                            peer
+                        );
+                    }
+                }
+                Some(Err(err)) => {
+                    info!("Failed to receive message: {}", err);
+                    return KeepaliveEvent::Closed { peer };
+                }
+                None => {
+                    info!("Connection closed by remote: {}", peer);
+                    return KeepaliveEvent::Closed { peer };
+                }
+            },
+        }
+    }
+}
+
+pub fn create_socket(bind_addr: SocketAddr) -> (TcpSocket, SocketAddr) {
+    let socket = bind_socket(Domain::for_address(bind_addr), bind_addr);
+    let addr = socket.local_addr().unwrap();
+    (socket, addr)
+}
+
+// Dial `peer` from `local` with a SYN limited to `ttl` hops. Returns the
+// connection if the SYN got through anyway, its TTL restored, otherwise the
+// socket is dropped while still connecting, which sends nothing, and the
+// mapping it opened is left for the real connect.
+async fn low_ttl_connect(
+    domain: Domain,
+    local: SocketAddr,
+    peer: SocketAddr,
+    ttl: u32,
+) -> io::Result<Option<TcpStream>> {
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    let default_ttl = if domain == Domain::IPV6 {
+        socket.set_only_v6(false)?;
+        let hops = socket.unicast_hops_v6()?;
+        socket.set_unicast_hops_v6(ttl)?;
+        hops
+    } else {
+        let default_ttl = socket.ttl()?;
+        socket.set_ttl(ttl)?;
+        default_ttl
+    };
+    socket.set_nonblocking(true)?;
+    socket.bind(&local.into())?;
+    match socket.connect(&peer.into()) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(err) => return Err(err),
     }
+    time::sleep(LOW_TTL_SYN_WAIT).await;
+    if socket.take_error()?.is_some() || socket.peer_addr().is_err() {
+        return Ok(None);
+    }
+    if domain == Domain::IPV6 {
+        socket.set_unicast_hops_v6(default_ttl)?;
+    } else {
+        socket.set_ttl(default_ttl)?;
+    }
+    TcpStream::from_std(socket.into()).map(Some)
 }
 
-pub fn create_socket(domain: Domain) -> (TcpSocket, SocketAddr) {
+fn bind_socket(domain: Domain, addr: SocketAddr) -> TcpSocket {
     let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).unwrap();
     socket.set_reuse_address(true).unwrap();
     socket.set_reuse_port(true).unwrap();
@@ -58,119 +596,417 @@ pub fn create_socket(domain: Domain) -> (TcpSocket, SocketAddr) {
     }
     socket.set_nonblocking(true).unwrap();
     let socket = unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) };
-    match domain {
-        Domain::IPV4 => socket.bind("0.0.0.0:0".parse().unwrap()).unwrap(),
-        Domain::IPV6 => socket.bind("[::]:0".parse().unwrap()).unwrap(),
-        _ => panic!("Unsupported domain"),
-    };
-    let addr = socket.local_addr().unwrap();
-    (socket, addr)
+    socket.bind(addr).unwrap();
+    socket
 }
 
-pub async fn nat_client(socket: TcpSocket, addr: SocketAddr) {
-    let listen_addr = socket.local_addr().unwrap();
-    let domain = Domain::for_address(addr);
-    let stream = socket.connect(addr).await.unwrap();
-    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+/// Rendezvous through the first reachable server and punch towards the peer.
+/// `inbound` carries connections accepted by `nat_server`: a peer that got
+/// through before the rendezvous server introduced it also completes traversal.
+/// Every punched connection must pass the handshake for `endpoint`, sockets
+/// that connected but cannot carry it are discarded and punching goes on.
+/// Sessions outlive the traversal, each runs in its own task. `punch` says
+/// how each attempt dials the peer: the server's introduction and what both
+/// ends know about their NATs pick a strategy. When no connection is up
+/// within `PUNCH_TIMEOUT` and the server handed out a relay room, the session
+/// runs over UDP through the relay instead.
+#[instrument(skip_all, fields(transport = "tcp", rendezvous = field::Empty, peer = field::Empty))]
+pub async fn nat_client(
+    socket: TcpSocket,
+    servers: Vec<SocketAddr>,
+    endpoint: Endpoint,
+    keepalive: KeepaliveConfig,
+    punch: PunchConfig,
+    mut inbound: mpsc::UnboundedReceiver<Connection>,
+) -> std::io::Result<TraversalResult> {
+    let start = Instant::now();
+    let listen_addr = socket.local_addr()?;
+    let domain = Domain::for_address(*servers.first().ok_or_else(no_servers)?);
+
+    // probed from the port we punch from, mapping requests are answered
+    // without registering us
+    let mappings = query_tcp_mappings(&servers, || bind_socket(domain, listen_addr)).await;
+    let symmetric = match MappingConsensus::from_mappings(&mappings) {
+        MappingConsensus::Consistent(_) => Some(false),
+        MappingConsensus::Inconsistent => Some(true),
+        MappingConsensus::Unverified(_) | MappingConsensus::Unknown => None,
+    };
 
-    if let Some(msg) = stream.next().await {
-        let msg = msg.unwrap();
-        let msg = serde_json::from_slice::<HashMap<String, String>>(&msg).unwrap();
-        let nat_addr: SocketAddr = msg.get("address").unwrap().parse().unwrap();
+    let mut socket = Some(socket);
+    let mut heartbeat;
+    let mut candidates = rendezvous_order(&mappings);
+    let (mut result, mut stream, msg) = loop {
+        let Some((addr, stream)) = connect_first(&candidates, || {
+            socket
+                .take()
+                .unwrap_or_else(|| bind_socket(domain, listen_addr))
+        })
+        .await
+        else {
+            return Err(std::io::Error::other("no rendezvous server reachable"));
+        };
+        let mut result = TraversalResult::new(listen_addr, addr, mappings.clone());
+        Span::current().record("rendezvous", field::display(addr));
+        // the TCP rendezvous server registers us as soon as we are connected
+        result.stats.server_connected = Some(start.elapsed());
+        result.stats.registered = result.stats.server_connected;
+        info!(
+            "Rendezvous via {}, mapping consensus: {:?}",
+            result.rendezvous, result.consensus
+        );
+        set_tcp_keepalive(&stream, RENDEZVOUS_HEARTBEAT);
+        let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+        // the server passes what we know about our NAT on to the peer it
+        // introduces us to
+        let mut hello = serde_json::Map::new();
+        hello.insert(REGISTER.to_string(), true.into());
+        if let Some(symmetric) = symmetric {
+            hello.insert(SYMMETRIC.to_string(), symmetric.into());
+        }
+        if let Some(candidate) = endpoint.candidate {
+            hello.insert(CANDIDATE.to_string(), candidate.to_string().into());
+        }
+        let hello = serde_json::Value::Object(hello);
+        stream.send(bytes::Bytes::from(hello.to_string())).await?;
+        heartbeat = time::interval_at(
+            time::Instant::now() + RENDEZVOUS_HEARTBEAT,
+            RENDEZVOUS_HEARTBEAT,
+        );
+
+        let msg = tokio::select! {
+            msg = next_message(&mut stream, &mut heartbeat) => msg,
+            Some(connection) = inbound.recv() => return Ok(inbound_result(result, connection, start)),
+        };
+        let Some(msg) = msg else {
+            break (result, stream, None);
+        };
+        let msg = msg?;
+        if msg.get(GOING_AWAY).is_none() {
+            break (result, stream, Some(msg));
+        }
+        // the server shuts down before introducing us, move on to the
+        // server it suggests or to the next one we know
+        let alternative = msg["alternative"]
+            .as_str()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok());
+        info!(
+            "Rendezvous server {} going away, alternative: {:?}",
+            addr, alternative
+        );
+        candidates.retain(|server| *server != addr);
+        if let Some(alternative) = alternative {
+            candidates.retain(|server| *server != alternative);
+            candidates.insert(0, alternative);
+        }
+    };
+    let mut next = msg;
+    while let Some(msg) = next.take() {
+        // anything but an introduction, e.g. a late peer_gone, is skipped
+        let Some(nat_addr) = peer_addr(&msg["address"], domain) else {
+            // the next peer may get through before the server introduces it
+            next = tokio::select! {
+                msg = next_message(&mut stream, &mut heartbeat) => msg.transpose()?,
+                Some(connection) = inbound.recv() => return Ok(inbound_result(result, connection, start)),
+            };
+            continue;
+        };
+        result.stats.peer_info = Some(start.elapsed());
         info!("Received address: {}", nat_addr);
-        let nat_addr = match domain {
-            Domain::IPV4 => SocketAddr::new(nat_addr.ip().to_canonical(), nat_addr.port()),
-            Domain::IPV6 => nat_addr,
-            _ => panic!("Unsupported domain"),
+        result.peer = Some(nat_addr);
+        result.relay = msg["relay"].as_str().and_then(|ticket| ticket.parse().ok());
+        Span::current().record("peer", field::display(nat_addr));
+        let introduction = Introduction {
+            peer: nat_addr,
+            role: msg["role"].as_str().and_then(Role::parse),
+            symmetric,
+            peer_symmetric: msg["peer_symmetric"].as_bool(),
+            peer_candidate: peer_addr(&msg["peer_candidate"], domain),
         };
+        let mut strategy = (punch.strategy)(&introduction);
+        info!(
+            role = introduction.role.map(|role| role.as_str()),
+            peer_symmetric = introduction.peer_symmetric,
+            peer_candidate = introduction.peer_candidate.map(field::display),
+            "Punching with {}",
+            strategy.name()
+        );
+        // the strategy may change its mind while punching
+        let (strategy_name, current_strategy) = watch::channel(strategy.name());
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (tx, mut rx) = tokio::sync::oneshot::channel();
+        let mut stats = result.stats.clone();
+        let endpoint = endpoint.clone();
 
-        tokio::spawn(async move {
-            // Use a fixed interval but add a small amount of randomness
-            let base_retry_interval = Duration::from_millis(200);
+        let punch = tokio::spawn(
+            async move {
+                // Use a fixed interval but add a small amount of randomness
+                let base_retry_interval = Duration::from_millis(200);
+                let mut attempts = 0;
+                let mut errors = Vec::new();
+                let mut last = None;
 
-            let stream = loop {
-                let jitter = Duration::from_millis(rand::random::<u64>() % 50);
-                let actual_interval = if rand::random::<bool>() {
-                    base_retry_interval + jitter
-                } else {
-                    base_retry_interval.saturating_sub(jitter)
-                };
-                let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).unwrap();
-                socket.set_reuse_address(true).unwrap();
-                socket.set_reuse_port(true).unwrap();
-                if domain == Domain::IPV6 {
-                    socket.set_only_v6(false).unwrap();
-                }
-                socket.set_nonblocking(true).unwrap();
-                let socket = unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) };
-                socket.bind(listen_addr).unwrap();
-
-                match time::timeout(time::Duration::from_millis(200), socket.connect(nat_addr))
-                    .await
-                {
-                    Ok(Ok(stream)) => {
-                        if let Err(err) = check_connection(&stream) {
-                            info!("Failed to connect to NAT(base check): {}", err);
+                let (mut stream, connection) = loop {
+                    attempts += 1;
+                    let jitter = Duration::from_millis(rand::random::<u64>() % 50);
+                    let actual_interval = if rand::random::<bool>() {
+                        base_retry_interval + jitter
+                    } else {
+                        base_retry_interval.saturating_sub(jitter)
+                    };
+                    let step = strategy.next(last.take());
+                    strategy_name.send_replace(strategy.name());
+                    stats.first_punch.get_or_insert_with(|| start.elapsed());
+                    let (target, ttl) = match step {
+                        Step::Dial(target) => (target, punch.syn_ttl(attempts)),
+                        Step::Listen(target) => (target, Some(punch.low_ttl.unwrap_or(PRIME_TTL))),
+                    };
+                    let mut connected = None;
+                    if let Some(ttl) = ttl {
+                        match low_ttl_connect(domain, listen_addr, target, ttl).await {
+                            Ok(stream) => connected = stream,
+                            Err(err) => info!("Failed to send low ttl SYN: {}", err),
                         }
-                        break Ok(stream);
                     }
-                    Err(err) => {
-                        info!("Failed to connect to NAT(timeout): {}", err);
+                    if connected.is_none() && matches!(step, Step::Listen(_)) {
+                        // the peer's dial arrives on `inbound`
+                        time::sleep(actual_interval).await;
+                        continue;
                     }
-                    Ok(Err(err)) => {
-                        if err.kind() == std::io::ErrorKind::AddrNotAvailable {
-                            break Err(err);
+                    let connect = async {
+                        match connected {
+                            Some(stream) => Ok(stream),
+                            None => bind_socket(domain, listen_addr).connect(target).await,
                         }
-                        info!("Failed to connect to NAT(other): {}, {}", err.kind(), err);
-                    }
-                }
-                time::sleep(actual_interval).await;
-            };
-            tx.send(()).unwrap();
+                    };
 
-            if let Ok(stream) = stream {
-                let remote_addr = stream.peer_addr().unwrap();
-                info!("remote addr: {}", remote_addr);
-                let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
-                loop {
-                    stream
-                        .send(bytes::Bytes::from("Hello, world!"))
-                        .await
-                        .unwrap();
-
-                    match tokio::time::timeout(Duration::from_millis(200), stream.next()).await {
-                        Ok(Some(Ok(msg))) => {
-                            let msg = msg.to_vec();
-                            info!(
-                                "Received message: {:?}, from: {}",
-                                String::from_utf8(msg).unwrap(),
-                                remote_addr
-                            );
-                        }
-                        Ok(None) => {
-                            info!("Connection closed by remote: {}", remote_addr);
-                            break;
+                    match time::timeout(time::Duration::from_millis(200), connect).await {
+                        Ok(Ok(stream)) => {
+                            stats
+                                .first_peer_packet
+                                .get_or_insert_with(|| start.elapsed());
+                            // a reset racing the connect leaves a socket that
+                            // connected but is dead already
+                            let established = match check_connection(&stream) {
+                                Ok(()) => {
+                                    let mut stream =
+                                        Framed::new(stream, LengthDelimitedCodec::new());
+                                    handshake(&mut stream, endpoint.session, true)
+                                        .await
+                                        .map(|connection| (stream, connection))
+                                }
+                                Err(err) => Err(err),
+                            };
+                            match established {
+                                Ok(established) => break established,
+                                Err(err) => {
+                                    info!("Discarding half-broken connection: {}", err);
+                                    last = Some(err.kind());
+                                    stats.record_error(err.kind());
+                                    errors.push(format!("handshake: {}", err));
+                                }
+                            }
                         }
-                        Ok(Some(Err(err))) => {
-                            info!("Failed to receive message: {}", err);
-                            break;
+                        Err(err) => {
+                            info!("Failed to connect to NAT(timeout): {}", err);
+                            last = Some(std::io::ErrorKind::TimedOut);
+                            stats.record_error(std::io::ErrorKind::TimedOut);
+                            errors.push(format!("connect: {}", err));
                         }
-                        Err(_) => {
-                            continue;
+                        // AddrNotAvailable means the four-tuple is taken by the
+                        // peer's connection that `nat_server` accepted, it is
+                        // announced on `inbound` once its handshake is done
+                        Ok(Err(err)) => {
+                            last = Some(err.kind());
+                            stats.record_error(err.kind());
+                            errors.push(err.to_string());
+                            info!("Failed to connect to NAT(other): {}, {}", err.kind(), err);
                         }
                     }
+                    time::sleep(actual_interval).await;
+                };
+                info!(
+                    formation = connection.formation.as_str(),
+                    peer_session = connection.peer_session,
+                    "remote addr: {}",
+                    connection.peer
+                );
+                let claim = match endpoint.admit(&mut stream, &connection).await {
+                    Admission::First(claim) => {
+                        let _ = tx.send((connection, attempts, errors, stats));
+                        Some(claim)
+                    }
+                    // the connection already up was accepted by `nat_server`
+                    // and is announced on `inbound`
+                    Admission::PeerDecides => None,
+                    Admission::Closed => return,
+                };
+                // aborting the punch once `inbound` won must not end this
+                tokio::spawn(
+                    async move {
+                        let _claim = claim;
+                        let event = endpoint.run_session(stream, connection, keepalive).await;
+                        info!("Session ended: {:?}", event);
+                    }
+                    .in_current_span(),
+                );
+            }
+            .in_current_span(),
+        );
+
+        // keep heartbeating while punching, the server tells us when the
+        // peer expires so we give up instead of punching on
+        let mut server_closed = false;
+        let fallback = relay_after(time::Instant::now() + PUNCH_TIMEOUT, result.relay.clone());
+        tokio::pin!(fallback);
+        let (connection, attempts, errors, stats) = loop {
+            tokio::select! {
+                // a punch that lost to an inbound duplicate drops `tx`
+                Ok(res) = &mut rx => break res,
+                // the peer's own dial got through to `nat_server` first
+                Some(connection) = inbound.recv() => {
+                    punch.abort();
+                    result.strategy = Some(*current_strategy.borrow());
+                    return Ok(inbound_result(result, connection, start));
+                }
+                // punching got nowhere, the relay room carries the session
+                ticket = &mut fallback => {
+                    punch.abort();
+                    info!(
+                        "No direct path to {} within {:?}, relaying via {}",
+                        nat_addr, PUNCH_TIMEOUT, ticket.relay
+                    );
+                    relay_session(&ticket, keepalive).await?;
+                    result.relayed = true;
+                    result.strategy = Some(*current_strategy.borrow());
+                    result.connected_after = Some(start.elapsed());
+                    result.stats.established = result.connected_after;
+                    result.stats.log();
+                    if !server_closed {
+                        report_complete(&mut stream).await;
+                    }
+                    return Ok(result);
                 }
+                msg = next_message(&mut stream, &mut heartbeat), if !server_closed => match msg {
+                    Some(Ok(msg)) if peer_addr(&msg[PEER_GONE], domain) == Some(nat_addr) => {
+                        info!("Peer {} left before the connection was up", nat_addr);
+                        punch.abort();
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::ConnectionAborted,
+                            format!("peer {} left before the connection was up", nat_addr),
+                        ));
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => server_closed = true,
+                },
             }
-        });
+        };
+        result.stats = stats;
+        result.attempts = attempts;
+        for err in errors {
+            result.record_error(err);
+        }
+        result.peer = Some(connection.peer);
+        result.formation = Some(connection.formation);
+        result.strategy = Some(*current_strategy.borrow());
+        result.connected_after = Some(start.elapsed());
+        result.stats.established = result.connected_after;
+        result.stats.log();
 
-        rx.await.unwrap();
+        if !server_closed {
+            report_complete(&mut stream).await;
+        }
+    }
+    Ok(result)
+}
 
-        stream
-            .send(bytes::Bytes::from("NAT traversal complete!"))
-            .await
-            .unwrap();
+// Tell the rendezvous server that the path to the peer is up. Only
+// informational, the server may be gone by now.
+async fn report_complete(stream: &mut Framed<TcpStream, LengthDelimitedCodec>) {
+    if let Err(err) = stream
+        .send(bytes::Bytes::from_static(TRAVERSAL_COMPLETE.as_bytes()))
+        .await
+    {
+        info!("Failed to tell the rendezvous server: {}", err);
+    }
+}
+
+// The relay room of `ticket` once `deadline` passed, never without a ticket.
+async fn relay_after(deadline: time::Instant, ticket: Option<RelayTicket>) -> RelayTicket {
+    let Some(ticket) = ticket else {
+        return std::future::pending().await;
+    };
+    time::sleep_until(deadline).await;
+    ticket
+}
+
+// Meet the peer in the relay room of `ticket` and keep the relayed path
+// alive in its own task. The relay forwards UDP only, so the session is a
+// UDP one.
+async fn relay_session(ticket: &RelayTicket, keepalive: KeepaliveConfig) -> io::Result<()> {
+    let sock = UdpSocket::bind(any_addr_for(ticket.relay)).await?;
+    relay::connect(&sock, ticket).await?;
+    tokio::spawn(
+        async move {
+            let event = udp::run_session(Arc::new(sock), keepalive).await;
+            info!("Relayed session ended: {:?}", event);
+        }
+        .in_current_span(),
+    );
+    Ok(())
+}
+
+// A peer connected to `nat_server` before we punched through to it.
+fn inbound_result(
+    mut result: TraversalResult,
+    connection: Connection,
+    start: Instant,
+) -> TraversalResult {
+    let peer = connection.peer;
+    info!("Peer {} connected before our punch got through", peer);
+    result.peer = Some(peer);
+    result.formation = Some(connection.formation);
+    result.connected_after = Some(start.elapsed());
+    result.stats.first_peer_packet = result.connected_after;
+    result.stats.established = result.connected_after;
+    Span::current().record("peer", field::display(peer));
+    result.stats.log();
+    result
+}
+
+// Address of a peer in a server message, in the family of our socket.
+fn peer_addr(value: &serde_json::Value, domain: Domain) -> Option<SocketAddr> {
+    let addr: SocketAddr = value.as_str()?.parse().ok()?;
+    match domain {
+        Domain::IPV4 => Some(SocketAddr::new(addr.ip().to_canonical(), addr.port())),
+        Domain::IPV6 => Some(addr),
+        _ => panic!("Unsupported domain"),
+    }
+}
+
+// Next json message of the rendezvous server. Heartbeats on `heartbeat` and
+// answers the server's heartbeats, so it does not expire us while we wait
+// whatever its idle timeout.
+async fn next_message(
+    stream: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    heartbeat: &mut time::Interval,
+) -> Option<std::io::Result<serde_json::Value>> {
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                if let Err(err) = stream.send(bytes::Bytes::from_static(KEEPALIVE)).await {
+                    return Some(Err(err));
+                }
+            }
+            msg = stream.next() => match msg? {
+                Ok(msg) if is_keepalive(&msg) => {
+                    if let Err(err) = stream.send(bytes::Bytes::from_static(KEEPALIVE)).await {
+                        return Some(Err(err));
+                    }
+                }
+                Ok(msg) => return Some(serde_json::from_slice(&msg).map_err(Into::into)),
+                Err(err) => return Some(Err(err)),
+            },
+        }
     }
 }
 
@@ -181,3 +1017,55 @@ fn check_connection(stream: &TcpStream) -> Result<(), std::io::Error> {
         Err(err) => Err(err),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use socket2::SockRef;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // The TTL a socket of `domain` starts with.
+    fn default_ttl(domain: Domain) -> u32 {
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        if domain == Domain::IPV6 {
+            socket.unicast_hops_v6().unwrap()
+        } else {
+            socket.ttl().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn low_ttl_connection_gets_its_ttl_back() {
+        for local in ["127.0.0.1:0", "[::1]:0"] {
+            let local: SocketAddr = local.parse().unwrap();
+            let domain = Domain::for_address(local);
+            let listener = TcpListener::bind(local).await.unwrap();
+            // loopback has no hops to count the TTL down, the SYN gets through
+            let stream = low_ttl_connect(domain, local, listener.local_addr().unwrap(), 1)
+                .await
+                .unwrap()
+                .expect("loopback connect");
+            let socket = SockRef::from(&stream);
+            let ttl = if domain == Domain::IPV6 {
+                socket.unicast_hops_v6().unwrap()
+            } else {
+                socket.ttl().unwrap()
+            };
+            assert_eq!(ttl, default_ttl(domain), "{}", local);
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_low_ttl_connect_returns_nothing() {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        // a port nobody listens on once the listener is gone
+        let peer = TcpListener::bind(local)
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let stream = low_ttl_connect(Domain::IPV4, local, peer, 1).await.unwrap();
+        assert!(stream.is_none());
+    }
+}
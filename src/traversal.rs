@@ -0,0 +1,488 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::info;
+
+use crate::{relay::RelayTicket, tcp::Formation, udp};
+
+// Request understood by the UDP rendezvous server: it answers with
+// `mapped <addr>` and does not register the sender for pairing. The TCP
+// server answers it as the first frame of a connection, then closes it.
+pub const MAPPING_REQUEST: &[u8] = b"mapping";
+pub const MAPPING_RESPONSE_PREFIX: &str = "mapped ";
+// Answer of the UDP rendezvous server to a `ping` of a client that waits for
+// a peer, so the client can tell a live server from a dead one.
+pub const WAITING: &str = "waiting";
+// UDP requests are padded with spaces to this size: the server never answers
+// with more bytes than it received, and the longest answer, an IPv6 peer
+// address followed by the IPv6 address its gateway mapped, has to fit.
+pub const REQUEST_SIZE: usize = 128;
+
+const MAPPING_TIMEOUT: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long clients punch towards an introduced peer before they fall back
+/// to the relay room the rendezvous server handed out. Without a room they
+/// punch on.
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn padded(request: &[u8]) -> Vec<u8> {
+    let mut request = request.to_vec();
+    request.resize(REQUEST_SIZE.max(request.len()), b' ');
+    request
+}
+
+/// Reflexive address reported by one server, `None` if it did not answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub server: SocketAddr,
+    pub mapped: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingConsensus {
+    /// No server answered.
+    Unknown,
+    /// Only one server answered, so the mapping could not be cross-checked.
+    Unverified(SocketAddr),
+    /// Every answering server saw the same reflexive address.
+    Consistent(SocketAddr),
+    /// Servers saw different reflexive addresses, which means the NAT
+    /// allocates a mapping per destination (symmetric NAT).
+    Inconsistent,
+}
+
+impl MappingConsensus {
+    pub fn from_mappings(mappings: &[Mapping]) -> Self {
+        let mut seen = mappings.iter().filter_map(|m| m.mapped);
+        let Some(first) = seen.next() else {
+            return MappingConsensus::Unknown;
+        };
+        let mut answers = 1;
+        for mapped in seen {
+            if mapped != first {
+                return MappingConsensus::Inconsistent;
+            }
+            answers += 1;
+        }
+        if answers == 1 {
+            MappingConsensus::Unverified(first)
+        } else {
+            MappingConsensus::Consistent(first)
+        }
+    }
+
+    pub fn is_symmetric(&self) -> bool {
+        matches!(self, MappingConsensus::Inconsistent)
+    }
+}
+
+/// Where the time of a traversal went, every phase measured from its start,
+/// and how the punch attempts failed.
+#[derive(Debug, Clone, Default)]
+pub struct TraversalStats {
+    /// A rendezvous server answered.
+    pub server_connected: Option<Duration>,
+    /// We are registered for pairing with the rendezvous server.
+    pub registered: Option<Duration>,
+    /// The rendezvous server told us the peer's address.
+    pub peer_info: Option<Duration>,
+    pub first_punch: Option<Duration>,
+    pub first_peer_packet: Option<Duration>,
+    pub established: Option<Duration>,
+    /// Failed punch attempts by kind, timeouts included.
+    pub errors: HashMap<io::ErrorKind, u32>,
+}
+
+impl TraversalStats {
+    pub fn record_error(&mut self, kind: io::ErrorKind) {
+        *self.errors.entry(kind).or_default() += 1;
+    }
+
+    /// Log every phase as a field of one event, inside the caller's span.
+    pub fn log(&self) {
+        let ms = |phase: Option<Duration>| phase.map(|d| d.as_millis() as u64);
+        info!(
+            server_connected_ms = ms(self.server_connected),
+            registered_ms = ms(self.registered),
+            peer_info_ms = ms(self.peer_info),
+            first_punch_ms = ms(self.first_punch),
+            first_peer_packet_ms = ms(self.first_peer_packet),
+            established_ms = ms(self.established),
+            errors = ?self.errors,
+            "Traversal phases"
+        );
+    }
+
+    pub fn report(&self) -> serde_json::Value {
+        let ms = |phase: Option<Duration>| phase.map(|d| d.as_millis() as u64);
+        serde_json::json!({
+            "server_connected_ms": ms(self.server_connected),
+            "registered_ms": ms(self.registered),
+            "peer_info_ms": ms(self.peer_info),
+            "first_punch_ms": ms(self.first_punch),
+            "first_peer_packet_ms": ms(self.first_peer_packet),
+            "established_ms": ms(self.established),
+            "errors": self
+                .errors
+                .iter()
+                .map(|(kind, count)| (format!("{:?}", kind), *count))
+                .collect::<BTreeMap<_, _>>(),
+        })
+    }
+}
+
+/// What a client learned while traversing: which server was used for
+/// rendezvous, how the servers saw us, who the peer is and how punching went.
+#[derive(Debug, Clone)]
+pub struct TraversalResult {
+    pub local: SocketAddr,
+    pub rendezvous: SocketAddr,
+    pub mappings: Vec<Mapping>,
+    pub consensus: MappingConsensus,
+    pub peer: Option<SocketAddr>,
+    /// How the TCP connection to the peer formed.
+    pub formation: Option<Formation>,
+    /// The punching strategy in use when the TCP connection formed, `None`
+    /// when the peer got through before being introduced.
+    pub strategy: Option<&'static str>,
+    /// Punch attempts made towards the peer.
+    pub attempts: u32,
+    /// Time from the start of traversal until the path to the peer was up.
+    pub connected_after: Option<Duration>,
+    /// Distinct errors seen while punching.
+    pub errors: Vec<String>,
+    pub stats: TraversalStats,
+    /// A relay room shared with the peer, from servers that hand them out.
+    pub relay: Option<RelayTicket>,
+    /// The path to the peer goes through the room of `relay`, punching did
+    /// not get through within `PUNCH_TIMEOUT`.
+    pub relayed: bool,
+}
+
+impl TraversalResult {
+    pub fn new(local: SocketAddr, rendezvous: SocketAddr, mappings: Vec<Mapping>) -> Self {
+        let consensus = MappingConsensus::from_mappings(&mappings);
+        TraversalResult {
+            local,
+            rendezvous,
+            mappings,
+            consensus,
+            peer: None,
+            formation: None,
+            strategy: None,
+            attempts: 0,
+            connected_after: None,
+            errors: Vec::new(),
+            stats: TraversalStats::default(),
+            relay: None,
+            relayed: false,
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.connected_after.is_some()
+    }
+
+    /// Our public address as seen by the rendezvous server.
+    pub fn reflexive(&self) -> Option<SocketAddr> {
+        self.mappings
+            .iter()
+            .find(|m| m.server == self.rendezvous)
+            .and_then(|m| m.mapped)
+    }
+
+    pub fn record_error(&mut self, err: impl ToString) {
+        let err = err.to_string();
+        if !self.errors.contains(&err) {
+            self.errors.push(err);
+        }
+    }
+
+    pub fn report(&self, mode: &str) -> serde_json::Value {
+        serde_json::json!({
+            "success": self.is_established(),
+            "mode": mode,
+            "family": family(self.rendezvous),
+            "local": self.local.to_string(),
+            "rendezvous": self.rendezvous.to_string(),
+            "reflexive": self.reflexive().map(|addr| addr.to_string()),
+            "mappings": self
+                .mappings
+                .iter()
+                .map(|m| serde_json::json!({
+                    "server": m.server.to_string(),
+                    "mapped": m.mapped.map(|addr| addr.to_string()),
+                }))
+                .collect::<Vec<_>>(),
+            "symmetric": self.consensus.is_symmetric(),
+            "peer": self.peer.map(|addr| addr.to_string()),
+            "formation": self.formation.map(|formation| formation.as_str()),
+            "strategy": self.strategy,
+            "attempts": self.attempts,
+            "connect_ms": self.connected_after.map(|d| d.as_millis() as u64),
+            "path": self
+                .is_established()
+                .then_some(if self.relayed { "relay" } else { "direct" }),
+            "relay": self.relay.as_ref().map(|ticket| ticket.to_string()),
+            "errors": self.errors,
+            "stats": self.stats.report(),
+        })
+    }
+}
+
+/// Report for a traversal that did not get far enough to produce a result.
+pub fn failure_report(mode: &str, servers: &[SocketAddr], error: &str) -> serde_json::Value {
+    serde_json::json!({
+        "success": false,
+        "mode": mode,
+        "family": servers.first().map(|server| family(*server)),
+        "servers": servers.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+        "errors": [error],
+    })
+}
+
+fn family(addr: SocketAddr) -> &'static str {
+    if addr.is_ipv4() { "ipv4" } else { "ipv6" }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// The servers see our own address, there is no NAT in the way.
+    Open,
+    /// The same mapping is used towards every server (cone NAT), so the
+    /// address learned from rendezvous is the one the peer must punch.
+    EndpointIndependent,
+    /// A new mapping is allocated per destination.
+    Symmetric,
+    /// Too few servers answered to tell.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct Classification {
+    pub local: SocketAddr,
+    pub mappings: Vec<Mapping>,
+    pub consensus: MappingConsensus,
+    pub nat_type: NatType,
+    /// The NAT kept our local port for the mapping.
+    pub port_preserved: bool,
+}
+
+impl Classification {
+    pub fn report(&self) -> serde_json::Value {
+        serde_json::json!({
+            "local": self.local.to_string(),
+            "mappings": self
+                .mappings
+                .iter()
+                .map(|m| serde_json::json!({
+                    "server": m.server.to_string(),
+                    "mapped": m.mapped.map(|addr| addr.to_string()),
+                }))
+                .collect::<Vec<_>>(),
+            "nat_type": format!("{:?}", self.nat_type),
+            "port_preserved": self.port_preserved,
+        })
+    }
+}
+
+/// Classify the NAT in front of us by asking every server for our mapping
+/// from one socket bound to `bind_addr`.
+pub async fn classify(servers: &[SocketAddr], bind_addr: SocketAddr) -> io::Result<Classification> {
+    let first = *servers.first().ok_or_else(no_servers)?;
+    let sock = udp::create_socket(bind_addr)?;
+    let port = sock.local_addr()?.port();
+    // the source address the kernel picks towards the first server
+    let local = {
+        let route = udp::create_socket(any_addr_for(first))?;
+        route.connect(first).await?;
+        SocketAddr::new(route.local_addr()?.ip().to_canonical(), port)
+    };
+
+    let mappings = query_mappings(&sock, servers).await;
+    let consensus = MappingConsensus::from_mappings(&mappings);
+    let mapped = match consensus {
+        MappingConsensus::Consistent(addr) | MappingConsensus::Unverified(addr) => Some(addr),
+        _ => None,
+    };
+    let nat_type = match consensus {
+        _ if mapped == Some(local) => NatType::Open,
+        MappingConsensus::Consistent(_) => NatType::EndpointIndependent,
+        MappingConsensus::Inconsistent => NatType::Symmetric,
+        MappingConsensus::Unverified(_) | MappingConsensus::Unknown => NatType::Unknown,
+    };
+    let port_preserved = mappings
+        .iter()
+        .filter_map(|m| m.mapped)
+        .any(|addr| addr.port() == port);
+    info!(
+        "Nat type: {:?}, port preserved: {}",
+        nat_type, port_preserved
+    );
+
+    Ok(Classification {
+        local,
+        mappings,
+        consensus,
+        nat_type,
+        port_preserved,
+    })
+}
+
+/// Error of a traversal given no server to talk to.
+pub fn no_servers() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "no rendezvous server given")
+}
+
+/// Wildcard address with an ephemeral port in the family of `server`.
+pub fn any_addr_for(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+pub fn parse_mapping_response(msg: &[u8]) -> Option<SocketAddr> {
+    std::str::from_utf8(msg)
+        .ok()?
+        .strip_prefix(MAPPING_RESPONSE_PREFIX)?
+        .parse()
+        .ok()
+}
+
+/// Ask every server in parallel for the reflexive address of `sock`.
+///
+/// All requests leave from the same local port, so different answers mean
+/// the NAT picked a new mapping per destination.
+pub async fn query_mappings(sock: &UdpSocket, servers: &[SocketAddr]) -> Vec<Mapping> {
+    let mut answers: HashMap<SocketAddr, SocketAddr> = HashMap::new();
+    for server in servers {
+        if let Err(err) = sock.send_to(&padded(MAPPING_REQUEST), *server).await {
+            info!("Failed to send mapping request to {}: {}", server, err);
+        }
+    }
+
+    let deadline = Instant::now() + MAPPING_TIMEOUT;
+    let mut buf = [0; 1024];
+    while answers.len() < servers.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, sock.recv_from(&mut buf)).await {
+            Ok(Ok((len, from))) => {
+                let from = canonical(from);
+                let Some(server) = servers.iter().find(|s| canonical(**s) == from) else {
+                    continue;
+                };
+                if let Some(mapped) = parse_mapping_response(&buf[..len]) {
+                    info!("Server {} sees us as {}", server, mapped);
+                    answers.insert(*server, canonical(mapped));
+                }
+            }
+            Ok(Err(err)) => {
+                info!("Failed to receive mapping response: {}", err);
+            }
+            Err(_) => break,
+        }
+    }
+
+    servers
+        .iter()
+        .map(|server| Mapping {
+            server: *server,
+            mapped: answers.get(server).copied(),
+        })
+        .collect()
+}
+
+/// Ask every server in parallel for the reflexive address of a TCP
+/// connection from our port. `socket` is called once per server for a
+/// freshly bound socket.
+///
+/// Like `query_mappings`, different answers mean the NAT picked a new
+/// mapping per destination, only this time the NAT's TCP mappings are seen.
+pub async fn query_tcp_mappings(
+    servers: &[SocketAddr],
+    mut socket: impl FnMut() -> TcpSocket,
+) -> Vec<Mapping> {
+    let queries = servers.iter().map(|server| {
+        let socket = socket();
+        async move {
+            let mapped =
+                match tokio::time::timeout(CONNECT_TIMEOUT, tcp_mapping(socket, *server)).await {
+                    Ok(Ok(mapped)) => {
+                        info!("Server {} sees our tcp port as {}", server, mapped);
+                        Some(mapped)
+                    }
+                    Ok(Err(err)) => {
+                        info!("Tcp mapping request to {} failed: {}", server, err);
+                        None
+                    }
+                    Err(_) => {
+                        info!("Tcp mapping request to {} timed out", server);
+                        None
+                    }
+                };
+            Mapping {
+                server: *server,
+                mapped,
+            }
+        }
+    });
+    futures::future::join_all(queries).await
+}
+
+async fn tcp_mapping(socket: TcpSocket, server: SocketAddr) -> io::Result<SocketAddr> {
+    let stream = socket.connect(server).await?;
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    stream
+        .send(bytes::Bytes::from_static(MAPPING_REQUEST))
+        .await?;
+    let answer = stream
+        .next()
+        .await
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+    let mapped = parse_mapping_response(&answer)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected a mapping answer"))?;
+    // the server closes first, so the rendezvous connection from the same
+    // port does not run into our TIME_WAIT
+    let _ = stream.next().await;
+    Ok(canonical(mapped))
+}
+
+/// Servers ordered for rendezvous: the ones that answered the mapping
+/// probe first, in configuration order, followed by the silent ones.
+pub fn rendezvous_order(mappings: &[Mapping]) -> Vec<SocketAddr> {
+    let (reachable, silent): (Vec<&Mapping>, Vec<&Mapping>) =
+        mappings.iter().partition(|m| m.mapped.is_some());
+    reachable
+        .into_iter()
+        .chain(silent)
+        .map(|m| m.server)
+        .collect()
+}
+
+/// Connect to the first reachable server, returning it with the stream.
+/// `socket` is called once per attempt for a freshly bound socket.
+pub async fn connect_first(
+    servers: &[SocketAddr],
+    mut socket: impl FnMut() -> TcpSocket,
+) -> Option<(SocketAddr, TcpStream)> {
+    for server in servers {
+        match tokio::time::timeout(CONNECT_TIMEOUT, socket().connect(*server)).await {
+            Ok(Ok(stream)) => return Some((*server, stream)),
+            Ok(Err(err)) => info!("Rendezvous server {} unreachable: {}", server, err),
+            Err(_) => info!("Rendezvous server {} unreachable: timeout", server),
+        }
+    }
+    None
+}
@@ -1,75 +1,251 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use log::info;
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, sync::oneshot, time};
+use tracing::{Span, field, info, instrument};
 
-pub async fn nat_client(addr: SocketAddr) {
-    let domain = socket2::Domain::for_address(addr);
-    let socket =
-        socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP)).unwrap();
-    socket.set_reuse_port(true).unwrap();
-    socket.set_reuse_address(true).unwrap();
+use crate::keepalive::{
+    KEEPALIVE, Keepalive, KeepaliveAction, KeepaliveConfig, KeepaliveEvent, is_keepalive,
+};
+use crate::mux::{Mode, Mux, MuxDriver};
+use crate::relay::{self, parse_ticket};
+use crate::reliable::{self, Link};
+use crate::shutdown::parse_udp_going_away;
+use crate::traversal::{
+    PUNCH_TIMEOUT, TraversalResult, WAITING, canonical, no_servers, padded, parse_mapping_response,
+    query_mappings, rendezvous_order,
+};
+
+// Unanswered requests before a rendezvous server or a peer is given up.
+const MAX_SILENT_RETRIES: usize = 3;
+// How long a rendezvous server or a peer gets to answer a request.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+// Room for the largest link packet and then some.
+const MAX_DATAGRAM: usize = 2048;
+
+pub fn create_socket(bind_addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = socket2::Domain::for_address(bind_addr);
+    let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_reuse_address(true)?;
     if domain == socket2::Domain::IPV6 {
-        socket.set_only_v6(false).unwrap();
+        socket.set_only_v6(false)?;
     }
-    let bind_addr = match domain {
-        socket2::Domain::IPV4 => "0.0.0.0:0".parse::<SocketAddr>().unwrap(),
-        socket2::Domain::IPV6 => "[::]:0".parse::<SocketAddr>().unwrap(),
-        _ => panic!("Unsupported domain"),
-    };
-    socket.bind(&bind_addr.into()).unwrap();
-    socket.set_nonblocking(true).unwrap();
+    socket.bind(&bind_addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
 
-    let sock = Arc::new(UdpSocket::from_std(socket.into()).unwrap());
+/// Register with the first reachable server under `id` and punch a path to
+/// the peer it introduces from a socket bound to `bind_addr`. Registering
+/// again with the same `id` replaces the previous registration on the server.
+/// A `candidate`, the address a gateway forwards to `bind_addr`, is passed on
+/// to the peer, which punches towards it as well. When punching does not get
+/// through within `PUNCH_TIMEOUT` and the server handed out a relay room, the
+/// returned socket is connected to the relay instead of the peer. Fails once
+/// every server stopped answering or went away.
+#[instrument(skip(servers, bind_addr, candidate), fields(transport = "udp", rendezvous = field::Empty, peer = field::Empty))]
+pub async fn nat_client(
+    servers: Vec<SocketAddr>,
+    bind_addr: SocketAddr,
+    id: u64,
+    candidate: Option<SocketAddr>,
+) -> io::Result<(Arc<UdpSocket>, TraversalResult)> {
+    let start = Instant::now();
+    let domain = socket2::Domain::for_address(*servers.first().ok_or_else(no_servers)?);
+    let sock = Arc::new(create_socket(bind_addr)?);
     let mut buf = [0; 1024];
 
-    sock.send_to(b"ping", addr).await.unwrap();
+    let mappings = query_mappings(&sock, &servers).await;
+    let mut order = rendezvous_order(&mappings);
+    let mapped_after = mappings
+        .iter()
+        .any(|m| m.mapped.is_some())
+        .then(|| start.elapsed());
+    let mut registered = None;
+    let mut relay = None;
+    let ping = match candidate {
+        Some(candidate) => format!("ping {} {}", id, candidate),
+        None => format!("ping {}", id),
+    };
 
-    let (len, _addr) = loop {
-        match tokio::time::timeout(Duration::from_secs(2), sock.recv_from(&mut buf)).await {
-            Ok(Ok((len, addr))) => break (len, addr),
-            Ok(Err(e)) => {
-                unreachable!("error: {:?}", e);
-            }
-            Err(_) => {
-                sock.send_to(b"get", addr).await.unwrap();
-                continue;
+    // every server is tried in turn until one introduces a peer, a server
+    // is given up once it stayed silent `MAX_SILENT_RETRIES` times or went
+    // away
+    let (addr, len) = 'rendezvous: loop {
+        let Some(&addr) = order.first() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no rendezvous server reachable",
+            ));
+        };
+        let mut silent = 0;
+        // pinging again registers us anew if the server expired us meanwhile
+        'ping: loop {
+            sock.send_to(&padded(ping.as_bytes()), addr).await?;
+            registered.get_or_insert_with(|| start.elapsed());
+            let deadline = time::Instant::now() + ANSWER_TIMEOUT;
+            loop {
+                let Ok(received) = time::timeout_at(deadline, sock.recv_from(&mut buf)).await
+                else {
+                    silent += 1;
+                    if silent >= MAX_SILENT_RETRIES {
+                        info!("Rendezvous server {} unreachable, trying next", addr);
+                        order.retain(|server| *server != addr);
+                        continue 'rendezvous;
+                    }
+                    continue 'ping;
+                };
+                let (len, from) = received?;
+                if canonical(from) != canonical(addr)
+                    || parse_mapping_response(&buf[..len]).is_some()
+                {
+                    continue;
+                }
+                if let Some(alternative) = parse_udp_going_away(&buf[..len]) {
+                    // the server shuts down, try the one it suggests next
+                    info!(
+                        "Rendezvous server {} going away, alternative: {:?}",
+                        addr, alternative
+                    );
+                    order.retain(|server| *server != addr);
+                    if let Some(alternative) = alternative {
+                        order.retain(|server| *server != alternative);
+                        order.insert(0, alternative);
+                    }
+                    continue 'rendezvous;
+                }
+                if &buf[..len] == WAITING.as_bytes() {
+                    silent = 0;
+                    continue;
+                }
+                // sent ahead of the introduction, it may come late as well
+                if let Some(ticket) = parse_ticket(&buf[..len]) {
+                    relay = Some(ticket);
+                    continue;
+                }
+                break 'rendezvous (addr, len);
             }
         }
     };
+    let mut result = TraversalResult::new(sock.local_addr()?, addr, mappings);
+    result.relay = relay;
+    Span::current().record("rendezvous", field::display(addr));
+    // apart from `waiting` the server's first answer to `ping` is the peer
+    result.stats.peer_info = Some(start.elapsed());
+    result.stats.server_connected = mapped_after.or(result.stats.peer_info);
+    result.stats.registered = registered;
+    info!(
+        "Rendezvous via {}, mapping consensus: {:?}",
+        result.rendezvous, result.consensus
+    );
 
-    let msg = String::from_utf8(buf[..len].to_vec()).unwrap();
+    let msg = String::from_utf8_lossy(&buf[..len]).into_owned();
 
     // here we can get the nat address from stun server
     // We can obtain 255 UDP sockets by adding a random algorithm to this address
     // and attempt to connect simultaneously. As long as one connection is successful,
     // it is sufficient. According to the birthday problem theory, randomly selecting
     // 255 from 2^16 - 1024 can achieve a success rate of 60%+. This is an effective port sniffing method.
-    let nat_addr: SocketAddr = msg.parse().unwrap();
-    info!("Received address: {}", nat_addr);
-    let nat_addr = match domain {
-        socket2::Domain::IPV4 => SocketAddr::new(nat_addr.ip().to_canonical(), nat_addr.port()),
-        socket2::Domain::IPV6 => nat_addr,
+    // the peer's address, followed by its candidate if it has one
+    let mut introduction = msg.split_whitespace();
+    let nat_addr: SocketAddr = introduction
+        .next()
+        .and_then(|addr| addr.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a peer address, got {:?}", msg),
+            )
+        })?;
+    let peer_candidate = introduction
+        .next()
+        .and_then(|c| c.parse::<SocketAddr>().ok());
+    info!(
+        "Received address: {}, candidate: {:?}",
+        nat_addr, peer_candidate
+    );
+    let (nat_addr, peer_candidate) = match domain {
+        socket2::Domain::IPV4 => (canonical(nat_addr), peer_candidate.map(canonical)),
+        socket2::Domain::IPV6 => (nat_addr, peer_candidate),
         _ => panic!("Unsupported domain"),
     };
+    result.peer = Some(nat_addr);
+    Span::current().record("peer", field::display(nat_addr));
 
-    loop {
-        sock.send_to(b"Hello, world!", nat_addr).await.unwrap();
+    let mut nat_addr = nat_addr;
+    let punch_deadline = time::Instant::now() + PUNCH_TIMEOUT;
+    let peer_msg = loop {
+        if time::Instant::now() >= punch_deadline
+            && let Some(ticket) = result.relay.clone()
+        {
+            info!(
+                "No direct path to {} within {:?}, relaying via {}",
+                nat_addr, PUNCH_TIMEOUT, ticket.relay
+            );
+            relay::connect(&sock, &ticket).await?;
+            result.relayed = true;
+            result.connected_after = Some(start.elapsed());
+            result.stats.established = result.connected_after;
+            result.stats.log();
+            return Ok((sock, result));
+        }
+        result.attempts += 1;
+        sock.send_to(b"Hello, world!", nat_addr).await?;
+        if let Some(peer_candidate) = peer_candidate
+            && let Err(err) = sock.send_to(b"Hello, world!", peer_candidate).await
+        {
+            info!(
+                "Failed to punch towards candidate {}: {}",
+                peer_candidate, err
+            );
+        }
+        result
+            .stats
+            .first_punch
+            .get_or_insert_with(|| start.elapsed());
         match tokio::time::timeout(Duration::from_millis(200), sock.recv_from(&mut buf)).await {
             Ok(Ok((len, addr))) => {
-                let msg = String::from_utf8(buf[..len].to_vec()).unwrap();
+                // late answers from rendezvous servers are not the peer, but
+                // the peer may have registered again from a new address
+                if servers.iter().any(|s| canonical(*s) == canonical(addr)) {
+                    if let Some(ticket) = parse_ticket(&buf[..len]) {
+                        result.relay = Some(ticket);
+                    } else if canonical(addr) == canonical(result.rendezvous)
+                        && let Some(Ok(peer)) = std::str::from_utf8(&buf[..len])
+                            .unwrap_or_default()
+                            .split_whitespace()
+                            .next()
+                            .map(str::parse::<SocketAddr>)
+                        && canonical(peer) != canonical(nat_addr)
+                    {
+                        info!("Peer re-registered, new address: {}", peer);
+                        nat_addr = match domain {
+                            socket2::Domain::IPV4 => canonical(peer),
+                            _ => peer,
+                        };
+                        result.peer = Some(nat_addr);
+                        Span::current().record("peer", field::display(nat_addr));
+                    }
+                    continue;
+                }
+                result.stats.first_peer_packet = Some(start.elapsed());
+                let msg = String::from_utf8_lossy(&buf[..len]).into_owned();
                 info!("Received message: {} from {}", msg, addr);
-                sock.connect(addr).await.unwrap();
-                break;
-            }
-            Ok(Err(e)) => {
-                unreachable!("error: {:?}", e);
+                sock.connect(addr).await?;
+                break msg;
             }
+            Ok(Err(err)) => return Err(err),
             Err(_) => {
+                result.stats.record_error(std::io::ErrorKind::TimedOut);
                 continue;
             }
         }
-    }
+    };
     // now we can send and receive message, nat traversal is done
     // but we need to get the mtu max size between the two peers
     // we can use some packet fragmentation to get the mtu size:
@@ -98,12 +274,208 @@ pub async fn nat_client(addr: SocketAddr) {
     //      drop(sock)
     //      let stream = { // rebind local_addr and connect to remote_addr }
     //     ```
-    sock.send(b"yes").await.unwrap();
-    let len = sock.recv(&mut buf).await.unwrap();
-    let msg = String::from_utf8(buf[..len].to_vec()).unwrap();
-    info!(
-        "Received message: {} from {}",
-        msg,
-        sock.peer_addr().unwrap()
+    sock.send(b"yes").await?;
+    // our first punches may have reached the peer before it learnt our
+    // address, then its `yes` came first and it waits for nothing more
+    if peer_msg != "yes" {
+        // a lost `yes` either way is made up for by sending ours again,
+        // anything the peer sends meanwhile proves the path as well
+        let mut silent = 0;
+        let len = loop {
+            match time::timeout(ANSWER_TIMEOUT, sock.recv(&mut buf)).await {
+                Ok(len) => break len?,
+                Err(_) if silent + 1 < MAX_SILENT_RETRIES => {
+                    silent += 1;
+                    result.stats.record_error(io::ErrorKind::TimedOut);
+                    sock.send(b"yes").await?;
+                }
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "peer did not confirm the punched path",
+                    ));
+                }
+            }
+        };
+        info!(
+            "Received message: {} from {}",
+            String::from_utf8_lossy(&buf[..len]),
+            sock.peer_addr()?
+        );
+    }
+    result.connected_after = Some(start.elapsed());
+    result.stats.established = result.connected_after;
+    result.stats.log();
+    Ok((sock, result))
+}
+
+/// Keep the punched path's NAT bindings alive, logging what the peer sends,
+/// until the peer stops answering. `sock` must be connected to the peer.
+pub async fn run_session(sock: Arc<UdpSocket>, config: KeepaliveConfig) -> KeepaliveEvent {
+    let peer = sock.peer_addr().unwrap();
+    let mut keepalive = Keepalive::new(config);
+    let mut buf = [0; 1024];
+
+    loop {
+        match recv_alive(&sock, &mut keepalive, &mut buf).await {
+            Ok(len) => info!(
+                "Received message: {} from {}",
+                String::from_utf8_lossy(&buf[..len]),
+                peer
+            ),
+            Err(event) => return event,
+        }
+    }
+}
+
+/// Receive the next payload from the connected peer, sending keepalives
+/// while waiting and swallowing the peer's ones.
+pub async fn recv_alive(
+    sock: &UdpSocket,
+    keepalive: &mut Keepalive,
+    buf: &mut [u8],
+) -> Result<usize, KeepaliveEvent> {
+    let peer = sock.peer_addr().unwrap();
+    loop {
+        tokio::select! {
+            action = keepalive.tick() => match action {
+                KeepaliveAction::Send => {
+                    if let Err(err) = sock.send(KEEPALIVE).await {
+                        info!("Failed to send keepalive: {}", err);
+                    }
+                }
+                KeepaliveAction::Dead(idle) => return Err(KeepaliveEvent::PathDead { peer, idle }),
+            },
+            res = sock.recv(buf) => match res {
+                Ok(len) => {
+                    keepalive.record();
+                    if !is_keepalive(&buf[..len]) {
+                        return Ok(len);
+                    }
+                }
+                // ICMP errors surface here on a connected socket, missed
+                // keepalives decide whether the path is really gone
+                Err(err) => info!("Failed to receive message: {}", err),
+            },
+        }
+    }
+}
+
+/// Like `run_session`, but the path carries multiplexed streams, made
+/// reliable by a `reliable::Link`. Both ends first send a random session id,
+/// the lower one allocates odd stream ids, and the `Mux` is handed to
+/// `announce` once the peer's id arrived. Both ends must be multiplexed. The
+/// session ends with the path, failing every stream still open.
+pub async fn run_mux_session(
+    sock: Arc<UdpSocket>,
+    config: KeepaliveConfig,
+    announce: oneshot::Sender<Mux>,
+) -> KeepaliveEvent {
+    let peer = sock.peer_addr().unwrap();
+    let mut keepalive = Keepalive::new(config);
+    let mut link = Link::new();
+    let session: u64 = rand::random();
+    link.send(
+        serde_json::json!({ "session": session })
+            .to_string()
+            .as_bytes(),
     );
+    let mut announce = Some(announce);
+    let mut driver: Option<MuxDriver> = None;
+    let mut buf = [0; MAX_DATAGRAM];
+
+    loop {
+        while let Some(packet) = link.transmit(time::Instant::now()) {
+            if let Err(err) = sock.send(&packet).await {
+                info!("Failed to send packet: {}", err);
+            }
+        }
+        let deadline = link.deadline();
+        tokio::select! {
+            action = keepalive.tick() => match action {
+                KeepaliveAction::Send => {
+                    if let Err(err) = sock.send(KEEPALIVE).await {
+                        info!("Failed to send keepalive: {}", err);
+                    }
+                }
+                KeepaliveAction::Dead(idle) => return KeepaliveEvent::PathDead { peer, idle },
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)), if deadline.is_some() => {
+                if let Err(idle) = link.on_timeout(time::Instant::now()) {
+                    info!("Peer stopped acknowledging");
+                    return KeepaliveEvent::PathDead { peer, idle };
+                }
+            }
+            frame = outbound(&mut driver), if link.ready() => link.send(&frame),
+            res = sock.recv(&mut buf) => match res {
+                Ok(len) => {
+                    keepalive.record();
+                    let packet = &buf[..len];
+                    if !reliable::is_packet(packet) {
+                        if !is_keepalive(packet) {
+                            info!(
+                                "Received message: {} from {}",
+                                String::from_utf8_lossy(packet),
+                                peer
+                            );
+                        }
+                        continue;
+                    }
+                    if let Err(err) = link.receive(packet, time::Instant::now()) {
+                        info!("Link protocol error: {}", err);
+                        return KeepaliveEvent::Closed { peer };
+                    }
+                    while let Some(msg) = link.recv() {
+                        let result = match &mut driver {
+                            Some(driver) => driver.inbound(&msg),
+                            None => start_mux(&msg, session).map(|(mux, started)| {
+                                driver = Some(started);
+                                if let Some(announce) = announce.take() {
+                                    let _ = announce.send(mux);
+                                }
+                            }),
+                        };
+                        if let Err(err) = result {
+                            info!("Mux protocol error: {}", err);
+                            return KeepaliveEvent::Closed { peer };
+                        }
+                    }
+                }
+                // ICMP errors surface here on a connected socket, missed
+                // keepalives decide whether the path is really gone
+                Err(err) => info!("Failed to receive message: {}", err),
+            },
+        }
+    }
+}
+
+// The session of a multiplexed path, from the session id in the peer's
+// first message.
+fn start_mux(hello: &[u8], session: u64) -> io::Result<(Mux, MuxDriver)> {
+    let hello: serde_json::Value = serde_json::from_slice(hello)?;
+    let mode = match hello["session"].as_u64() {
+        Some(peer_session) if session < peer_session => Mode::Client,
+        Some(peer_session) if session > peer_session => Mode::Server,
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer picked our session id",
+            ));
+        }
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a session id",
+            ));
+        }
+    };
+    Ok(Mux::new(mode))
+}
+
+// Next frame of the session's driver, none before the session started.
+async fn outbound(driver: &mut Option<MuxDriver>) -> bytes::Bytes {
+    match driver {
+        Some(driver) => driver.outbound().await,
+        None => std::future::pending().await,
+    }
 }
@@ -0,0 +1,61 @@
+use std::{net::SocketAddr, time::Duration};
+
+use nat_traversal_test::{RendezvousServer, cluster::ClusterConfig, udp};
+use tokio::time;
+
+const SECRET: &str = "cluster test secret";
+
+// A rendezvous server on `udp` gossiping on `node` with `peers`.
+fn start_node(udp: SocketAddr, node: SocketAddr, peers: Vec<SocketAddr>, secret: &str) {
+    tokio::spawn(
+        RendezvousServer::builder()
+            .udp_listen([udp])
+            .udp_workers(1)
+            .cluster(ClusterConfig {
+                node,
+                peers,
+                secret: secret.into(),
+            })
+            .build()
+            .run(),
+    );
+}
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[tokio::test]
+async fn introduces_clients_of_different_nodes() {
+    // only the introducing node, the lower one, names its peer: the other
+    // one has to announce its clients over a link it did not configure
+    start_node(addr(28101), addr(28102), vec![addr(28104)], SECRET);
+    start_node(addr(28103), addr(28104), vec![], SECRET);
+    time::sleep(Duration::from_millis(300)).await;
+
+    let bind = addr(0);
+    let (a, b) = tokio::join!(
+        udp::nat_client(vec![addr(28101)], bind, 1, None),
+        udp::nat_client(vec![addr(28103)], bind, 2, None),
+    );
+    let ((a_sock, a), (b_sock, b)) = (a.unwrap(), b.unwrap());
+    assert_eq!(a.peer, Some(b_sock.local_addr().unwrap()));
+    assert_eq!(b.peer, Some(a_sock.local_addr().unwrap()));
+}
+
+#[tokio::test]
+async fn ignores_nodes_without_the_secret() {
+    start_node(addr(28105), addr(28106), vec![], SECRET);
+    start_node(addr(28107), addr(28108), vec![addr(28106)], "guess");
+    time::sleep(Duration::from_millis(300)).await;
+
+    let bind = addr(0);
+    let paired = time::timeout(Duration::from_secs(3), async {
+        tokio::join!(
+            udp::nat_client(vec![addr(28105)], bind, 1, None),
+            udp::nat_client(vec![addr(28107)], bind, 2, None),
+        )
+    })
+    .await;
+    assert!(paired.is_err(), "clients of untrusted nodes were paired");
+}
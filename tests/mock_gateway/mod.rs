@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use nat_traversal_test::{
+    http::{self, Request, Response},
+    lifetime::Protocol,
+    portmap::{MappingProtocol, PCP_PORT, SSDP_PORT, xml_element},
+};
+use tokio::net::UdpSocket;
+use tracing::info;
+
+const SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+// UPnP error of a port mapped to another client.
+const CONFLICT: u16 = 718;
+// NAT-PMP and PCP result codes.
+const UNSUPPORTED_VERSION: u8 = 1;
+const UNSUPPORTED_OPCODE: u8 = 5;
+// SOAP calls are a few hundred bytes.
+const MAX_BODY: usize = 16 * 1024;
+
+/// A gateway that maps ports on request but forwards nothing, so the port
+/// mapping client can be tested without a router.
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    /// Address the NAT-PMP/PCP, SSDP and HTTP servers bind.
+    pub listen: Ipv4Addr,
+    /// The address mappings announce.
+    pub external: Ipv4Addr,
+    /// Port of the UPnP device description and control endpoint.
+    pub http_port: u16,
+    /// The protocols answered, requests of the others are refused.
+    pub protocols: Vec<MappingProtocol>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    internal: SocketAddr,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub struct MockGateway {
+    config: GatewayConfig,
+    started: Instant,
+    mappings: Mutex<HashMap<(Protocol, u16), Mapping>>,
+}
+
+impl MockGateway {
+    /// Where the gateway forwards `transport` port `external`, if anywhere.
+    pub fn mapping(&self, transport: Protocol, external: u16) -> Option<SocketAddr> {
+        let mappings = self.mappings.lock().unwrap();
+        mappings
+            .get(&(transport, external))
+            .filter(|mapping| mapping.expires > Instant::now())
+            .map(|mapping| mapping.internal)
+    }
+
+    fn speaks(&self, protocol: MappingProtocol) -> bool {
+        self.config.protocols.contains(&protocol)
+    }
+
+    // Map `external` or, when it is taken, the next free port to `internal`.
+    // A zero lifetime removes the mapping of `internal` instead. Returns the
+    // external port, 0 after removing.
+    fn map(
+        &self,
+        protocol: MappingProtocol,
+        transport: Protocol,
+        internal: SocketAddr,
+        external: u16,
+        lifetime: Duration,
+    ) -> Option<u16> {
+        let mut mappings = self.mappings.lock().unwrap();
+        let now = Instant::now();
+        mappings.retain(|_, mapping| mapping.expires > now);
+        if lifetime.is_zero() {
+            mappings.retain(|(t, port), mapping| {
+                let keep = *t != transport || mapping.internal != internal;
+                if !keep {
+                    info!("Gateway removed {} port {} -> {}", t, port, internal);
+                }
+                keep
+            });
+            return Some(0);
+        }
+        let mut port = if external == 0 {
+            internal.port()
+        } else {
+            external
+        };
+        loop {
+            match mappings.get(&(transport, port)) {
+                Some(mapping) if mapping.internal != internal => {
+                    // UPnP asks for one port exactly
+                    if protocol == MappingProtocol::Upnp {
+                        return None;
+                    }
+                    port = port.checked_add(1)?;
+                }
+                _ => break,
+            }
+        }
+        info!(
+            "Gateway maps {} port {} -> {} via {} for {:?}",
+            transport, port, internal, protocol, lifetime
+        );
+        mappings.insert(
+            (transport, port),
+            Mapping {
+                internal,
+                expires: now + lifetime,
+            },
+        );
+        Some(port)
+    }
+
+    fn epoch(&self) -> [u8; 4] {
+        (self.started.elapsed().as_secs() as u32).to_be_bytes()
+    }
+
+    fn nat_pmp(&self, request: &[u8], from: SocketAddr) -> Vec<u8> {
+        let opcode = request.get(1).copied().unwrap_or_default();
+        let mut response = vec![0, 0x80 | opcode, 0, 0];
+        response.extend_from_slice(&self.epoch());
+        match opcode {
+            0 => response.extend_from_slice(&self.config.external.octets()),
+            1 | 2 if request.len() >= 12 => {
+                let transport = if opcode == 1 {
+                    Protocol::Udp
+                } else {
+                    Protocol::Tcp
+                };
+                let internal = u16::from_be_bytes([request[4], request[5]]);
+                let external = u16::from_be_bytes([request[6], request[7]]);
+                let lifetime =
+                    u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+                let lifetime = Duration::from_secs(lifetime.into());
+                let internal = SocketAddr::new(from.ip(), internal);
+                let port = self
+                    .map(
+                        MappingProtocol::NatPmp,
+                        transport,
+                        internal,
+                        external,
+                        lifetime,
+                    )
+                    .unwrap_or_default();
+                response.extend_from_slice(&internal.port().to_be_bytes());
+                response.extend_from_slice(&port.to_be_bytes());
+                response.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+            }
+            _ => response[3] = UNSUPPORTED_OPCODE,
+        }
+        response
+    }
+
+    fn pcp(&self, request: &[u8]) -> Vec<u8> {
+        let mut response = vec![0; 24];
+        response[0] = 2;
+        response[1] = 0x80 | request[1];
+        response[8..12].copy_from_slice(&self.epoch());
+        if request[1] != 1 || request.len() < 60 {
+            response[3] = UNSUPPORTED_OPCODE;
+            return response;
+        }
+        let lifetime = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+        let client: [u8; 16] = request[8..24].try_into().unwrap();
+        let transport = match request[36] {
+            6 => Protocol::Tcp,
+            _ => Protocol::Udp,
+        };
+        let internal = u16::from_be_bytes([request[40], request[41]]);
+        let external = u16::from_be_bytes([request[42], request[43]]);
+        let internal = SocketAddr::new(Ipv6Addr::from(client).to_canonical(), internal);
+        let port = self
+            .map(
+                MappingProtocol::Pcp,
+                transport,
+                internal,
+                external,
+                Duration::from_secs(lifetime.into()),
+            )
+            .unwrap_or_default();
+        response[4..8].copy_from_slice(&lifetime.to_be_bytes());
+        response.extend_from_slice(&request[24..42]);
+        response.extend_from_slice(&port.to_be_bytes());
+        response.extend_from_slice(&self.config.external.to_ipv6_mapped().octets());
+        response
+    }
+
+    fn http(&self, request: &Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/rootDesc.xml") => Response::ok("text/xml", self.description()),
+            ("POST", CONTROL_PATH) => self.control(&request.body),
+            _ => Response::not_found(),
+        }
+    }
+
+    fn description(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\
+             <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device>\
+             <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+             <deviceList><device>\
+             <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>\
+             <deviceList><device>\
+             <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>\
+             <serviceList><service>\
+             <serviceType>{}</serviceType>\
+             <controlURL>{}</controlURL>\
+             </service></serviceList>\
+             </device></deviceList>\
+             </device></deviceList>\
+             </device></root>",
+            SERVICE, CONTROL_PATH
+        )
+    }
+
+    // Answer a SOAP call, the action is the first element of the body.
+    fn control(&self, body: &str) -> Response {
+        if !self.speaks(MappingProtocol::Upnp) {
+            return Response::not_found();
+        }
+        let arg = |name| xml_element(body, name).unwrap_or_default().trim();
+        let transport = match arg("NewProtocol") {
+            "TCP" => Protocol::Tcp,
+            _ => Protocol::Udp,
+        };
+        let external = arg("NewExternalPort").parse().unwrap_or_default();
+        let action = [
+            "AddPortMapping",
+            "DeletePortMapping",
+            "GetExternalIPAddress",
+        ]
+        .into_iter()
+        .find(|action| body.contains(&format!(":{} ", action)));
+        let args = match action {
+            Some("AddPortMapping") => {
+                let internal = arg("NewInternalClient")
+                    .parse::<IpAddr>()
+                    .ok()
+                    .zip(arg("NewInternalPort").parse().ok())
+                    .map(|(ip, port)| SocketAddr::new(ip, port));
+                let lifetime = arg("NewLeaseDuration").parse().unwrap_or_default();
+                let mapped = internal.and_then(|internal| {
+                    self.map(
+                        MappingProtocol::Upnp,
+                        transport,
+                        internal,
+                        external,
+                        Duration::from_secs(lifetime),
+                    )
+                });
+                if mapped.is_none() {
+                    return fault(CONFLICT, "ConflictInMappingEntry");
+                }
+                String::new()
+            }
+            Some("DeletePortMapping") => {
+                let mut mappings = self.mappings.lock().unwrap();
+                if mappings.remove(&(transport, external)).is_none() {
+                    return fault(714, "NoSuchEntryInArray");
+                }
+                info!("Gateway removed {} port {}", transport, external);
+                String::new()
+            }
+            Some(_) => format!(
+                "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+                self.config.external
+            ),
+            None => return fault(401, "Invalid Action"),
+        };
+        let action = action.unwrap();
+        Response::ok(
+            "text/xml",
+            format!(
+                "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body><u:{action}Response xmlns:u=\"{SERVICE}\">{args}</u:{action}Response></s:Body></s:Envelope>"
+            ),
+        )
+    }
+}
+
+fn fault(code: u16, description: &str) -> Response {
+    Response {
+        status: 500,
+        content_type: "text/xml",
+        body: format!(
+            "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+            code, description
+        ),
+    }
+}
+
+/// Start the mock gateway, it runs until the runtime ends.
+pub async fn start(config: GatewayConfig) -> Arc<MockGateway> {
+    let gateway = Arc::new(MockGateway {
+        config,
+        started: Instant::now(),
+        mappings: Mutex::default(),
+    });
+    let listen = gateway.config.listen;
+    let http_addr = SocketAddr::new(listen.into(), gateway.config.http_port);
+    info!(
+        "Mock gateway on {}, external address {}, protocols {:?}",
+        listen, gateway.config.external, gateway.config.protocols
+    );
+
+    let control = UdpSocket::bind((listen, PCP_PORT)).await.unwrap();
+    tokio::spawn(port_control(Arc::clone(&gateway), control));
+    if gateway.speaks(MappingProtocol::Upnp) {
+        let search = UdpSocket::bind((listen, SSDP_PORT)).await.unwrap();
+        tokio::spawn(ssdp(search, http_addr));
+        let handler = Arc::clone(&gateway);
+        tokio::spawn(http::serve_with_body(http_addr, MAX_BODY, move |request| {
+            handler.http(request)
+        }));
+    }
+    gateway
+}
+
+// NAT-PMP and PCP share a port, the first byte is the version.
+async fn port_control(gateway: Arc<MockGateway>, socket: UdpSocket) {
+    let mut buf = [0; 1100];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let request = &buf[..len];
+        if len < 2 {
+            continue;
+        }
+        let response = match request[0] {
+            0 if gateway.speaks(MappingProtocol::NatPmp) => gateway.nat_pmp(request, from),
+            2 if gateway.speaks(MappingProtocol::Pcp) => gateway.pcp(request),
+            // answer in the version we speak, clients fall back on that
+            _ if gateway.speaks(MappingProtocol::Pcp) => {
+                let mut response = vec![0; 24];
+                response[0] = 2;
+                response[1] = 0x80 | request[1];
+                response[3] = UNSUPPORTED_VERSION;
+                response
+            }
+            _ if gateway.speaks(MappingProtocol::NatPmp) => {
+                vec![0, 0x80 | request[1], 0, UNSUPPORTED_VERSION]
+            }
+            _ => continue,
+        };
+        let _ = socket.send_to(&response, from).await;
+    }
+}
+
+async fn ssdp(socket: UdpSocket, http_addr: SocketAddr) {
+    let mut buf = [0; 2048];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let search = String::from_utf8_lossy(&buf[..len]);
+        if !search.starts_with("M-SEARCH") {
+            continue;
+        }
+        let answer = format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nUSN: uuid:nat-traversal-mock::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n",
+            http_addr
+        );
+        let _ = socket.send_to(answer.as_bytes(), from).await;
+    }
+}
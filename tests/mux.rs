@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use nat_traversal_test::{keepalive::KeepaliveConfig, mux::Mux, udp};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::oneshot,
+    time,
+};
+
+async fn bound() -> Arc<UdpSocket> {
+    Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+}
+
+// Forward what `from` receives through `to`, losing about one datagram in
+// thirty and holding every fifth one back until the next went through, or
+// for a few milliseconds.
+async fn lossy(from: Arc<UdpSocket>, to: Arc<UdpSocket>) {
+    let mut buf = [0; 2048];
+    let mut held: Option<Vec<u8>> = None;
+    for n in 0u64.. {
+        let received = match &held {
+            Some(_) => time::timeout(Duration::from_millis(5), from.recv(&mut buf)).await,
+            None => Ok(from.recv(&mut buf).await),
+        };
+        let Ok(Ok(len)) = received else {
+            if let Some(datagram) = held.take() {
+                let _ = to.send(&datagram).await;
+            }
+            continue;
+        };
+        if rand::random::<u8>() < 8 {
+            continue;
+        }
+        if n.is_multiple_of(5) && held.is_none() {
+            held = Some(buf[..len].to_vec());
+            continue;
+        }
+        let _ = to.send(&buf[..len]).await;
+        if let Some(datagram) = held.take() {
+            let _ = to.send(&datagram).await;
+        }
+    }
+}
+
+// Two multiplexed ends of a punched path between `lossy` proxies.
+async fn lossy_pair() -> (Mux, Mux) {
+    let (a, b, a_proxy, b_proxy) = (bound().await, bound().await, bound().await, bound().await);
+    a.connect(a_proxy.local_addr().unwrap()).await.unwrap();
+    a_proxy.connect(a.local_addr().unwrap()).await.unwrap();
+    b.connect(b_proxy.local_addr().unwrap()).await.unwrap();
+    b_proxy.connect(b.local_addr().unwrap()).await.unwrap();
+    tokio::spawn(lossy(Arc::clone(&a_proxy), Arc::clone(&b_proxy)));
+    tokio::spawn(lossy(b_proxy, a_proxy));
+
+    let (a_announce, a_mux) = oneshot::channel();
+    let (b_announce, b_mux) = oneshot::channel();
+    tokio::spawn(udp::run_mux_session(
+        a,
+        KeepaliveConfig::default(),
+        a_announce,
+    ));
+    tokio::spawn(udp::run_mux_session(
+        b,
+        KeepaliveConfig::default(),
+        b_announce,
+    ));
+    let (a_mux, b_mux) = time::timeout(Duration::from_secs(10), async {
+        (a_mux.await.unwrap(), b_mux.await.unwrap())
+    })
+    .await
+    .unwrap();
+    (a_mux, b_mux)
+}
+
+#[tokio::test]
+async fn streams_survive_loss_and_reordering_on_udp() {
+    let (opener, mut acceptor) = lossy_pair().await;
+    tokio::spawn(async move {
+        while let Some(stream) = acceptor.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+        }
+    });
+
+    let echoes = (0..4u8).map(|i| {
+        let mut stream = opener.open().unwrap();
+        tokio::spawn(async move {
+            // more than the window of a stream and of the link
+            let data = (0..512 * 1024).map(|n| (n as u8) ^ i).collect::<Vec<_>>();
+            let (mut reader, mut writer) = tokio::io::split(&mut stream);
+            let write = async {
+                writer.write_all(&data).await.unwrap();
+                writer.shutdown().await.unwrap();
+            };
+            let mut echo = Vec::new();
+            let read = reader.read_to_end(&mut echo);
+            let (_, read) = tokio::join!(write, read);
+            read.unwrap();
+            assert!(echo == data, "stream {} echoed other data", i);
+        })
+    });
+    time::timeout(
+        Duration::from_secs(30),
+        futures::future::try_join_all(echoes),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+}
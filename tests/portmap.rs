@@ -0,0 +1,107 @@
+mod mock_gateway;
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use mock_gateway::{GatewayConfig, MockGateway};
+use nat_traversal_test::{
+    lifetime::Protocol,
+    portmap::{MappingProtocol, PCP_PORT, PortMapConfig, map_port},
+};
+use std::sync::Arc;
+use tokio::{net::UdpSocket, time};
+
+const EXTERNAL: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+
+// A gateway on a loopback address of its own, the protocols use fixed ports.
+async fn gateway(listen: Ipv4Addr, protocols: &[MappingProtocol]) -> Arc<MockGateway> {
+    let gateway = mock_gateway::start(GatewayConfig {
+        listen,
+        external: EXTERNAL,
+        http_port: 5000,
+        protocols: protocols.to_vec(),
+    })
+    .await;
+    time::sleep(Duration::from_millis(100)).await;
+    gateway
+}
+
+fn config(gateway: Ipv4Addr, protocols: &[MappingProtocol]) -> PortMapConfig {
+    PortMapConfig {
+        gateway: Some(gateway),
+        lifetime: Duration::from_secs(60),
+        protocols: protocols.to_vec(),
+    }
+}
+
+// Map, renew and remove a port of each transport through `protocol`.
+async fn maps_renews_and_removes(listen: Ipv4Addr, protocol: MappingProtocol) {
+    let gateway = gateway(listen, &[protocol]).await;
+    for (transport, port) in [(Protocol::Tcp, 40001), (Protocol::Udp, 40002)] {
+        let mut mapping = map_port(transport, port, &config(listen, &[protocol]))
+            .await
+            .unwrap();
+        assert_eq!(mapping.protocol, protocol);
+        assert_eq!(mapping.external, SocketAddr::new(EXTERNAL.into(), port));
+        let internal = gateway.mapping(transport, port).unwrap();
+        assert_eq!(internal.port(), port);
+
+        mapping.renew().await.unwrap();
+        assert_eq!(mapping.external, SocketAddr::new(EXTERNAL.into(), port));
+        assert_eq!(gateway.mapping(transport, port), Some(internal));
+
+        mapping.remove().await.unwrap();
+        assert_eq!(gateway.mapping(transport, port), None);
+    }
+}
+
+#[tokio::test]
+async fn pcp() {
+    maps_renews_and_removes(Ipv4Addr::new(127, 0, 0, 11), MappingProtocol::Pcp).await;
+}
+
+#[tokio::test]
+async fn nat_pmp() {
+    maps_renews_and_removes(Ipv4Addr::new(127, 0, 0, 12), MappingProtocol::NatPmp).await;
+}
+
+#[tokio::test]
+async fn upnp() {
+    maps_renews_and_removes(Ipv4Addr::new(127, 0, 0, 13), MappingProtocol::Upnp).await;
+}
+
+#[tokio::test]
+async fn falls_back_to_a_protocol_the_gateway_speaks() {
+    let listen = Ipv4Addr::new(127, 0, 0, 14);
+    let gateway = gateway(listen, &[MappingProtocol::NatPmp]).await;
+    let mapping = map_port(Protocol::Udp, 40003, &config(listen, &MappingProtocol::ALL))
+        .await
+        .unwrap();
+    assert_eq!(mapping.protocol, MappingProtocol::NatPmp);
+    assert!(gateway.mapping(Protocol::Udp, 40003).is_some());
+}
+
+#[tokio::test]
+async fn reports_pcp_errors_without_opcode_data() {
+    let listen = Ipv4Addr::new(127, 0, 0, 15);
+    // a gateway answering with the bare header and NOT_AUTHORIZED
+    let sock = UdpSocket::bind((listen, PCP_PORT)).await.unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1100];
+        while let Ok((_, from)) = sock.recv_from(&mut buf).await {
+            let mut answer = [0; 24];
+            answer[..4].copy_from_slice(&[2, 0x80 | buf[1], 0, 2]);
+            let _ = sock.send_to(&answer, from).await;
+        }
+    });
+    let err = map_port(
+        Protocol::Udp,
+        40004,
+        &config(listen, &[MappingProtocol::Pcp]),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("PCP result code 2"), "{}", err);
+}
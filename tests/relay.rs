@@ -0,0 +1,233 @@
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use nat_traversal_test::{
+    REGISTER, RendezvousServer,
+    keepalive::KeepaliveConfig,
+    relay::{self, JOIN_PREFIX, RelayIssuer, RelayServer, parse_ticket},
+    tcp::{self, Endpoint, PunchConfig},
+    traversal::{PUNCH_TIMEOUT, padded},
+    udp,
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+const SECRET: &str = "relay test secret";
+
+async fn start_relay(listen: SocketAddr) {
+    tokio::spawn(
+        RelayServer::builder()
+            .listen(listen)
+            .secret(SECRET)
+            .build()
+            .run(),
+    );
+    time::sleep(Duration::from_millis(100)).await;
+}
+
+async fn peer() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").await.unwrap()
+}
+
+// Whether anything comes back to `sock` within a short while.
+async fn answered(sock: &UdpSocket) -> bool {
+    let mut buf = [0; 1024];
+    time::timeout(Duration::from_millis(300), sock.recv_from(&mut buf))
+        .await
+        .is_ok()
+}
+
+#[tokio::test]
+async fn forwards_between_ticket_holders() {
+    let listen: SocketAddr = "127.0.0.1:28091".parse().unwrap();
+    start_relay(listen).await;
+    let (a, b) = (peer().await, peer().await);
+    let issuer = RelayIssuer::new(listen, SECRET);
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    relay::join(&a, &issuer.ticket(a_addr, b_addr))
+        .await
+        .unwrap();
+    relay::join(&b, &issuer.ticket(b_addr, a_addr))
+        .await
+        .unwrap();
+
+    a.send_to(b"hello", listen).await.unwrap();
+    let mut buf = [0; 1024];
+    let (len, from) = time::timeout(Duration::from_secs(1), b.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(from, listen);
+}
+
+#[tokio::test]
+async fn rejects_joins_without_a_valid_ticket() {
+    let listen: SocketAddr = "127.0.0.1:28092".parse().unwrap();
+    start_relay(listen).await;
+    let sock = peer().await;
+    let ticket = RelayIssuer::new(listen, SECRET).ticket(sock.local_addr().unwrap(), listen);
+
+    for join in [
+        format!("{}{}", JOIN_PREFIX, ticket.room),
+        format!("{}{} 1.00", JOIN_PREFIX, ticket.room),
+        format!("{}other {}", JOIN_PREFIX, ticket.token),
+    ] {
+        sock.send_to(join.as_bytes(), listen).await.unwrap();
+        assert!(!answered(&sock).await, "{} was admitted", join);
+    }
+    // a ticket of another secret
+    let forged = RelayIssuer::new(listen, "guess").ticket(sock.local_addr().unwrap(), listen);
+    let join = format!("{}{} {}", JOIN_PREFIX, forged.room, forged.token);
+    sock.send_to(join.as_bytes(), listen).await.unwrap();
+    assert!(!answered(&sock).await);
+    // unallocated peers are not forwarded to anyone
+    sock.send_to(b"hello", listen).await.unwrap();
+    assert!(!answered(&sock).await);
+}
+
+#[tokio::test]
+async fn admits_two_peers_per_room() {
+    let listen: SocketAddr = "127.0.0.1:28093".parse().unwrap();
+    start_relay(listen).await;
+    let issuer = RelayIssuer::new(listen, SECRET);
+    let (a, b, c) = (peer().await, peer().await, peer().await);
+    let ticket = issuer.ticket(a.local_addr().unwrap(), b.local_addr().unwrap());
+    relay::join(&a, &ticket).await.unwrap();
+    relay::join(&b, &ticket).await.unwrap();
+
+    let join = format!("{}{} {}", JOIN_PREFIX, ticket.room, ticket.token);
+    c.send_to(join.as_bytes(), listen).await.unwrap();
+    assert!(!answered(&c).await);
+}
+
+#[tokio::test]
+async fn rendezvous_hands_out_tickets() {
+    let relay: SocketAddr = "127.0.0.1:28094".parse().unwrap();
+    let rendezvous: SocketAddr = "127.0.0.1:28095".parse().unwrap();
+    start_relay(relay).await;
+    tokio::spawn(
+        RendezvousServer::builder()
+            .udp_listen([rendezvous])
+            .udp_workers(1)
+            .relay(RelayIssuer::new(relay, SECRET))
+            .build()
+            .run(),
+    );
+    time::sleep(Duration::from_millis(100)).await;
+
+    let bind: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let (a, b) = tokio::join!(
+        udp::nat_client(vec![rendezvous], bind, 1, None),
+        udp::nat_client(vec![rendezvous], bind, 2, None),
+    );
+    let (a, b) = (a.unwrap(), b.unwrap());
+    let (a_ticket, b_ticket) = (a.1.relay.unwrap(), b.1.relay.unwrap());
+    assert_eq!(a_ticket.relay, relay);
+    assert_eq!(a_ticket.room, b_ticket.room);
+
+    // the punched sockets are connected to each other, the relay needs its own
+    let (a_sock, b_sock) = (peer().await, peer().await);
+    relay::join(&a_sock, &a_ticket).await.unwrap();
+    relay::join(&b_sock, &b_ticket).await.unwrap();
+}
+
+async fn start_rendezvous(relay: SocketAddr, rendezvous: SocketAddr) {
+    tokio::spawn(
+        RendezvousServer::builder()
+            .udp_listen([rendezvous])
+            .tcp_listen([rendezvous])
+            .udp_workers(1)
+            .relay(RelayIssuer::new(relay, SECRET))
+            .build()
+            .run(),
+    );
+    time::sleep(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn udp_clients_fall_back_to_the_relay() {
+    let relay: SocketAddr = "127.0.0.1:28096".parse().unwrap();
+    let rendezvous: SocketAddr = "127.0.0.1:28097".parse().unwrap();
+    start_relay(relay).await;
+    start_rendezvous(relay, rendezvous).await;
+
+    let bind: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let client = tokio::spawn(udp::nat_client(vec![rendezvous], bind, 1, None));
+    time::sleep(Duration::from_millis(100)).await;
+    // a peer that never answers punches, it only meets the client in the room
+    let peer = peer().await;
+    peer.send_to(&padded(b"ping 2"), rendezvous).await.unwrap();
+    let mut buf = [0; 1024];
+    let ticket = loop {
+        let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+        if let Some(ticket) = parse_ticket(&buf[..len]) {
+            break ticket;
+        }
+    };
+    time::sleep(PUNCH_TIMEOUT).await;
+    relay::connect(&peer, &ticket).await.unwrap();
+
+    let (sock, result) = time::timeout(Duration::from_secs(5), client)
+        .await
+        .expect("the client relays")
+        .unwrap()
+        .unwrap();
+    assert!(result.relayed);
+    assert_eq!(sock.peer_addr().unwrap(), relay);
+    let report = result.report("udp");
+    assert_eq!(report["path"], "relay");
+    assert_eq!(report["relay"], ticket.to_string());
+}
+
+#[tokio::test]
+async fn tcp_clients_fall_back_to_the_relay() {
+    let relay: SocketAddr = "127.0.0.1:28098".parse().unwrap();
+    let rendezvous: SocketAddr = "127.0.0.1:28099".parse().unwrap();
+    start_relay(relay).await;
+    start_rendezvous(relay, rendezvous).await;
+
+    // registers first, nothing listens where the client dials it
+    let waiting = TcpStream::connect(rendezvous).await.unwrap();
+    let mut waiting = Framed::new(waiting, LengthDelimitedCodec::new());
+    let register = serde_json::json!({ REGISTER: true });
+    waiting
+        .send(bytes::Bytes::from(register.to_string()))
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(200)).await;
+
+    let (socket, _) = tcp::create_socket("127.0.0.1:0".parse().unwrap());
+    let (_inbound, inbound) = mpsc::unbounded_channel();
+    let client = tokio::spawn(tcp::nat_client(
+        socket,
+        vec![rendezvous],
+        Endpoint::new(),
+        KeepaliveConfig::default(),
+        PunchConfig::default(),
+        inbound,
+    ));
+    let introduction = loop {
+        let msg = waiting.next().await.unwrap().unwrap();
+        if !msg.is_empty() {
+            break msg;
+        }
+    };
+    let introduction: serde_json::Value = serde_json::from_slice(&introduction).unwrap();
+    let ticket = introduction["relay"].as_str().unwrap().parse().unwrap();
+    time::sleep(PUNCH_TIMEOUT).await;
+    let sock = peer().await;
+    relay::connect(&sock, &ticket).await.unwrap();
+
+    let result = time::timeout(Duration::from_secs(5), client)
+        .await
+        .expect("the client relays")
+        .unwrap()
+        .unwrap();
+    assert!(result.relayed);
+    assert_eq!(result.report("tcp")["path"], "relay");
+}
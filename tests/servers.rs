@@ -0,0 +1,136 @@
+use std::{io, net::SocketAddr, sync::atomic::Ordering, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use nat_traversal_test::{
+    PEER_GONE, REGISTER, RendezvousServer,
+    keepalive::KeepaliveConfig,
+    tcp::{self, Endpoint, PunchConfig},
+    traversal::query_tcp_mappings,
+    udp,
+};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn start_server(listen: SocketAddr) -> RendezvousServer {
+    let server = RendezvousServer::builder()
+        .udp_listen([listen])
+        .udp_workers(1)
+        .build();
+    tokio::spawn(server.clone().run());
+    server
+}
+
+#[tokio::test]
+async fn servers_of_one_process_stay_apart() {
+    let (a, b) = (start_server(addr(28111)), start_server(addr(28112)));
+    time::sleep(Duration::from_millis(100)).await;
+
+    // one client on each server, they must not be paired with each other
+    let bind = addr(0);
+    let first = tokio::spawn(udp::nat_client(vec![addr(28111)], bind, 1, None));
+    let alone = tokio::spawn(udp::nat_client(vec![addr(28112)], bind, 2, None));
+    time::sleep(Duration::from_millis(500)).await;
+    for server in [&a, &b] {
+        let registrations = server.metrics().udp_registrations.load(Ordering::Relaxed);
+        assert_eq!(registrations, 1);
+    }
+
+    let second = udp::nat_client(vec![addr(28111)], bind, 3, None).await;
+    let (second_sock, second) = second.unwrap();
+    let (first_sock, first) = first.await.unwrap().unwrap();
+    assert_eq!(first.peer, Some(second_sock.local_addr().unwrap()));
+    assert_eq!(second.peer, Some(first_sock.local_addr().unwrap()));
+    assert!(!alone.is_finished());
+    alone.abort();
+
+    assert_eq!(a.metrics().udp_pairings.load(Ordering::Relaxed), 2);
+    assert_eq!(b.metrics().udp_pairings.load(Ordering::Relaxed), 0);
+    assert_eq!(b.metrics().udp_registrations.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn answers_tcp_mapping_requests_without_registering() {
+    let server = RendezvousServer::builder()
+        .tcp_listen([addr(28113)])
+        .build();
+    tokio::spawn(server.clone().run());
+    time::sleep(Duration::from_millis(100)).await;
+
+    let mut bound = None;
+    let mappings = query_tcp_mappings(&[addr(28113)], || {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(addr(0)).unwrap();
+        bound = socket.local_addr().ok();
+        socket
+    })
+    .await;
+    assert_eq!(mappings[0].mapped, bound);
+    assert_eq!(server.metrics().active_sessions.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn tells_the_peer_of_a_client_that_drops() {
+    let server = RendezvousServer::builder()
+        .tcp_listen([addr(28114)])
+        .build();
+    tokio::spawn(server.run());
+    time::sleep(Duration::from_millis(100)).await;
+
+    // registers first, so it is introduced as soon as the client registers
+    let dropped = TcpStream::connect(addr(28114)).await.unwrap();
+    let mut dropped = Framed::new(dropped, LengthDelimitedCodec::new());
+    let register = serde_json::json!({ REGISTER: true });
+    dropped
+        .send(bytes::Bytes::from(register.to_string()))
+        .await
+        .unwrap();
+    time::sleep(Duration::from_millis(200)).await;
+
+    let (socket, _) = tcp::create_socket(addr(0));
+    let (_inbound, inbound) = mpsc::unbounded_channel();
+    let client = tokio::spawn(tcp::nat_client(
+        socket,
+        vec![addr(28114)],
+        Endpoint::new(),
+        KeepaliveConfig::default(),
+        PunchConfig::default(),
+        inbound,
+    ));
+    let introduction = dropped.next().await.unwrap().unwrap();
+    let introduction: serde_json::Value = serde_json::from_slice(&introduction).unwrap();
+    assert!(introduction["address"].is_string());
+    assert!(introduction.get(PEER_GONE).is_none());
+    drop(dropped);
+
+    let err = time::timeout(Duration::from_secs(5), client)
+        .await
+        .expect("the client punches on")
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[tokio::test]
+async fn closes_connections_that_do_not_say_what_they_want() {
+    let server = RendezvousServer::builder()
+        .tcp_listen([addr(28115)])
+        .build();
+    tokio::spawn(server.run());
+    time::sleep(Duration::from_millis(100)).await;
+
+    let stream = TcpStream::connect(addr(28115)).await.unwrap();
+    let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
+    stream.send(bytes::Bytes::from("hello")).await.unwrap();
+    let closed = time::timeout(Duration::from_secs(2), stream.next())
+        .await
+        .expect("the server closes the connection");
+    assert!(closed.is_none_or(|frame| frame.is_err()));
+}